@@ -645,14 +645,28 @@ rec {
           "base64" = "base64 0.10.1 (registry+https://github.com/rust-lang/crates.io-index)";
           "dirs" = "dirs 2.0.2 (registry+https://github.com/rust-lang/crates.io-index)";
           "env_logger" = "env_logger 0.7.0 (registry+https://github.com/rust-lang/crates.io-index)";
-          "git2" = "git2 0.10.1 (registry+https://github.com/rust-lang/crates.io-index)";
+          "git2" = {
+            packageId = "git2 0.10.1 (registry+https://github.com/rust-lang/crates.io-index)";
+            usesDefaultFeatures = false;
+          };
+          "libc" = {
+            packageId = "libc 0.2.62 (registry+https://github.com/rust-lang/crates.io-index)";
+            target = target."unix";
+          };
           "log" = "log 0.4.8 (registry+https://github.com/rust-lang/crates.io-index)";
+          "regex" = "regex 1.3.1 (registry+https://github.com/rust-lang/crates.io-index)";
           "structopt" = "structopt 0.3.3 (registry+https://github.com/rust-lang/crates.io-index)";
           "tempfile" = "tempfile 3.1.0 (registry+https://github.com/rust-lang/crates.io-index)";
+          "unicode-normalization" = "unicode-normalization 0.1.8 (registry+https://github.com/rust-lang/crates.io-index)";
           "walkdir" = "walkdir 2.2.9 (registry+https://github.com/rust-lang/crates.io-index)";
         };
         features = {
+          "default" = [ "https" "ssh" ];
+          "https" = [ "git2/https" ];
+          "ssh" = [ "git2/ssh" ];
+          "vendored-openssl" = [ "https" "git2/vendored-openssl" ];
         };
+        resolvedDefaultFeatures = [ "default" "https" "ssh" ];
       };
     "git2 0.10.1 (registry+https://github.com/rust-lang/crates.io-index)"
       = rec {
@@ -688,7 +702,7 @@ rec {
           "ssh_key_from_memory" = [ "libgit2-sys/ssh_key_from_memory" ];
           "vendored-openssl" = [ "openssl-sys/vendored" ];
         };
-        resolvedDefaultFeatures = [ "default" "https" "openssl-probe" "openssl-sys" "ssh" "ssh_key_from_memory" ];
+        resolvedDefaultFeatures = [ "https" "openssl-probe" "openssl-sys" "ssh" ];
       };
     "heck 0.3.1 (registry+https://github.com/rust-lang/crates.io-index)"
       = rec {
@@ -825,7 +839,7 @@ rec {
           "https" = [ "openssl-sys" ];
           "ssh" = [ "libssh2-sys" ];
         };
-        resolvedDefaultFeatures = [ "https" "libssh2-sys" "openssl-sys" "ssh" "ssh_key_from_memory" ];
+        resolvedDefaultFeatures = [ "https" "libssh2-sys" "openssl-sys" "ssh" ];
       };
     "libssh2-sys 0.2.12 (registry+https://github.com/rust-lang/crates.io-index)"
       = rec {
@@ -0,0 +1,81 @@
+//! The git implementation used to talk to upstreams. Cloning into and
+//! fetching into the cache, which is all that touches the network, goes
+//! through `Backend`, so another implementation than libgit2 can take
+//! over that part without the rest of the code noticing. Only libgit2
+//! is implemented for now.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use git2::{build::RepoBuilder, FetchOptions, RemoteCallbacks, Repository};
+
+use crate::{credentials::{self, Credentials}, interrupt, progress::Progress};
+
+/// How a clone or fetch should behave towards the user
+pub struct Transfer<'a> {
+    /// Draw a progress bar of the objects received
+    pub progress: bool,
+    pub credentials: &'a Credentials,
+}
+
+pub trait Backend: Send + Sync {
+    /// Clone `url` into a new bare repository at `path`. Its `origin`
+    /// remote is set to `origin`, so credentials from the environment
+    /// that were expanded into `url` aren't left lying around.
+    fn clone_bare(&self, url: &str, origin: &str, path: &Path, transfer: &Transfer) -> Result<()>;
+
+    /// Fetch all branches and tags of `url` into the bare repository at
+    /// `path`, replacing the ones it has. Returns the ref the upstream's
+    /// HEAD points at, like `refs/heads/main`, if it says.
+    fn fetch(&self, url: &str, path: &Path, transfer: &Transfer) -> Result<Option<String>>;
+}
+
+/// Options for cloning and fetching with libgit2, which report the
+/// transfer progress
+pub fn fetch_options(transfer: &Transfer) -> FetchOptions<'static> {
+    let mut progress = Progress::new(transfer.progress, "Receiving objects");
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.transfer_progress(move |stats| {
+        if stats.total_deltas() == 0 || stats.received_objects() < stats.total_objects() {
+            progress.set_label("Receiving objects");
+            progress.update(stats.received_objects(), Some(stats.total_objects()), Some(stats.received_bytes()));
+        } else {
+            progress.set_label("Resolving deltas");
+            progress.update(stats.indexed_deltas(), Some(stats.total_deltas()), None);
+        }
+        // Returning false cancels the transfer
+        !interrupt::is_interrupted()
+    });
+    callbacks.credentials(transfer.credentials.handler());
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks);
+    options
+}
+
+pub struct Libgit2;
+
+impl Backend for Libgit2 {
+    fn clone_bare(&self, url: &str, origin: &str, path: &Path, transfer: &Transfer) -> Result<()> {
+        let repo = RepoBuilder::new()
+            .bare(true)
+            .fetch_options(fetch_options(transfer))
+            .clone(url, path)
+            .map_err(|err| credentials::explain_refusal(url, err))
+            .context("failed to clone repository")?;
+        repo.remote_set_url("origin", origin).context("failed to set clone's remote url")?;
+        Ok(())
+    }
+
+    fn fetch(&self, url: &str, path: &Path, transfer: &Transfer) -> Result<Option<String>> {
+        let repo = Repository::open_bare(path).context("failed to open cached bare repository")?;
+        let mut remote = repo.remote_anonymous(url).context("failed to create anonymous remote")?;
+        remote.fetch(&["+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"], Some(&mut fetch_options(transfer)), None)
+            .map_err(|err| credentials::explain_refusal(url, err))
+            .context("failed to fetch from anonymous remote")?;
+        let default_branch = remote.list().context("failed to list remote refs")?
+            .iter()
+            .find(|head| head.name() == "HEAD")
+            .and_then(|head| head.symref_target().map(String::from));
+        Ok(default_branch)
+    }
+}
@@ -0,0 +1,278 @@
+//! Answering credential requests while cloning and fetching. Like git,
+//! ssh-agent and credential helpers are asked first, then the tokens
+//! of forge tools like `gh`, and the user only as a last resort.
+
+use std::{
+    env,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use git2::{Config, Cred, CredentialType};
+use log::{debug, warn};
+
+use crate::{open_superproject, sources};
+
+/// Keys ssh tries by default, in order
+const SSH_KEYS: &[&str] = &["id_rsa", "id_ecdsa", "id_ed25519"];
+
+/// How many times to ask for a password, in case of typos
+const MAX_PROMPTS: usize = 3;
+
+/// A forge whose command line tool can give us a token to use as the
+/// password over HTTPS
+struct Forge {
+    /// The public instance, which the environment variables are for
+    host: &'static str,
+    env: &'static [&'static str],
+    /// Prints the token of the host appended to it
+    command: &'static [&'static str],
+    username: &'static str,
+}
+
+const FORGES: &[Forge] = &[
+    Forge {
+        host: "github.com",
+        env: &["GH_TOKEN", "GITHUB_TOKEN"],
+        command: &["gh", "auth", "token", "--hostname"],
+        username: "x-access-token",
+    },
+    Forge {
+        host: "gitlab.com",
+        env: &["GITLAB_TOKEN"],
+        command: &["glab", "config", "get", "token", "--host"],
+        username: "oauth2",
+    },
+];
+
+/// Credentials typed in by the user, which can be stored once they're
+/// known to work
+struct Prompted {
+    url: String,
+    username: String,
+    password: String,
+}
+
+#[derive(Clone, Default)]
+pub struct Credentials {
+    interactive: bool,
+    prompted: Arc<Mutex<Option<Prompted>>>,
+}
+impl Credentials {
+    /// Only ask on the terminal if `interactive` is set
+    pub fn new(interactive: bool) -> Self {
+        Self {
+            interactive,
+            prompted: Arc::default(),
+        }
+    }
+
+    /// A handler for `RemoteCallbacks::credentials`. libgit2 keeps
+    /// calling it for as long as authentication fails, so each way of
+    /// getting credentials is only tried once.
+    pub fn handler(&self) -> impl FnMut(&str, Option<&str>, CredentialType) -> Result<Cred, git2::Error> + 'static {
+        let prompted = Arc::clone(&self.prompted);
+        let interactive = self.interactive;
+        let mut tried_agent = false;
+        let mut ssh_keys: Vec<PathBuf> = dirs::home_dir()
+            .map(|home| SSH_KEYS.iter().rev().map(|name| home.join(".ssh").join(name)).collect())
+            .unwrap_or_default();
+        let mut tried_helper = false;
+        let mut tried_forge = false;
+        let mut tried_default = false;
+        let mut prompts = 0;
+
+        move |url, username, allowed| {
+            if allowed.contains(CredentialType::USERNAME) {
+                return Cred::username(username.unwrap_or("git"));
+            }
+            if allowed.contains(CredentialType::SSH_KEY) {
+                if !tried_agent {
+                    tried_agent = true;
+                    return Cred::ssh_key_from_agent(username.unwrap_or("git"));
+                }
+                // Same as ssh does without an agent
+                while let Some(key) = ssh_keys.pop() {
+                    if key.exists() {
+                        debug!("trying ssh key {}", key.display());
+                        return Cred::ssh_key(username.unwrap_or("git"), None, &key, None);
+                    }
+                }
+            }
+            if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+                if !tried_helper {
+                    tried_helper = true;
+                    match git_config().and_then(|config| Ok(Cred::credential_helper(&config, url, username)?)) {
+                        Ok(cred) => return Ok(cred),
+                        Err(err) => debug!("no credential helper answered: {:#}", err),
+                    }
+                }
+                if !tried_forge {
+                    tried_forge = true;
+                    if let Some((username, token)) = forge_token(url) {
+                        return Cred::userpass_plaintext(username, &token);
+                    }
+                }
+                if interactive && prompts < MAX_PROMPTS {
+                    prompts += 1;
+                    match prompt(url, username) {
+                        Ok((username, password)) => {
+                            let cred = Cred::userpass_plaintext(&username, &password);
+                            *prompted.lock().expect("credentials lock poisoned") = Some(Prompted {
+                                url: url.to_owned(),
+                                username,
+                                password,
+                            });
+                            return cred;
+                        },
+                        Err(err) => warn!("failed to ask for credentials: {:#}", err),
+                    }
+                }
+            }
+            if allowed.contains(CredentialType::DEFAULT) && !tried_default {
+                tried_default = true;
+                return Cred::default();
+            }
+            Err(git2::Error::from_str(
+                "authentication failed, set up ssh-agent or a credential helper, or run in a terminal to be asked",
+            ))
+        }
+    }
+
+    /// Call after a clone or fetch succeeded. If the user had to type
+    /// in credentials for it, and `subcopy.storeCredentials` is set,
+    /// they're saved with `git credential approve` so the user isn't
+    /// asked again.
+    pub fn approve(&self) -> Result<()> {
+        let prompted = match self.prompted.lock().expect("credentials lock poisoned").take() {
+            Some(prompted) => prompted,
+            None => return Ok(()),
+        };
+        let store = git_config()?.get_bool("subcopy.storeCredentials").unwrap_or(false);
+        if !store {
+            debug!("not storing credentials, subcopy.storeCredentials isn't set");
+            return Ok(());
+        }
+
+        let mut child = Command::new("git")
+            .args(["credential", "approve"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("failed to run git credential")?;
+        let mut stdin = child.stdin.take().expect("stdin should be piped");
+        write!(stdin, "url={}\nusername={}\npassword={}\n\n", prompted.url, prompted.username, prompted.password)
+            .context("failed to pass credentials to git credential")?;
+        drop(stdin);
+        let status = child.wait().context("failed to wait for git credential")?;
+        if !status.success() {
+            warn!("failed to store credentials, git credential exited with {}", status);
+        }
+        Ok(())
+    }
+}
+
+/// The config of the repository we're in, or the global one, so
+/// repository specific credential helpers are used
+fn git_config() -> Result<Config> {
+    match open_superproject() {
+        Ok(repo) => repo.config().context("failed to open repository config"),
+        Err(_) => Config::open_default().context("failed to open git config"),
+    }
+}
+
+/// A token for an HTTPS URL from the environment, or from the forge's
+/// command line tool if it's logged in to the host
+fn forge_token(url: &str) -> Option<(&'static str, String)> {
+    let parsed = sources::Url::parse(url);
+    let host = parsed.host?;
+    if parsed.scheme != Some("https") {
+        return None;
+    }
+    for forge in FORGES {
+        if host == forge.host {
+            if let Some(token) = forge.env.iter().find_map(|name| env::var(name).ok().filter(|token| !token.is_empty())) {
+                debug!("using token from the environment for {}", host);
+                return Some((forge.username, token));
+            }
+        }
+        let (program, args) = forge.command.split_first().expect("forge command shouldn't be empty");
+        let output = match Command::new(program).args(args).arg(host).stdin(Stdio::null()).stderr(Stdio::null()).output() {
+            Ok(output) => output,
+            Err(err) => {
+                debug!("failed to run {}: {}", program, err);
+                continue;
+            },
+        };
+        let token = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+        if output.status.success() && !token.is_empty() {
+            debug!("using token from {} for {}", program, host);
+            return Some((forge.username, token));
+        }
+    }
+    None
+}
+
+/// Explain a clone or fetch over HTTPS that was turned away, since
+/// libgit2 only says which status it got. Forges answer with 429, or 403
+/// on GitHub, when too many requests were made. When to try again is in
+/// the response headers, which libgit2 doesn't give us.
+pub fn explain_refusal(url: &str, err: git2::Error) -> anyhow::Error {
+    let status = err.message().strip_prefix("unexpected HTTP status code: ").and_then(|code| code.parse::<u16>().ok());
+    let host = sources::Url::parse(url).host.unwrap_or("the server").to_owned();
+    let forge = FORGES.iter().find(|forge| forge.host == host);
+    let token_hint = match forge {
+        Some(forge) if forge_token(url).is_none() => format!(", and logging in or setting {} raises the limit", forge.env[0]),
+        _ => String::new(),
+    };
+    let message = match status {
+        Some(429) => format!("{} is limiting how many requests can be made, try again later{}", host, token_hint),
+        Some(403) => format!(
+            "{} refused the request. If it's limiting how many requests can be made, try again later{}. Otherwise, the credentials can't read this repository",
+            host, token_hint,
+        ),
+        _ => return err.into(),
+    };
+    anyhow::Error::new(err).context(message)
+}
+
+/// Ask for a username and password on the terminal
+fn prompt(url: &str, username: Option<&str>) -> Result<(String, String)> {
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty").context("failed to open terminal")?;
+    let url = sources::Url::parse(url).redacted();
+
+    let username = match username {
+        Some(username) => username.to_owned(),
+        None => {
+            write!(tty, "Username for '{}': ", url).context("failed to write to terminal")?;
+            read_line(&tty)?
+        },
+    };
+
+    write!(tty, "Password for '{}': ", url).context("failed to write to terminal")?;
+    set_echo(&tty, false)?;
+    let password = read_line(&tty);
+    set_echo(&tty, true)?;
+    writeln!(tty).context("failed to write to terminal")?;
+
+    Ok((username, password?))
+}
+
+fn read_line(tty: &File) -> Result<String> {
+    let mut line = String::new();
+    BufReader::new(tty).read_line(&mut line).context("failed to read from terminal")?;
+    Ok(line.trim_end_matches(['\n', '\r']).to_owned())
+}
+
+/// Turn showing typed characters on the terminal on or off
+fn set_echo(tty: &File, echo: bool) -> Result<()> {
+    Command::new("stty")
+        .arg(if echo { "echo" } else { "-echo" })
+        .stdin(tty.try_clone().context("failed to duplicate terminal")?)
+        .status()
+        .context("failed to run stty")?;
+    Ok(())
+}
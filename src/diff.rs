@@ -0,0 +1,216 @@
+//! Printing diffs of subcopies, either as patches or as a summary like
+//! `git diff --stat`. Binary files are never printed, only how their
+//! size changed. They're detected the same way as git does: by a
+//! `binary` or `-diff` attribute in upstream's `.gitattributes`, or
+//! otherwise by looking for NUL bytes.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use git2::{Delta, Diff, Oid, Patch, Repository, Tree};
+
+use crate::skip::{self, Skip};
+
+/// The width of the bars in `--stat` output
+const STAT_WIDTH: usize = 50;
+
+/// Whether upstream said files are binary or text, from the root
+/// `.gitattributes`. Only the attributes affecting diffs are read.
+#[derive(Default)]
+pub struct Attributes {
+    rules: Vec<(Skip, bool)>,
+}
+
+impl Attributes {
+    /// Read the `.gitattributes` in the root of an upstream tree
+    pub fn from_tree(repo: &Repository, tree: &Tree) -> Result<Self> {
+        let mut attributes = Self::default();
+        let blob = match tree.get_name(".gitattributes") {
+            Some(entry) => repo.find_blob(entry.id()).context("failed to read .gitattributes")?,
+            None => return Ok(attributes),
+        };
+        for line in String::from_utf8_lossy(blob.content()).lines() {
+            let mut words = line.split_whitespace();
+            let pattern = match words.next() {
+                Some(pattern) if !pattern.starts_with('#') => pattern,
+                _ => continue,
+            };
+            let binary = words.fold(None, |binary, attribute| match attribute {
+                "binary" | "-diff" => Some(true),
+                "diff" | "text" => Some(false),
+                _ => binary,
+            });
+            if let (Some(binary), Ok(pattern)) = (binary, pattern.parse()) {
+                attributes.rules.push((pattern, binary));
+            }
+        }
+        Ok(attributes)
+    }
+
+    /// What the attributes say about a file, relative to the root of
+    /// upstream. Later lines win, like in git.
+    fn is_binary(&self, path: &Path) -> Option<bool> {
+        self.rules.iter().rev().find(|(pattern, _)| pattern.matches(path)).map(|(_, binary)| *binary)
+    }
+}
+
+/// How to read a diff of a subcopy
+pub struct Differ<'a> {
+    pub repo: &'a Repository,
+    pub attributes: &'a Attributes,
+    /// Where the diffed content is in upstream, for looking up
+    /// attributes
+    pub upstream_path: &'a Path,
+    /// Files that aren't vendored, and so aren't shown
+    pub skips: &'a [Skip],
+    /// Turns a path in the diff into the path to show
+    pub display: &'a dyn Fn(&Path) -> PathBuf,
+}
+
+/// A changed file, ready to be printed
+pub struct FileChange {
+    path: PathBuf,
+    status: Delta,
+    binary: bool,
+    old_size: usize,
+    new_size: usize,
+    additions: usize,
+    deletions: usize,
+    patch: Option<Patch>,
+}
+
+impl Differ<'_> {
+    fn size(&self, id: Oid) -> usize {
+        if id.is_zero() {
+            return 0;
+        }
+        self.repo.find_blob(id).map(|blob| blob.size()).unwrap_or(0)
+    }
+
+    fn is_binary(&self, path: &Path, ids: [Oid; 2]) -> bool {
+        if let Some(binary) = self.attributes.is_binary(&self.upstream_path.join(path)) {
+            return binary;
+        }
+        ids.iter()
+            .filter(|id| !id.is_zero())
+            .any(|id| self.repo.find_blob(*id).is_ok_and(|blob| blob.is_binary()))
+    }
+
+    /// The files changed in a diff
+    pub fn changes(&self, diff: &Diff) -> Result<Vec<FileChange>> {
+        let mut changes = Vec::new();
+        for (i, delta) in diff.deltas().enumerate() {
+            let (old, new) = (delta.old_file(), delta.new_file());
+            let path = new.path().or_else(|| old.path()).unwrap_or_else(|| Path::new(""));
+            if skip::is_skipped(self.skips, path) {
+                continue;
+            }
+            let binary = self.is_binary(path, [old.id(), new.id()]);
+            let patch = if binary { None } else { Patch::from_diff(diff, i).context("failed to compute patch")? };
+            let (additions, deletions) = match &patch {
+                Some(patch) => {
+                    let (_, additions, deletions) = patch.line_stats()?;
+                    (additions, deletions)
+                },
+                None => (0, 0),
+            };
+            changes.push(FileChange {
+                path: (self.display)(path),
+                status: delta.status(),
+                binary,
+                old_size: self.size(old.id()),
+                new_size: self.size(new.id()),
+                additions,
+                deletions,
+                patch,
+            });
+        }
+        Ok(changes)
+    }
+}
+
+/// Print changes as a patch, with paths as they're shown
+pub fn print_patch(changes: &mut [FileChange], out: &mut dyn Write) -> Result<()> {
+    for change in changes {
+        let path = change.path.display();
+        writeln!(out, "diff --git a/{} b/{}", path, path)?;
+        let (old, new) = match change.status {
+            Delta::Added => {
+                writeln!(out, "new file")?;
+                (String::from("/dev/null"), format!("b/{}", path))
+            },
+            Delta::Deleted => {
+                writeln!(out, "deleted file")?;
+                (format!("a/{}", path), String::from("/dev/null"))
+            },
+            _ => (format!("a/{}", path), format!("b/{}", path)),
+        };
+        let patch = match &mut change.patch {
+            Some(patch) => patch,
+            None => {
+                writeln!(out, "Binary files {} and {} differ ({})", old, new, size_change(change))?;
+                continue;
+            },
+        };
+        writeln!(out, "--- {}\n+++ {}", old, new)?;
+        let mut result = Ok(());
+        patch.print(&mut |_, _, line| {
+            let written = match line.origin() {
+                // The file header was already printed with the right paths
+                'F' => Ok(()),
+                origin @ ('+' | '-' | ' ') => write!(out, "{}", origin).and_then(|()| out.write_all(line.content())),
+                _ => out.write_all(line.content()),
+            };
+            result = written;
+            result.is_ok()
+        })?;
+        result?;
+    }
+    Ok(())
+}
+
+/// Print changes as a summary of changed lines per file
+pub fn print_stat(changes: &[FileChange], out: &mut dyn Write) -> Result<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let width = changes.iter().map(|change| change.path.to_string_lossy().len()).max().unwrap_or(0);
+    let most = changes.iter().map(|change| change.additions + change.deletions).max().unwrap_or(0);
+    let (mut additions, mut deletions) = (0, 0);
+    for change in changes {
+        let path = change.path.to_string_lossy();
+        if change.binary {
+            writeln!(out, " {:width$} | Bin {}", path, size_change(change), width = width)?;
+            continue;
+        }
+        let lines = change.additions + change.deletions;
+        let (plus, minus) = if most > STAT_WIDTH {
+            let scale = |n: usize| if n == 0 { 0 } else { (n * STAT_WIDTH / most).max(1) };
+            (scale(change.additions), scale(change.deletions))
+        } else {
+            (change.additions, change.deletions)
+        };
+        writeln!(out, " {:width$} | {:>5} {}{}", path, lines, "+".repeat(plus), "-".repeat(minus), width = width)?;
+        additions += change.additions;
+        deletions += change.deletions;
+    }
+    writeln!(
+        out,
+        " {} file{} changed, {} insertion{}(+), {} deletion{}(-)",
+        changes.len(),
+        if changes.len() == 1 { "" } else { "s" },
+        additions,
+        if additions == 1 { "" } else { "s" },
+        deletions,
+        if deletions == 1 { "" } else { "s" },
+    )?;
+    Ok(())
+}
+
+fn size_change(change: &FileChange) -> String {
+    let delta = change.new_size as i64 - change.old_size as i64;
+    format!("{} -> {} bytes, {:+}", change.old_size, change.new_size, delta)
+}
@@ -0,0 +1,221 @@
+//! The manifest and lockfile in formats other tools can read, for
+//! build systems, dashboards or policy checks. Everything is built as a
+//! tree of values first, which is then written as JSON, YAML or TOML.
+//! Strings are always quoted, so none get mistaken for a number or
+//! boolean.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use git_subcopy::porcelain;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            _ => Err(anyhow!("unknown export format {:?}, expected json, yaml or toml", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    /// Left out in TOML, which can't express it
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    List(Vec<Value>),
+    Table(Vec<(String, Value)>),
+}
+impl Value {
+    pub fn string<S: Into<String>>(value: S) -> Self {
+        Value::String(value.into())
+    }
+
+    pub fn optional<S: Into<String>>(value: Option<S>) -> Self {
+        value.map_or(Value::Null, Value::string)
+    }
+
+    pub fn strings<I, S>(values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Value::List(values.into_iter().map(Value::string).collect())
+    }
+
+    fn is_table(&self) -> bool {
+        matches!(self, Value::Table(_))
+    }
+}
+
+/// Write a table as a document
+pub fn render(format: Format, document: &[(String, Value)]) -> String {
+    let mut out = String::new();
+    match format {
+        Format::Json => {
+            json(&mut out, &Value::Table(document.to_vec()), 0);
+            out.push('\n');
+        },
+        Format::Yaml => yaml_table(&mut out, document, 0),
+        Format::Toml => {
+            toml_table(&mut out, &[], document);
+            // Tables start with an empty line to set them apart
+            out.remove(0);
+        },
+    }
+    out
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => Some("null".to_owned()),
+        Value::Bool(value) => Some(value.to_string()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::String(value) => Some(porcelain::json_string(value)),
+        Value::List(items) if items.is_empty() => Some("[]".to_owned()),
+        Value::Table(entries) if entries.is_empty() => Some("{}".to_owned()),
+        _ => None,
+    }
+}
+
+fn key(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        key.to_owned()
+    } else {
+        porcelain::json_string(key)
+    }
+}
+
+fn json(out: &mut String, value: &Value, indent: usize) {
+    if let Some(scalar) = scalar(value) {
+        out.push_str(&scalar);
+        return;
+    }
+    let inner = " ".repeat(indent + 2);
+    let (open, close) = if value.is_table() { ('{', '}') } else { ('[', ']') };
+    out.push(open);
+    let items: Vec<(Option<&str>, &Value)> = match value {
+        Value::Table(entries) => entries.iter().map(|(key, value)| (Some(key.as_str()), value)).collect(),
+        Value::List(items) => items.iter().map(|item| (None, item)).collect(),
+        _ => unreachable!(),
+    };
+    for (i, (key, value)) in items.into_iter().enumerate() {
+        out.push_str(if i == 0 { "\n" } else { ",\n" });
+        out.push_str(&inner);
+        if let Some(key) = key {
+            out.push_str(&porcelain::json_string(key));
+            out.push_str(": ");
+        }
+        json(out, value, indent + 2);
+    }
+    out.push('\n');
+    out.push_str(&" ".repeat(indent));
+    out.push(close);
+}
+
+fn yaml_table(out: &mut String, entries: &[(String, Value)], indent: usize) {
+    let prefix = " ".repeat(indent);
+    for (name, value) in entries {
+        match scalar(value) {
+            Some(scalar) => out.push_str(&format!("{}{}: {}\n", prefix, key(name), scalar)),
+            None => {
+                out.push_str(&format!("{}{}:\n", prefix, key(name)));
+                yaml_value(out, value, indent + 2);
+            },
+        }
+    }
+}
+
+fn yaml_value(out: &mut String, value: &Value, indent: usize) {
+    let prefix = " ".repeat(indent);
+    match value {
+        Value::Table(entries) => yaml_table(out, entries, indent),
+        Value::List(items) => {
+            for item in items {
+                match (scalar(item), item) {
+                    (Some(scalar), _) => out.push_str(&format!("{}- {}\n", prefix, scalar)),
+                    // The first entry of a table goes on the same line
+                    // as its dash
+                    (None, Value::Table(entries)) => {
+                        let mut table = String::new();
+                        yaml_table(&mut table, entries, indent + 2);
+                        out.push_str(&prefix);
+                        out.push_str("- ");
+                        out.push_str(&table[indent + 2..]);
+                    },
+                    (None, item) => {
+                        out.push_str(&format!("{}-\n", prefix));
+                        yaml_value(out, item, indent + 2);
+                    },
+                }
+            }
+        },
+        _ => (),
+    }
+}
+
+fn is_table_list(value: &Value) -> bool {
+    matches!(value, Value::List(items) if !items.is_empty() && items.iter().all(Value::is_table))
+}
+
+fn toml_inline(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Bool(value) => Some(value.to_string()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::String(value) => Some(porcelain::json_string(value)),
+        Value::List(items) => {
+            let items: Vec<String> = items.iter().filter_map(toml_inline).collect();
+            Some(format!("[{}]", items.join(", ")))
+        },
+        Value::Table(entries) => {
+            let entries: Vec<String> = entries.iter()
+                .filter_map(|(name, value)| Some(format!("{} = {}", key(name), toml_inline(value)?)))
+                .collect();
+            Some(format!("{{ {} }}", entries.join(", ")))
+        },
+    }
+}
+
+fn toml_table(out: &mut String, path: &[String], entries: &[(String, Value)]) {
+    // Plain values have to come before any sub-tables
+    for (name, value) in entries {
+        if value.is_table() || is_table_list(value) {
+            continue;
+        }
+        if let Some(inline) = toml_inline(value) {
+            out.push_str(&format!("{} = {}\n", key(name), inline));
+        }
+    }
+    for (name, value) in entries {
+        let mut path = path.to_vec();
+        path.push(key(name));
+        match value {
+            Value::Table(entries) => {
+                out.push_str(&format!("\n[{}]\n", path.join(".")));
+                toml_table(out, &path, entries);
+            },
+            Value::List(items) if is_table_list(value) => {
+                for item in items {
+                    if let Value::Table(entries) = item {
+                        out.push_str(&format!("\n[[{}]]\n", path.join(".")));
+                        toml_table(out, &path, entries);
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+}
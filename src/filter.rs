@@ -0,0 +1,353 @@
+//! Content transformations applied to text files as they're extracted,
+//! written in `.gitcopies` as `filter = <filter>`. Filters are applied
+//! in order when extracting, and undone in reverse order when copying
+//! local content back to upstream, so mechanical differences don't
+//! show up as local changes.
+//!
+//! - `replace <upstream> <local>` replaces one literal string with
+//!   another, which can be undone exactly.
+//! - `sub /<regex>/<replacement>/` replaces regex matches, with any
+//!   character as the delimiter.
+//! - `eol lf` or `eol crlf` normalizes line endings.
+//! - `strip-header` removes the leading comment block, like a license
+//!   header.
+//! - `exec <name>` pipes the content through a shell command, which is
+//!   set in the git config as `subcopy.filter.<name>.command`. It's not
+//!   in `.gitcopies`, so that cloning a repository never runs commands
+//!   it brought along. The path of the file within upstream is in
+//!   `$GIT_SUBCOPY_FILE`.
+//! - `provenance` adds a comment at the top saying where the file is
+//!   from and not to edit it, for files whose comment syntax is known
+//!   from their extension. It's always removed again before comparing
+//!   to upstream.
+//!
+//! Filters other than `replace` can't be undone by themselves, so when
+//! a local file is exactly what the filters would've produced from
+//! upstream, it's treated as unchanged. Otherwise undoing them is best
+//! effort.
+
+use std::{
+    borrow::Cow,
+    fmt,
+    io::Write,
+    ops::Range,
+    path::Path,
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use git2::{Config, Oid};
+use regex::bytes::Regex;
+
+/// How to write a comment line, by the lowercase extension or, for
+/// files without one, name of a file
+const COMMENTS: &[(&[&str], &str, &str)] = &[
+    (
+        &[
+            "c", "h", "cc", "cpp", "cxx", "hh", "hpp", "rs", "go", "java", "kt", "kts", "scala", "swift", "js", "mjs",
+            "cjs", "jsx", "ts", "tsx", "cs", "dart", "zig", "proto", "groovy", "gradle", "scss", "less",
+        ],
+        "// ",
+        "",
+    ),
+    (
+        &[
+            "py", "rb", "sh", "bash", "zsh", "fish", "pl", "pm", "r", "toml", "yaml", "yml", "nix", "cmake", "mk",
+            "ps1", "tf", "jl", "ex", "exs", "makefile", "dockerfile",
+        ],
+        "# ",
+        "",
+    ),
+    (&["lua", "sql", "hs", "elm"], "-- ", ""),
+    (&["el", "lisp", "clj", "cljs", "scm", "rkt"], ";; ", ""),
+    (&["tex", "erl"], "% ", ""),
+    (&["vim"], "\" ", ""),
+    (&["css"], "/* ", " */"),
+    (&["html", "htm", "xml", "svg", "md", "markdown", "vue"], "<!-- ", " -->"),
+];
+
+/// What the provenance header says, between the comment markers
+const PROVENANCE_START: &str = "vendored from ";
+const PROVENANCE_END: &str = " by git-subcopy \u{2014} do not edit";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Eol {
+    Lf,
+    Crlf,
+}
+
+#[derive(Clone, Debug)]
+pub enum Filter {
+    Replace { upstream: String, local: String },
+    Sub { pattern: Regex, replacement: String, delimiter: char },
+    Eol(Eol),
+    StripHeader,
+    Exec { name: String, command: Option<String> },
+    Provenance,
+}
+
+/// Where content being extracted is from, for the `provenance` filter
+#[derive(Clone, Copy, Debug)]
+pub struct Origin<'a> {
+    pub url: &'a str,
+    pub rev: Oid,
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (name, args) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+        let args = args.trim_start();
+
+        match name {
+            "replace" => {
+                let mut words = args.split_whitespace();
+                match (words.next(), words.next(), words.next()) {
+                    (Some(upstream), Some(local), None) => Ok(Filter::Replace {
+                        upstream: upstream.to_owned(),
+                        local: local.to_owned(),
+                    }),
+                    _ => bail!("expected `replace <upstream> <local>`"),
+                }
+            },
+            "sub" => {
+                let delimiter = args.chars().next().ok_or_else(|| anyhow!("expected `sub /<regex>/<replacement>/`"))?;
+                let parts: Vec<&str> = args[delimiter.len_utf8()..].split(delimiter).collect();
+                match parts[..] {
+                    [pattern, replacement, ""] => Ok(Filter::Sub {
+                        pattern: Regex::new(pattern).context("invalid regex")?,
+                        replacement: replacement.to_owned(),
+                        delimiter,
+                    }),
+                    _ => bail!("expected `sub {0}<regex>{0}<replacement>{0}`", delimiter),
+                }
+            },
+            "eol" => match args {
+                "lf" => Ok(Filter::Eol(Eol::Lf)),
+                "crlf" => Ok(Filter::Eol(Eol::Crlf)),
+                _ => bail!("expected `eol lf` or `eol crlf`"),
+            },
+            "strip-header" if args.is_empty() => Ok(Filter::StripHeader),
+            "exec" => {
+                let valid = !args.is_empty() && args.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+                ensure!(valid, "expected `exec <name>`, with the command in `subcopy.filter.<name>.command` of the git config");
+                Ok(Filter::Exec { name: args.to_owned(), command: None })
+            },
+            "provenance" if args.is_empty() => Ok(Filter::Provenance),
+            _ => bail!("unknown filter {:?}", s),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::Replace { upstream, local } => write!(f, "replace {} {}", upstream, local),
+            Filter::Sub { pattern, replacement, delimiter: d } => write!(f, "sub {}{}{}{}{}", d, pattern, d, replacement, d),
+            Filter::Eol(Eol::Lf) => write!(f, "eol lf"),
+            Filter::Eol(Eol::Crlf) => write!(f, "eol crlf"),
+            Filter::StripHeader => write!(f, "strip-header"),
+            Filter::Exec { name, .. } => write!(f, "exec {}", name),
+            Filter::Provenance => write!(f, "provenance"),
+        }
+    }
+}
+
+/// Same heuristic as git: text files don't have NUL bytes early on
+fn is_binary(content: &[u8]) -> bool {
+    content.iter().take(8000).any(|&b| b == 0)
+}
+
+fn replace(content: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(i) = rest.windows(from.len()).position(|window| window == from) {
+        output.extend_from_slice(&rest[..i]);
+        output.extend_from_slice(to);
+        rest = &rest[i + from.len()..];
+    }
+    output.extend_from_slice(rest);
+    output
+}
+
+fn convert_eol(content: &[u8], eol: Eol) -> Vec<u8> {
+    let lf = replace(content, b"\r\n", b"\n");
+    match eol {
+        Eol::Lf => lf,
+        Eol::Crlf => replace(&lf, b"\n", b"\r\n"),
+    }
+}
+
+/// The length of the leading comment block, including blank lines
+/// following it
+fn header_len(content: &[u8]) -> usize {
+    const MARKERS: &[&[u8]] = &[b"//", b"#", b"/*", b"*", b"--", b";"];
+
+    let trimmed = |line: &[u8]| -> usize { line.iter().take_while(|b| b.is_ascii_whitespace()).count() };
+    let mut lines = content.split_inclusive(|&b| b == b'\n').peekable();
+    let mut len = 0;
+    while let Some(line) = lines.next_if(|line| MARKERS.iter().any(|m| line[trimmed(line)..].starts_with(m))) {
+        len += line.len();
+    }
+    if len > 0 {
+        while let Some(line) = lines.next_if(|line| trimmed(line) == line.len()) {
+            len += line.len();
+        }
+    }
+    len
+}
+
+fn comment_syntax(path: &Path) -> Option<(&'static str, &'static str)> {
+    let key = path.extension().or_else(|| path.file_name())?.to_str()?.to_lowercase();
+    COMMENTS.iter()
+        .find(|(keys, _, _)| keys.contains(&&*key))
+        .map(|(_, start, end)| (*start, *end))
+}
+
+/// Where the provenance header goes. Shebangs and XML declarations
+/// have to stay on the first line.
+fn provenance_position(content: &[u8]) -> usize {
+    if content.starts_with(b"#!") || content.starts_with(b"<?xml") {
+        content.iter().position(|&b| b == b'\n').map_or(content.len(), |i| i + 1)
+    } else {
+        0
+    }
+}
+
+fn add_provenance(content: &[u8], origin: Origin, path: &Path) -> Vec<u8> {
+    let (start, end) = match comment_syntax(path) {
+        Some(syntax) => syntax,
+        None => return content.to_vec(),
+    };
+    let eol: &[u8] = if content.windows(2).any(|w| w == b"\r\n") { b"\r\n" } else { b"\n" };
+    let position = provenance_position(content);
+    let mut output = content[..position].to_vec();
+    if !output.is_empty() && !output.ends_with(b"\n") {
+        output.extend_from_slice(eol);
+    }
+    let header = format!("{}{}{}@{}:{}{}{}", start, PROVENANCE_START, origin.url, origin.rev, path.display(), PROVENANCE_END, end);
+    output.extend_from_slice(header.as_bytes());
+    output.extend_from_slice(eol);
+    output.extend_from_slice(&content[position..]);
+    output
+}
+
+/// The provenance header's line in local content, if it has one
+fn find_provenance(content: &[u8], path: &Path) -> Option<Range<usize>> {
+    let (start, end) = comment_syntax(path)?;
+    let position = provenance_position(content);
+    let rest = &content[position..];
+    let len = rest.iter().position(|&b| b == b'\n').map_or(rest.len(), |i| i + 1);
+    let line = rest[..len].strip_suffix(b"\n").unwrap_or(&rest[..len]);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let is_header = line.starts_with(format!("{}{}", start, PROVENANCE_START).as_bytes())
+        && line.ends_with(format!("{}{}", PROVENANCE_END, end).as_bytes());
+    if is_header { Some(position..position + len) } else { None }
+}
+
+/// Look up the commands of `exec` filters in the git config
+pub fn configure(filters: &mut [Filter], config: &Config) -> Result<()> {
+    for filter in filters {
+        if let Filter::Exec { name, command } = filter {
+            let key = format!("subcopy.filter.{}.command", name);
+            *command = match config.get_string(&key) {
+                Ok(value) => Some(value),
+                Err(err) if err.code() == git2::ErrorCode::NotFound => None,
+                Err(err) => return Err(err).with_context(|| format!("failed to read {}", key)),
+            };
+        }
+    }
+    Ok(())
+}
+
+fn exec(name: &str, command: Option<&str>, path: &Path, content: &[u8]) -> Result<Vec<u8>> {
+    let command = command.ok_or_else(|| anyhow!(
+        "filter {0:?} has no command, set one with `git config subcopy.filter.{0}.command <command>`",
+        name,
+    ))?;
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("GIT_SUBCOPY_FILE", path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run filter {:?}", command))?;
+
+    // Write from another thread, so a command printing a lot before
+    // reading everything can't deadlock us
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = content.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+
+    let output = child.wait_with_output().context("failed to wait for filter")?;
+    writer.join().map_err(|_| anyhow!("filter input thread panicked"))?
+        .context("failed to write to filter")?;
+    ensure!(output.status.success(), "filter {:?} failed on {}: {}", command, path.display(), output.status);
+    Ok(output.stdout)
+}
+
+/// Transform upstream content into what's stored locally. `path` is the
+/// path of the file in upstream. The `provenance` filter does nothing
+/// when the origin isn't known.
+pub fn smudge<'a>(filters: &[Filter], origin: Option<Origin>, path: &Path, content: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+    if filters.is_empty() || is_binary(content) {
+        return Ok(Cow::Borrowed(content));
+    }
+    let mut content = content.to_vec();
+    for filter in filters {
+        content = match filter {
+            Filter::Replace { upstream, local } => replace(&content, upstream.as_bytes(), local.as_bytes()),
+            Filter::Sub { pattern, replacement, .. } => pattern.replace_all(&content, replacement.as_bytes()).into_owned(),
+            Filter::Eol(eol) => convert_eol(&content, *eol),
+            Filter::StripHeader => content[header_len(&content)..].to_vec(),
+            Filter::Exec { name, command } => exec(name, command.as_deref(), path, &content)?,
+            Filter::Provenance => match origin {
+                Some(origin) => add_provenance(&content, origin, path),
+                None => content,
+            },
+        };
+    }
+    Ok(Cow::Owned(content))
+}
+
+/// Transform local content back into upstream content, as well as
+/// possible. `base` is the upstream content the local file was
+/// extracted from, if known.
+pub fn clean<'a>(filters: &[Filter], path: &Path, content: &'a [u8], base: Option<&'a [u8]>) -> Result<Cow<'a, [u8]>> {
+    if filters.is_empty() || is_binary(content) {
+        return Ok(Cow::Borrowed(content));
+    }
+    // The provenance header is never in upstream, whatever else the
+    // filters did
+    let content = match find_provenance(content, path).filter(|_| filters.iter().any(|f| matches!(f, Filter::Provenance))) {
+        Some(range) => Cow::Owned([&content[..range.start], &content[range.end..]].concat()),
+        None => Cow::Borrowed(content),
+    };
+    if let Some(base) = base {
+        if *smudge(filters, None, path, base)? == *content {
+            return Ok(Cow::Borrowed(base));
+        }
+    }
+
+    let mut content = content.into_owned();
+    for filter in filters.iter().rev() {
+        match filter {
+            Filter::Replace { upstream, local } => content = replace(&content, local.as_bytes(), upstream.as_bytes()),
+            Filter::Eol(_) => if let Some(base) = base {
+                let eol = if base.windows(2).any(|w| w == b"\r\n") { Eol::Crlf } else { Eol::Lf };
+                content = convert_eol(&content, eol);
+            },
+            Filter::StripHeader => if let Some(base) = base {
+                let mut with_header = base[..header_len(base)].to_vec();
+                with_header.extend_from_slice(&content);
+                content = with_header;
+            },
+            Filter::Sub { .. } | Filter::Exec { .. } | Filter::Provenance => (),
+        }
+    }
+    Ok(Cow::Owned(content))
+}
@@ -0,0 +1,109 @@
+//! Revisions in `.gitcopies` that follow upstream instead of naming a
+//! commit, like `branch:main` or `tag:v1.*`. `update` resolves them
+//! again each time, and the commit they resolved to is pinned in the
+//! lockfile, which is what everything else uses as the revision. Tag
+//! patterns pick the highest matching version, with `*` matching
+//! anything and `?` any one character.
+
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use git2::{Oid, Repository};
+use regex::Regex;
+
+#[derive(Clone, Debug)]
+pub enum Floating {
+    Branch(String),
+    Tag { pattern: String, regex: Regex },
+}
+
+/// Whether a revision from `.gitcopies` is floating, rather than a
+/// commit
+pub fn is_floating(rev: &str) -> bool {
+    rev.starts_with("branch:") || rev.starts_with("tag:")
+}
+
+impl FromStr for Floating {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(branch) = s.strip_prefix("branch:") {
+            if branch.is_empty() {
+                bail!("{:?} doesn't name a branch", s);
+            }
+            return Ok(Floating::Branch(branch.to_owned()));
+        }
+        let pattern = s.strip_prefix("tag:").ok_or_else(|| anyhow!("{:?} should start with branch: or tag:", s))?;
+        if pattern.is_empty() {
+            bail!("{:?} doesn't name a tag", s);
+        }
+
+        let mut regex = String::from("^");
+        for c in pattern.chars() {
+            match c {
+                '*' => regex.push_str(".*"),
+                '?' => regex.push('.'),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        Ok(Floating::Tag {
+            pattern: pattern.to_owned(),
+            regex: Regex::new(&regex)?,
+        })
+    }
+}
+
+impl fmt::Display for Floating {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Floating::Branch(branch) => write!(f, "branch:{}", branch),
+            Floating::Tag { pattern, .. } => write!(f, "tag:{}", pattern),
+        }
+    }
+}
+
+/// Compare versions like `v1.10` and `v1.9` the way people would, by
+/// comparing runs of digits as numbers
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (a_digits, b_digits) = (a.starts_with(|c: char| c.is_ascii_digit()), b.starts_with(|c: char| c.is_ascii_digit()));
+        let split = |s: &str, digits: bool| s.find(|c: char| c.is_ascii_digit() != digits).unwrap_or(s.len());
+        let (a_part, a_rest) = a.split_at(split(a, a_digits));
+        let (b_part, b_rest) = b.split_at(split(b, b_digits));
+        let ordering = match (a_digits, b_digits) {
+            (true, true) => {
+                let (a_part, b_part) = (a_part.trim_start_matches('0'), b_part.trim_start_matches('0'));
+                a_part.len().cmp(&b_part.len()).then_with(|| a_part.cmp(b_part))
+            },
+            _ => a_part.cmp(b_part),
+        };
+        if ordering != Ordering::Equal || (a_rest.is_empty() && b_rest.is_empty()) {
+            return ordering;
+        }
+        a = a_rest;
+        b = b_rest;
+    }
+}
+
+impl Floating {
+    /// The commit this currently means in a cache of upstream
+    pub fn resolve(&self, repo: &Repository) -> Result<Oid> {
+        let name = match self {
+            Floating::Branch(branch) => format!("refs/heads/{}", branch),
+            Floating::Tag { pattern, regex } => {
+                let tags = repo.tag_names(None).context("failed to list tags")?;
+                let tag = tags.iter()
+                    .flatten()
+                    .filter(|tag| regex.is_match(tag))
+                    .max_by(|a, b| compare_versions(a, b))
+                    .ok_or_else(|| anyhow!("no tag matches {}", pattern))?;
+                format!("refs/tags/{}", tag)
+            },
+        };
+        let commit = repo.revparse_single(&name).with_context(|| format!("failed to find {}", self))?
+            .peel_to_commit().with_context(|| format!("{} is not a commit", self))?;
+        Ok(commit.id())
+    }
+}
@@ -0,0 +1,441 @@
+//! Reading the configuration of other vendoring tools, so switching
+//! doesn't mean adding every subcopy again by hand. Only what maps onto
+//! subcopies is understood: git sources, a path in them, where it goes,
+//! and files that are left out. Anything else is warned about and
+//! skipped.
+//!
+//! - `git-vendor` keeps no file, but writes `git-vendor-dir`,
+//!   `git-vendor-repository` and `git-vendor-ref` lines into the
+//!   messages of the commits it makes.
+//! - `vendir` has `vendir.yml`, which is read with a small YAML parser
+//!   that handles block mappings and sequences, but no anchors or
+//!   multi-line strings.
+//! - `copybara` has workflows in `copy.bara.sky`, which is Starlark. The
+//!   `origin`, `origin_files`, `destination_files` and first `core.move`
+//!   of each `core.workflow` are picked out of it.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use git_subcopy::skip::Skip;
+use log::warn;
+use regex::Regex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tool {
+    GitVendor,
+    Vendir,
+    Copybara,
+}
+impl FromStr for Tool {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "git-vendor" => Ok(Tool::GitVendor),
+            "vendir" => Ok(Tool::Vendir),
+            "copybara" => Ok(Tool::Copybara),
+            _ => Err(anyhow!("unknown tool {:?}, expected git-vendor, vendir or copybara", s)),
+        }
+    }
+}
+impl Tool {
+    /// Where the tool keeps its configuration, if it's a file
+    pub fn default_file(self) -> Option<&'static Path> {
+        match self {
+            Tool::GitVendor => None,
+            Tool::Vendir => Some(Path::new("vendir.yml")),
+            Tool::Copybara => Some(Path::new("copy.bara.sky")),
+        }
+    }
+}
+
+/// A subcopy as another tool described it
+#[derive(Debug, Default)]
+pub struct Imported {
+    pub url: String,
+    /// The branch, tag or commit, or `None` for the default branch
+    pub rev: Option<String>,
+    pub upstream_path: PathBuf,
+    pub local_path: PathBuf,
+    pub skips: Vec<Skip>,
+}
+
+/// The subcopies in the messages of commits made by git-vendor, newest
+/// first. Only the newest mention of each directory counts.
+pub fn git_vendor<'a, I: IntoIterator<Item = &'a str>>(messages: I) -> Vec<Imported> {
+    let mut seen = HashSet::new();
+    let mut imported = Vec::new();
+    for message in messages {
+        let field = |name: &str| {
+            message.lines()
+                .find_map(|line| line.trim().strip_prefix(name)?.strip_prefix(':'))
+                .map(|value| value.trim().to_owned())
+        };
+        let (dir, url) = match (field("git-vendor-dir"), field("git-vendor-repository")) {
+            (Some(dir), Some(url)) => (dir, url),
+            _ => continue,
+        };
+        if seen.insert(dir.clone()) {
+            imported.push(Imported {
+                url,
+                rev: field("git-vendor-ref"),
+                upstream_path: PathBuf::from("."),
+                local_path: PathBuf::from(dir),
+                skips: Vec::new(),
+            });
+        }
+    }
+    imported
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Yaml {
+    Null,
+    Scalar(String),
+    List(Vec<Yaml>),
+    Map(Vec<(String, Yaml)>),
+}
+impl Yaml {
+    fn get(&self, key: &str) -> &Yaml {
+        match self {
+            Yaml::Map(entries) => entries.iter().find(|(k, _)| k == key).map_or(&Yaml::Null, |(_, value)| value),
+            _ => &Yaml::Null,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Yaml::Scalar(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_list(&self) -> &[Yaml] {
+        match self {
+            Yaml::List(items) => items,
+            _ => &[],
+        }
+    }
+}
+
+/// Remove a comment from a line, unless the `#` is quoted
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '#') if previous == ' ' => return &line[..i],
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            _ => (),
+        }
+        previous = c;
+    }
+    line
+}
+
+fn yaml_scalar(value: &str) -> Result<Yaml> {
+    let value = value.trim();
+    if value.starts_with(['|', '>', '&', '*', '{']) {
+        bail!("unsupported YAML value {:?}", value);
+    }
+    if let Some(items) = value.strip_prefix('[').and_then(|value| value.strip_suffix(']')) {
+        return items.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(yaml_scalar)
+            .collect::<Result<_>>()
+            .map(Yaml::List);
+    }
+    let unquoted = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        value[1..value.len() - 1].replace("\\\"", "\"").replace("\\\\", "\\")
+    } else if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        value[1..value.len() - 1].replace("''", "'")
+    } else if value.is_empty() || value == "~" || value == "null" {
+        return Ok(Yaml::Null);
+    } else {
+        value.to_owned()
+    };
+    Ok(Yaml::Scalar(unquoted))
+}
+
+/// Split `key: value` at the colon, if it's a mapping entry
+fn yaml_key(text: &str) -> Option<(&str, &str)> {
+    if text.starts_with(['"', '\'', '[']) {
+        return None;
+    }
+    let (key, value) = match text.find(": ") {
+        Some(i) => (&text[..i], &text[i + 2..]),
+        None => (text.strip_suffix(':')?, ""),
+    };
+    Some((key.trim(), value))
+}
+
+fn yaml_block(lines: &mut [(usize, String)], i: &mut usize, indent: usize) -> Result<Yaml> {
+    if *i >= lines.len() || lines[*i].0 < indent {
+        return Ok(Yaml::Null);
+    }
+    let is_item = |text: &str| text == "-" || text.starts_with("- ");
+    if is_item(&lines[*i].1) {
+        let mut items = Vec::new();
+        while *i < lines.len() && lines[*i].0 == indent && is_item(&lines[*i].1) {
+            let rest = lines[*i].1[1..].trim_start().to_owned();
+            if rest.is_empty() {
+                *i += 1;
+                let inner = lines.get(*i).map_or(indent, |line| line.0);
+                items.push(if inner > indent { yaml_block(lines, i, inner)? } else { Yaml::Null });
+            } else if yaml_key(&rest).is_some() {
+                // `- key: value` starts a mapping, lined up with `key`
+                let column = indent + lines[*i].1.len() - rest.len();
+                lines[*i] = (column, rest);
+                items.push(yaml_block(lines, i, column)?);
+            } else {
+                items.push(yaml_scalar(&rest)?);
+                *i += 1;
+            }
+        }
+        return Ok(Yaml::List(items));
+    }
+
+    let mut entries = Vec::new();
+    while *i < lines.len() && lines[*i].0 == indent && !is_item(&lines[*i].1) {
+        let (key, value) = yaml_key(&lines[*i].1)
+            .ok_or_else(|| anyhow!("expected `key: value`, found {:?}", lines[*i].1))?;
+        let (key, value) = (key.to_owned(), value.to_owned());
+        *i += 1;
+        let value = if !value.trim().is_empty() {
+            yaml_scalar(&value)?
+        } else {
+            match lines.get(*i) {
+                Some((inner, _)) if *inner > indent => {
+                    let inner = *inner;
+                    yaml_block(lines, i, inner)?
+                },
+                // Sequences may be lined up with their key
+                Some((inner, text)) if *inner == indent && is_item(text) => yaml_block(lines, i, indent)?,
+                _ => Yaml::Null,
+            }
+        };
+        entries.push((key, value));
+    }
+    Ok(Yaml::Map(entries))
+}
+
+fn parse_yaml(content: &str) -> Result<Yaml> {
+    let mut lines: Vec<(usize, String)> = content.lines()
+        .map(strip_comment)
+        .map(str::trim_end)
+        .filter(|line| !line.trim().is_empty() && *line != "---")
+        .map(|line| {
+            let text = line.trim_start();
+            (line.len() - text.len(), text.to_owned())
+        })
+        .collect();
+    let mut i = 0;
+    let indent = lines.first().map_or(0, |line| line.0);
+    let document = yaml_block(&mut lines, &mut i, indent)?;
+    if let Some((_, text)) = lines.get(i) {
+        bail!("couldn't make sense of {:?}", text);
+    }
+    Ok(document)
+}
+
+/// The directory a glob like `src/**` or `src/**/*` covers, or `None`
+/// if it's something more specific
+fn glob_dir(pattern: &str) -> Option<&str> {
+    let dir = pattern.strip_suffix("/**/*").or_else(|| pattern.strip_suffix("/**"))
+        .or_else(|| if pattern == "**" || pattern == "**/*" { Some("") } else { None })?;
+    if dir.contains(['*', '?', '[']) {
+        return None;
+    }
+    Some(dir)
+}
+
+fn skips(patterns: &[String]) -> Result<Vec<Skip>> {
+    patterns.iter()
+        .map(|pattern| pattern.parse::<Skip>().with_context(|| format!("can't skip {:?}", pattern)))
+        .collect()
+}
+
+/// The subcopies in a `vendir.yml`
+pub fn vendir(content: &str) -> Result<Vec<Imported>> {
+    let config = parse_yaml(content).context("failed to parse vendir.yml")?;
+    let mut imported = Vec::new();
+    for directory in config.get("directories").as_list() {
+        let dir = directory.get("path").as_str().ok_or_else(|| anyhow!("directory without a path"))?;
+        for contents in directory.get("contents").as_list() {
+            let path = contents.get("path").as_str().ok_or_else(|| anyhow!("contents of {} without a path", dir))?;
+            let local_path = Path::new(dir).join(path);
+            let git = contents.get("git");
+            let url = match git.get("url").as_str() {
+                Some(url) => url,
+                None => {
+                    warn!("skipping {}, only git sources can be imported", local_path.display());
+                    continue;
+                },
+            };
+            let strings = |key: &str| -> Vec<String> {
+                contents.get(key).as_list().iter().filter_map(Yaml::as_str).map(String::from).collect()
+            };
+
+            let root = contents.get("newRootPath").as_str().unwrap_or("").trim_matches('/');
+            for include in strings("includePaths") {
+                if glob_dir(&include).is_none_or(|dir| dir.trim_matches('/') != root) {
+                    warn!("{}: includePaths {:?} isn't supported, everything under {:?} is copied", local_path.display(), include, root);
+                }
+            }
+            // Exclusions are relative to the root of upstream, and skips
+            // to the upstream path
+            let excludes: Vec<String> = strings("excludePaths").into_iter()
+                .filter_map(|pattern| match root {
+                    "" => Some(pattern),
+                    root => pattern.strip_prefix(&format!("{}/", root)).map(String::from),
+                })
+                .collect();
+
+            imported.push(Imported {
+                url: url.to_owned(),
+                rev: git.get("ref").as_str().map(|rev| rev.strip_prefix("origin/").unwrap_or(rev).to_owned()),
+                upstream_path: PathBuf::from(if root.is_empty() { "." } else { root }),
+                local_path,
+                skips: skips(&excludes)?,
+            });
+        }
+    }
+    Ok(imported)
+}
+
+/// The text from an opening parenthesis to its match, skipping over
+/// strings
+fn parenthesized(text: &str) -> &str {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return &text[..=i];
+                }
+            },
+            _ => (),
+        }
+    }
+    text
+}
+
+/// The string arguments of a call to `glob`, as `(include, exclude)`
+fn glob_args(call: &str) -> (Vec<String>, Vec<String>) {
+    let string = Regex::new(r#""([^"]*)"|'([^']*)'"#).expect("valid regex");
+    let strings = |list: &str| -> Vec<String> {
+        string.captures_iter(list)
+            .filter_map(|captures| captures.get(1).or_else(|| captures.get(2)))
+            .map(|value| value.as_str().to_owned())
+            .collect()
+    };
+    match call.find("exclude") {
+        Some(i) => (strings(&call[..i]), strings(&call[i..])),
+        None => (strings(call), Vec::new()),
+    }
+}
+
+/// The subcopies in a `copy.bara.sky`, one per workflow
+pub fn copybara(content: &str) -> Result<Vec<Imported>> {
+    let argument = |workflow: &str, name: &str| -> Option<String> {
+        let regex = Regex::new(&format!(r"\b{}\s*=\s*", name)).expect("valid regex");
+        let start = regex.find(workflow)?.end();
+        let rest = &workflow[start..];
+        let open = rest.find('(')?;
+        Some(parenthesized(&rest[open..]).to_owned())
+    };
+    let keyword = |call: &str, name: &str| -> Option<String> {
+        let regex = Regex::new(&format!(r#"\b{}\s*=\s*(?:"([^"]*)"|'([^']*)')"#, name)).expect("valid regex");
+        let captures = regex.captures(call)?;
+        Some(captures.get(1).or_else(|| captures.get(2))?.as_str().to_owned())
+    };
+    let moves = Regex::new(r#"core\.move\(\s*(?:"([^"]*)"|'([^']*)')\s*,\s*(?:"([^"]*)"|'([^']*)')"#).expect("valid regex");
+
+    let mut imported = Vec::new();
+    for (start, _) in content.match_indices("core.workflow(") {
+        let workflow = parenthesized(&content[start + "core.workflow".len()..]);
+        let name = keyword(workflow, "name").unwrap_or_else(|| "default".to_owned());
+        let origin = match argument(workflow, "origin") {
+            Some(origin) if origin.len() > 2 => origin,
+            _ => {
+                warn!("skipping workflow {}, it has no origin", name);
+                continue;
+            },
+        };
+        let url = match keyword(&origin, "url") {
+            Some(url) => url,
+            None => {
+                warn!("skipping workflow {}, only git origins can be imported", name);
+                continue;
+            },
+        };
+
+        let (includes, excludes) = argument(workflow, "origin_files").map_or_else(Default::default, |call| glob_args(&call));
+        let origin_dir = match &includes[..] {
+            [] => Some(""),
+            [include] => glob_dir(include),
+            _ => None,
+        };
+        let origin_dir = match origin_dir {
+            Some(dir) => dir.trim_matches('/').to_owned(),
+            None => {
+                warn!("skipping workflow {}, its origin_files {:?} aren't one directory", name, includes);
+                continue;
+            },
+        };
+        let destination_dir = argument(workflow, "destination_files")
+            .and_then(|call| match &glob_args(&call).0[..] {
+                [include] => glob_dir(include).map(|dir| dir.trim_matches('/').to_owned()),
+                _ => None,
+            });
+        // Moving the root of what's copied says where it goes
+        let moved = moves.captures_iter(workflow).find_map(|captures| {
+            let from = captures.get(1).or_else(|| captures.get(2))?.as_str().trim_matches('/');
+            let to = captures.get(3).or_else(|| captures.get(4))?.as_str().trim_matches('/');
+            if from.is_empty() || from == origin_dir {
+                Some(to.to_owned())
+            } else {
+                None
+            }
+        });
+        let local_path = match (moved, destination_dir) {
+            (Some(to), _) if !to.is_empty() => to,
+            (_, Some(dir)) if !dir.is_empty() => dir,
+            _ => {
+                warn!("skipping workflow {}, it doesn't say which directory the files go in", name);
+                continue;
+            },
+        };
+        let excludes: Vec<String> = excludes.into_iter()
+            .filter_map(|pattern| match &*origin_dir {
+                "" => Some(pattern),
+                dir => pattern.strip_prefix(&format!("{}/", dir)).map(String::from),
+            })
+            .collect();
+
+        imported.push(Imported {
+            url,
+            rev: keyword(&origin, "ref"),
+            upstream_path: PathBuf::from(if origin_dir.is_empty() { "." } else { &origin_dir }),
+            local_path: PathBuf::from(local_path),
+            skips: skips(&excludes)?,
+        });
+    }
+    Ok(imported)
+}
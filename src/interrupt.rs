@@ -0,0 +1,84 @@
+//! Stopping cleanly on Ctrl-C. The signal handler only takes note of
+//! it, and long running work checks every so often, failing with an
+//! error. That way everything unwinds like any other error would:
+//! temporary directories and partial clones are removed, and staged
+//! files are put back. Pressing Ctrl-C a second time exits right away,
+//! in case something doesn't check.
+//!
+//! Some work can't be stopped halfway without losing data, like copying
+//! the result of a shell session back into the superproject. Ctrl-C is
+//! put off while that runs, and takes effect at the next check after.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use anyhow::{bail, Result};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// How many interactive commands are running, which get Ctrl-C too
+static FOREGROUND: AtomicUsize = AtomicUsize::new(0);
+/// How many pieces of work are running that must not be interrupted
+static DEFERRED: AtomicUsize = AtomicUsize::new(0);
+
+/// The exit status of a process killed by Ctrl-C
+pub const EXIT_STATUS: i32 = 130;
+
+#[cfg(unix)]
+extern "C" fn handle(_signal: libc::c_int) {
+    if FOREGROUND.load(Ordering::SeqCst) > 0 {
+        return;
+    }
+    if INTERRUPTED.swap(true, Ordering::SeqCst) && DEFERRED.load(Ordering::SeqCst) == 0 {
+        unsafe { libc::_exit(EXIT_STATUS) };
+    }
+}
+
+/// Catch Ctrl-C and termination requests
+pub fn install() {
+    #[cfg(unix)]
+    unsafe {
+        let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Whether Ctrl-C was pressed
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Fail if Ctrl-C was pressed, unless it's being put off
+pub fn check() -> Result<()> {
+    if is_interrupted() && DEFERRED.load(Ordering::SeqCst) == 0 {
+        bail!("interrupted");
+    }
+    Ok(())
+}
+
+/// Run an interactive command, like a shell, which handles Ctrl-C
+/// itself. Pressing it there doesn't interrupt us.
+pub fn foreground<T>(f: impl FnOnce() -> T) -> T {
+    FOREGROUND.fetch_add(1, Ordering::SeqCst);
+    let result = f();
+    FOREGROUND.fetch_sub(1, Ordering::SeqCst);
+    result
+}
+
+/// Ctrl-C being put off, until this is dropped
+#[must_use = "Ctrl-C is only put off until this is dropped"]
+pub struct Deferred {
+    _private: (),
+}
+
+/// Put off Ctrl-C while doing something that must not be stopped
+/// halfway
+pub fn defer() -> Deferred {
+    DEFERRED.fetch_add(1, Ordering::SeqCst);
+    Deferred { _private: () }
+}
+
+impl Drop for Deferred {
+    fn drop(&mut self) {
+        DEFERRED.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,126 @@
+//! The journal of mutating operations, so the last one can be undone.
+//! Before an operation writes anything, the files and manifests it's
+//! about to touch are saved as a commit in the superproject, and a line
+//! `<commit> <operation>` is appended to `.git/subcopy/journal`. Each
+//! commit is kept alive by a ref under `refs/subcopy/journal/` for as
+//! long as it's in the journal.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use git2::{Oid, Repository, Signature};
+
+use crate::snapshot;
+
+/// How many operations are remembered before the oldest is forgotten
+const MAX_ENTRIES: usize = 20;
+
+/// The line in an entry's message for each path it saved, in the order
+/// they're named in its tree
+const PATH: &str = "Path: ";
+
+/// An operation as it was recorded
+pub struct Operation {
+    pub id: Oid,
+    pub name: String,
+}
+
+fn journal_path(repo: &Repository) -> PathBuf {
+    repo.path().join("subcopy").join("journal")
+}
+
+fn ref_name(id: Oid) -> String {
+    format!("refs/subcopy/journal/{}", id)
+}
+
+/// The operations in the journal, oldest first
+pub fn operations(repo: &Repository) -> Result<Vec<Operation>> {
+    let path = journal_path(repo);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(_) => return Ok(Vec::new()),
+    };
+    content.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (id, name) = line.split_once(' ').unwrap_or((line, ""));
+            Ok(Operation {
+                id: Oid::from_str(id).with_context(|| format!("invalid line in {}: {:?}", path.display(), line))?,
+                name: name.to_owned(),
+            })
+        })
+        .collect()
+}
+
+fn write(repo: &Repository, operations: &[Operation]) -> Result<()> {
+    let path = journal_path(repo);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context("failed to create journal directory")?;
+    }
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    for operation in operations {
+        writeln!(file, "{} {}", operation.id, operation.name).context("failed to write journal")?;
+    }
+    Ok(())
+}
+
+/// Save the paths, relative to the root of the working tree, before
+/// `operation` changes them. Paths that don't exist are recorded too,
+/// so undoing removes them again.
+pub fn record(repo: &Repository, operation: &str, paths: &[PathBuf]) -> Result<Oid> {
+    let workdir = repo.workdir().ok_or_else(|| anyhow!("repository is bare and has no workdir"))?;
+    let mut root = repo.treebuilder(None)?;
+    let mut message = format!("{}\n\n", operation);
+    for (i, path) in paths.iter().enumerate() {
+        snapshot::write_path(repo, &mut root, &i.to_string(), &workdir.join(path))?;
+        message.push_str(&format!("{}{}\n", PATH, path.display()));
+    }
+    let tree = repo.find_tree(root.write()?)?;
+    let sign = Signature::now("git-subcopy", "there's nobody to blame this time")?;
+    let id = repo.commit(None, &sign, &sign, &message, &tree, &[]).context("failed to commit journal entry")?;
+    repo.reference(&ref_name(id), id, true, "subcopy: journal").context("failed to save journal ref")?;
+
+    let mut operations = operations(repo)?;
+    operations.push(Operation { id, name: operation.to_owned() });
+    if operations.len() > MAX_ENTRIES {
+        for forgotten in operations.drain(..operations.len() - MAX_ENTRIES) {
+            if let Ok(mut reference) = repo.find_reference(&ref_name(forgotten.id)) {
+                reference.delete()?;
+            }
+        }
+    }
+    write(repo, &operations)?;
+    Ok(id)
+}
+
+/// Put back everything the last operation changed, and remove it from
+/// the journal. Returns `None` if there's nothing to undo.
+pub fn undo(repo: &Repository) -> Result<Option<Operation>> {
+    let workdir = repo.workdir().ok_or_else(|| anyhow!("repository is bare and has no workdir"))?;
+    let mut operations = operations(repo)?;
+    let operation = match operations.pop() {
+        Some(operation) => operation,
+        None => return Ok(None),
+    };
+
+    let commit = repo.find_commit(operation.id)
+        .with_context(|| format!("journal entry {} is missing from the repository", operation.id))?;
+    let tree = commit.tree()?;
+    let paths = commit.message().unwrap_or("").lines().filter_map(|line| line.strip_prefix(PATH));
+    for (i, path) in paths.enumerate() {
+        let entry = tree.get_name(&i.to_string());
+        snapshot::restore_path(repo, entry.as_ref(), &workdir.join(Path::new(path)))
+            .with_context(|| format!("failed to restore {}", path))?;
+    }
+
+    write(repo, &operations)?;
+    if let Ok(mut reference) = repo.find_reference(&ref_name(operation.id)) {
+        reference.delete()?;
+    }
+    Ok(Some(operation))
+}
@@ -1,112 +1,1781 @@
-use std::{collections::HashMap, fs, path::{PathBuf, Path}};
+use std::{collections::{BTreeMap, HashMap, HashSet}, env, ffi::{OsStr, OsString}, fs, io::{self, Read, Write}, iter, path::{Component, PathBuf, Path}, process::Command, sync::Mutex, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use git2::{
-    build::RepoBuilder,
+    build::{CloneLocal, RepoBuilder},
     Config,
+    Delta,
+    Diff,
+    ErrorCode,
+    FetchOptions,
+    IndexAddOption,
+    Object,
+    ObjectType,
     Oid,
+    PushOptions,
+    RemoteCallbacks,
     Repository,
     ResetType,
-    TreeWalkMode,
-    TreeWalkResult,
+    Status,
+    StatusOptions,
+    Tree,
+    TreeEntry,
 };
-use log::{debug, info};
+use log::{debug, info, warn};
+use regex::Regex;
 use tempfile::Builder;
 use walkdir::WalkDir;
 
+mod backend;
+mod credentials;
+pub mod diff;
+pub mod filter;
+pub mod floating;
+pub mod interrupt;
+pub mod journal;
+pub mod license;
+pub mod lock;
+pub mod porcelain;
+mod progress;
+pub mod registry;
+pub mod remap;
+pub mod skip;
+pub mod snapshot;
+pub mod sources;
+pub mod span;
+mod staging;
+mod unicode;
+
+use backend::{Backend, Libgit2, Transfer};
+use credentials::Credentials;
+use filter::{Filter, Origin};
+use floating::Floating;
+use lock::{Lock, LockEntry};
+use progress::Progress;
+use remap::Remap;
+use skip::Skip;
+use snapshot::Snapshot;
+use staging::Staging;
+
 fn path_to_string(path: &Path) -> Result<&str> {
     path.to_str().ok_or_else(|| anyhow!("path must be valid utf-8"))
 }
 
-#[derive(Debug, Default)]
-pub struct SubcopyConfigOption {
-    pub url: Option<String>,
-    pub rev: Option<String>,
-    pub upstream_path: Option<PathBuf>,
-    pub local_path: PathBuf,
-}
-#[derive(Debug, Default)]
-pub struct SubcopyConfig {
-    pub url: String,
-    pub rev: String,
-    pub upstream_path: PathBuf,
-}
+/// `path` relative to `base`, where both are absolute
+fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let path: Vec<_> = path.components().collect();
+    let base: Vec<_> = base.components().collect();
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    let mut relative: PathBuf = iter::repeat_n(Component::ParentDir, base.len() - common).collect();
+    relative.extend(&path[common..]);
+    relative
+}
+
+/// Upstream paths are relative to the root of the upstream repository,
+/// where `.` or an empty path means the root itself. Components like
+/// `./src` are removed, since trees can't look them up.
+fn normalize_upstream_path(path: &Path) -> PathBuf {
+    path.components().filter(|c| *c != Component::CurDir).collect()
+}
+
+/// Join a path from upstream (or derived from one) onto `base`, making
+/// sure it can't point outside of it. A hostile upstream could contain
+/// entries like `..` which would otherwise let it write anywhere.
+fn join_inside(base: &Path, relative: &Path) -> Result<PathBuf> {
+    for component in relative.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => (),
+            _ => bail!("refusing to use path {:?}, it could point outside of {}", relative, base.display()),
+        }
+    }
+    Ok(base.join(relative))
+}
+
+/// The URL a cached repository was cloned from
+fn cached_url(repo: &Repository) -> String {
+    repo.find_remote("origin").ok()
+        .and_then(|remote| remote.url().map(String::from))
+        .unwrap_or_default()
+}
+
+/// Whether a file already has exactly this content. It's compared a
+/// chunk at a time, so big files aren't read into memory a second time.
+fn is_unchanged(path: &Path, content: &[u8]) -> bool {
+    if !fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.len() == content.len() as u64) {
+        return false;
+    }
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    let mut buf = vec![0; CHUNK_SIZE.min(content.len())];
+    for expected in content.chunks(CHUNK_SIZE) {
+        let buf = &mut buf[..expected.len()];
+        if read_chunk(&mut file, buf).ok() != Some(expected.len()) || buf != expected {
+            return false;
+        }
+    }
+    true
+}
+
+/// Reserve space for a file that's about to be written, so big files
+/// aren't fragmented. Only a hint, so failures are ignored.
+fn preallocate(file: &fs::File, len: u64) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) };
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, len);
+}
+
+/// Write a file, preallocating it first if it's large
+fn write_file(path: &Path, content: &[u8]) -> Result<()> {
+    if (content.len() as u64) < LARGE_FILE {
+        return fs::write(path, content).context("failed to write file");
+    }
+    let mut file = fs::File::create(path).context("failed to create file")?;
+    preallocate(&file, content.len() as u64);
+    file.write_all(content).context("failed to write file")
+}
+
+/// Read as much of a file as fits in the buffer, for reading two files
+/// in step
+fn read_chunk(file: &mut fs::File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+/// Whether two files have the same content, without reading either
+/// into memory as a whole
+fn is_same_file(a: &Path, b: &Path) -> Result<bool> {
+    let (a_len, b_len) = match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) if a.is_file() && b.is_file() => (a.len(), b.len()),
+        _ => return Ok(false),
+    };
+    if a_len != b_len {
+        return Ok(false);
+    }
+    let mut a = fs::File::open(a).context("failed to open file")?;
+    let mut b = fs::File::open(b).context("failed to open file")?;
+    let (mut a_buf, mut b_buf) = (vec![0; CHUNK_SIZE], vec![0; CHUNK_SIZE]);
+    loop {
+        let read = read_chunk(&mut a, &mut a_buf).context("failed to read file")?;
+        if read != read_chunk(&mut b, &mut b_buf).context("failed to read file")? || a_buf[..read] != b_buf[..read] {
+            return Ok(false);
+        }
+        if read == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Copy a file and its permissions. Large files are copied in chunks,
+/// calling `on_progress` with the bytes written as it goes, so huge
+/// assets don't look like a hang. Each chunk is still copied by the
+/// kernel where possible, as `io::copy` between files uses
+/// `copy_file_range` on Linux.
+fn copy_file(from: &Path, to: &Path, on_progress: &mut dyn FnMut(usize)) -> Result<()> {
+    let metadata = fs::metadata(from).context("failed to read file metadata")?;
+    if metadata.len() < LARGE_FILE {
+        fs::copy(from, to).context("failed to copy file")?;
+        on_progress(metadata.len() as usize);
+        return Ok(());
+    }
+
+    let mut reader = fs::File::open(from).context("failed to open file")?;
+    let mut writer = fs::File::create(to).context("failed to create file")?;
+    preallocate(&writer, metadata.len());
+    loop {
+        interrupt::check()?;
+        let copied = io::copy(&mut (&mut reader).take(CHUNK_SIZE as u64), &mut writer).context("failed to copy file")?;
+        if copied == 0 {
+            break;
+        }
+        on_progress(copied as usize);
+    }
+    fs::set_permissions(to, metadata.permissions()).context("failed to copy permissions")
+}
+
+/// Open the repository we're in, the way git would. This is like
+/// `Repository::open_from_env`, except that `GIT_WORK_TREE` is
+/// respected instead of being an error, as git sets it in some hooks
+/// and when running commands with `--work-tree`.
+pub fn open_superproject() -> Result<Repository, git2::Error> {
+    let work_tree = match env::var_os("GIT_WORK_TREE") {
+        Some(work_tree) => work_tree,
+        None => return Repository::open_from_env(),
+    };
+    let cwd = env::current_dir().map_err(|err| git2::Error::from_str(&err.to_string()))?;
+    let repo = match env::var_os("GIT_DIR") {
+        Some(dir) => Repository::open(cwd.join(dir))?,
+        None => Repository::discover(&cwd)?,
+    };
+    repo.set_workdir(&cwd.join(work_tree), false)?;
+    Ok(repo)
+}
+
+/// The git directory shared by all worktrees of a repository. For a
+/// linked worktree, `Repository::path` is its private directory inside
+/// of `.git/worktrees`, while refs, config and objects live here.
+fn common_dir(repo: &Repository) -> Result<PathBuf> {
+    if !repo.is_worktree() {
+        return Ok(repo.path().to_path_buf());
+    }
+    let file = repo.path().join("commondir");
+    let content = fs::read_to_string(&file).with_context(|| format!("failed to read {}", file.display()))?;
+    Ok(repo.path().join(content.trim_end_matches(['\n', '\r'])))
+}
+
+/// Where caches record when they were last fetched into
+const LAST_FETCHED: &str = "subcopy.lastFetched";
+
+/// Where caches record the upstream's default branch, as of the last
+/// fetch
+const DEFAULT_BRANCH: &str = "subcopy.defaultBranch";
+
+/// Files from this size on are copied in chunks, with progress
+const LARGE_FILE: u64 = 16 << 20;
+const CHUNK_SIZE: usize = 1 << 20;
+
+fn unix_time() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs() as i64)
+}
+
+/// Format a unix time as a UTC timestamp, like `2019-10-31T12:00:00Z`
+fn timestamp(time: i64) -> String {
+    let (days, secs) = (time.div_euclid(86400), time.rem_euclid(86400));
+    // Days to a civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/// Run git on a cache, for what libgit2 can't do
+fn git_in<S: AsRef<OsStr>>(git_dir: &Path, args: &[S]) -> Result<()> {
+    let status = Command::new("git")
+        .arg("--git-dir")
+        .arg(git_dir)
+        .args(args)
+        .status()
+        .context("failed to run git")?;
+    ensure!(status.success(), "git exited with {}", status);
+    Ok(())
+}
+
+/// Parse durations like `30s`, `10m`, `1h`, `1d`, or `2w`
+fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &s[digits.len()..] {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        unit => bail!("unknown unit {:?}, expected s, m, h, d, or w", unit),
+    };
+    let number = digits.parse::<u64>().with_context(|| format!("invalid duration {:?}", s))?;
+    Ok(Duration::from_secs(number * multiplier))
+}
+
+/// Walk a tree depth-first in pre-order, like `Tree::walk`, but the
+/// callback may fail. The path given is the full path of the entry
+/// relative to `tree`. Only the trees leading to the current entry are
+/// kept loaded, one per level, so huge upstream trees take as much
+/// memory as deep ones do, not more. Nothing else is loaded, it's up
+/// to the callback to look up objects and let go of them.
+fn walk_tree<F>(repo: &Repository, tree: &Tree, mut callback: F) -> Result<()>
+where
+    F: FnMut(PathBuf, &TreeEntry) -> Result<()>,
+{
+    // The directory, its tree, and the index of the next entry in it
+    let mut stack = vec![(PathBuf::new(), tree.clone(), 0)];
+    while let Some((dir, tree, next)) = stack.last_mut() {
+        let entry = tree.get(*next).map(|entry| entry.to_owned());
+        let entry = match entry {
+            Some(entry) => entry,
+            None => {
+                stack.pop();
+                continue;
+            },
+        };
+        *next += 1;
+        let path = dir.join(entry.name().ok_or_else(|| anyhow!("name is not utf-8 encoded"))?);
+        callback(path.clone(), &entry)?;
+        if entry.kind() == Some(ObjectType::Tree) {
+            let subtree = repo.find_tree(entry.id()).context("failed to find subtree")?;
+            stack.push((path, subtree, 0));
+        }
+    }
+    Ok(())
+}
+
+/// A file or directory in an upstream tree
+#[derive(Debug)]
+pub struct UpstreamEntry {
+    /// The path relative to the directory being listed
+    pub path: PathBuf,
+    pub id: Oid,
+    pub kind: Option<ObjectType>,
+    pub filemode: i32,
+}
+
+/// Remove a file from a subcopy, and the directories that leaves empty.
+/// The ones that are meant to be empty stay.
+fn remove_local_file(local_path: &Path, path: &Path, options: &CopyOptions) -> Result<()> {
+    debug!("removing {}", path.display());
+    fs::remove_file(join_inside(local_path, path)?).context("failed to remove file")?;
+
+    // remove_dir fails on directories that aren't empty
+    for parent in path.ancestors().skip(1).take_while(|p| !p.as_os_str().is_empty()) {
+        if options.empty_dirs.iter().any(|dir| dir == parent) || fs::remove_dir(local_path.join(parent)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Create the directories a subcopy should have even when empty
+fn create_empty_dirs(local_path: &Path, options: &CopyOptions) -> Result<()> {
+    for dir in &options.empty_dirs {
+        let path = join_inside(local_path, dir)?;
+        fs::create_dir_all(&path).with_context(|| format!("failed to create {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Files that would be written to the same place on a
+/// case-insensitive filesystem, in groups
+fn case_collisions(repo: &Repository, tree: &Tree, options: &CopyOptions) -> Result<Vec<Vec<PathBuf>>> {
+    let mut paths: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    walk_tree(repo, tree, |path, entry| {
+        let dot_git = !options.allow_dot_git && path.components().any(|c| c.as_os_str() == ".git");
+        if dot_git || skip::is_skipped(&options.skips, &path) || entry.kind() != Some(ObjectType::Blob) {
+            return Ok(());
+        }
+        let path = remap::to_local(&options.remap, &path);
+        paths.entry(path.to_string_lossy().to_lowercase()).or_default().push(path);
+        Ok(())
+    })?;
+    Ok(paths.into_values().filter(|group| group.len() > 1).collect())
+}
+
+/// Whether the filesystem `dir` is on can't tell names apart by case,
+/// like by default on macOS and Windows
+fn is_case_insensitive(dir: &Path) -> bool {
+    let probe = match Builder::new().prefix(".git-subcopy-Case").tempfile_in(dir) {
+        Ok(probe) => probe,
+        Err(_) => return false,
+    };
+    let name = probe.path().file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default();
+    probe.path().with_file_name(name).exists()
+}
+
+/// The git filemode a file would be stored with
+#[cfg(unix)]
+fn filemode(metadata: &fs::Metadata) -> i32 {
+    use std::os::unix::fs::PermissionsExt;
+    if metadata.permissions().mode() & 0o111 != 0 {
+        0o100755
+    } else {
+        0o100644
+    }
+}
+#[cfg(not(unix))]
+fn filemode(_metadata: &fs::Metadata) -> i32 {
+    0o100644
+}
+
+/// Build a tree out of files at arbitrary paths, creating any
+/// subdirectories needed
+fn build_tree(repo: &Repository, files: Vec<(PathBuf, Oid, i32)>) -> Result<Oid> {
+    let mut builder = repo.treebuilder(None).context("failed to create tree builder")?;
+    let mut dirs: BTreeMap<OsString, Vec<(PathBuf, Oid, i32)>> = BTreeMap::new();
+
+    for (path, id, mode) in files {
+        let mut components = path.components();
+        let first = components.next().ok_or_else(|| anyhow!("can't store a file without a name"))?;
+        let rest = components.as_path();
+        if rest.as_os_str().is_empty() {
+            builder.insert(first.as_os_str(), id, mode).context("failed to insert into tree")?;
+        } else {
+            dirs.entry(first.as_os_str().to_owned()).or_default().push((rest.to_path_buf(), id, mode));
+        }
+    }
+    for (name, files) in dirs {
+        let id = build_tree(repo, files)?;
+        builder.insert(&name, id, 0o040000).context("failed to insert into tree")?;
+    }
+    builder.write().context("failed to write tree")
+}
+
+/// Settings from `.gitcopies` that affect how content is copied
+/// between upstream and the local copy
+#[derive(Clone, Debug, Default)]
+pub struct CopyOptions {
+    pub remap: Vec<Remap>,
+    pub filters: Vec<Filter>,
+    /// Upstream files that are left out, and not treated as deleted
+    pub skips: Vec<Skip>,
+    /// Extract `.git` directories and files found in upstream, instead
+    /// of leaving them out
+    pub allow_dot_git: bool,
+    /// Directories created after extracting, relative to the local
+    /// copy, for layouts that rely on them existing even though git
+    /// can't track them while empty
+    pub empty_dirs: Vec<PathBuf>,
+    /// Only keep the files that are there locally up to date, instead
+    /// of adding new ones from upstream. Missing files are treated as
+    /// left out, like skipped ones.
+    pub frozen_file_set: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct SubcopyConfigOption {
+    pub url: Option<String>,
+    pub rev: Option<String>,
+    pub upstream_path: Option<PathBuf>,
+    pub branch: Option<String>,
+    pub test_command: Option<String>,
+    pub groups: Vec<String>,
+    pub frozen: bool,
+    pub frozen_reason: Option<String>,
+    pub floating: Option<Floating>,
+    pub description: Option<String>,
+    pub added_by: Option<String>,
+    pub added_at: Option<String>,
+    pub last_updated_at: Option<String>,
+    pub options: CopyOptions,
+    pub local_path: PathBuf,
+    /// Whether the url or rev come from `.gitcopies.local`
+    pub overridden: bool,
+}
+#[derive(Debug, Default)]
+pub struct SubcopyConfig {
+    pub url: String,
+    pub rev: String,
+    pub upstream_path: PathBuf,
+    /// The branch or tag to follow when updating, instead of the
+    /// upstream's default branch
+    pub branch: Option<String>,
+    /// A shell command that has to succeed after updating, or else the
+    /// update is rolled back
+    pub test_command: Option<String>,
+    /// Labels like `frontend`, for operating on some subcopies at once
+    pub groups: Vec<String>,
+    /// Whether `update --all` leaves this subcopy at its revision, and
+    /// why
+    pub frozen: bool,
+    pub frozen_reason: Option<String>,
+    /// What `rev` says to follow, like `branch:main`, in which case
+    /// `rev` is the commit pinned in the lockfile
+    pub floating: Option<Floating>,
+    /// Why this is vendored, for future maintainers
+    pub description: Option<String>,
+    /// Who added this subcopy and when, and when its revision last
+    /// changed, as UTC timestamps like `2019-10-31T12:00:00Z`
+    pub added_by: Option<String>,
+    pub added_at: Option<String>,
+    pub last_updated_at: Option<String>,
+    pub options: CopyOptions,
+    /// The path as written in `.gitcopies`, relative to the root of
+    /// the repository
+    pub local_path: PathBuf,
+    /// Whether the url or rev come from `.gitcopies.local`
+    pub overridden: bool,
+}
+impl SubcopyConfigOption {
+    /// Returns the full config, if no required fields are missing
+    pub fn complete(self) -> Option<SubcopyConfig> {
+        Some(SubcopyConfig {
+            url: self.url?,
+            rev: self.rev?,
+            upstream_path: self.upstream_path?,
+            branch: self.branch,
+            test_command: self.test_command,
+            groups: self.groups,
+            frozen: self.frozen,
+            frozen_reason: self.frozen_reason,
+            floating: self.floating,
+            description: self.description,
+            added_by: self.added_by,
+            added_at: self.added_at,
+            last_updated_at: self.last_updated_at,
+            options: self.options,
+            local_path: self.local_path,
+            overridden: self.overridden,
+        })
+    }
+}
+
+/// What happened when merging upstream changes into a subcopy
+#[derive(Debug)]
+pub enum Merge {
+    /// Upstream changes were applied, on top of any local changes
+    Updated,
+    /// Local and upstream changes conflicted in these files, so
+    /// nothing was written
+    Conflicted(Vec<PathBuf>),
+}
+
+/// Read the subcopies out of a manifest, keyed by their local path as
+/// written in it
+fn parse_manifest(config: &mut Config) -> Result<HashMap<String, SubcopyConfigOption>> {
+    let snapshot = config.snapshot().context("failed to take a snapshot of config")?;
+
+    let mut map: HashMap<String, SubcopyConfigOption> = HashMap::new();
+
+    for entry in &snapshot.entries(Some(r"^subcopy\..*\.[^.]+$")).context("failed to iter config entries")? {
+        let entry = entry.context("failed to read config entry")?;
+        let name = entry.name().ok_or_else(|| anyhow!("entry name was not valid utf-8"))?;
+        let value = entry.value().ok_or_else(|| anyhow!("value of {} was not valid utf-8", name))?;
+
+        let (withoutend, var) = name.rsplit_once('.').ok_or_else(|| anyhow!("incomplete subcopy property name"))?;
+        let (_, middle) = withoutend.split_once('.').ok_or_else(|| anyhow!("incomplete subcopy property name"))?;
+        let slot = map.entry(middle.to_owned()).or_insert_with(|| SubcopyConfigOption {
+            local_path: PathBuf::from(&middle),
+            ..SubcopyConfigOption::default()
+        });
+
+        match var {
+            "url" => slot.url = Some(value.to_owned()),
+            "rev" => slot.rev = Some(value.to_owned()),
+            "upstreampath" => slot.upstream_path = Some(normalize_upstream_path(Path::new(value))),
+            "branch" => slot.branch = Some(value.to_owned()),
+            "testcommand" => slot.test_command = Some(value.to_owned()),
+            "group" => slot.groups.push(value.to_owned()),
+            "frozen" => slot.frozen = Config::parse_bool(value).with_context(|| format!("invalid {}", name))?,
+            "frozenreason" => slot.frozen_reason = Some(value.to_owned()),
+            "description" => slot.description = Some(value.to_owned()),
+            "addedby" => slot.added_by = Some(value.to_owned()),
+            "addedat" => slot.added_at = Some(value.to_owned()),
+            "lastupdatedat" => slot.last_updated_at = Some(value.to_owned()),
+            "remap" => slot.options.remap.push(value.parse::<Remap>().with_context(|| format!("invalid {}", name))?),
+            "filter" => slot.options.filters.push(value.parse::<Filter>().with_context(|| format!("invalid {}", name))?),
+            "skip" => slot.options.skips.push(value.parse::<Skip>().with_context(|| format!("invalid {}", name))?),
+            "emptydir" => slot.options.empty_dirs.push(PathBuf::from(value)),
+            "allowdotgit" => slot.options.allow_dot_git = Config::parse_bool(value).with_context(|| format!("invalid {}", name))?,
+            "frozenfileset" => slot.options.frozen_file_set = Config::parse_bool(value).with_context(|| format!("invalid {}", name))?,
+            _ => debug!("ignoring unknown property {}", name),
+        }
+    }
+
+    Ok(map)
+}
+
+pub struct App {
+    cache_dir: PathBuf,
+    manifest_path: Option<PathBuf>,
+    progress: bool,
+    refresh: Option<bool>,
+    credentials: Credentials,
+    backend: Box<dyn Backend>,
+    /// Caches fetched into during this run, which subcopies from the
+    /// same upstream don't need to fetch again
+    fetched: Mutex<HashSet<String>>,
+    /// The `.gitcopies` in subdirectories, by working tree, found once
+    /// per run since searching a big working tree is slow
+    nested_manifests: Mutex<HashMap<PathBuf, Vec<(PathBuf, PathBuf)>>>,
+}
+impl App {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            cache_dir: dirs::cache_dir().map(|mut path| {
+                path.push(env!("CARGO_PKG_NAME"));
+                path
+            }).ok_or_else(|| anyhow!("can't choose a cache directory"))?,
+            manifest_path: None,
+            progress: false,
+            refresh: None,
+            credentials: Credentials::default(),
+            backend: Box::new(Libgit2),
+            fetched: Mutex::default(),
+            nested_manifests: Mutex::default(),
+        })
+    }
+
+    /// Draw progress bars on stderr while cloning and copying files
+    pub fn set_progress(&mut self, progress: bool) {
+        self.progress = progress;
+    }
+
+    /// Ask for a username and password on the terminal when nothing
+    /// else can authenticate with upstream
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.credentials = Credentials::new(interactive);
+    }
+
+    /// Force fetching from upstream (`Some(true)`) or using the cache
+    /// as-is (`Some(false)`). By default, the cache is refreshed when
+    /// it's older than `subcopy.cache.ttl`, and only if the revision
+    /// needed isn't already cached.
+    pub fn set_refresh(&mut self, refresh: Option<bool>) {
+        self.refresh = refresh;
+    }
+
+    /// How long a cache is fresh after fetching, from the
+    /// `subcopy.cache.ttl` git config. Without it caches are always
+    /// refreshed.
+    fn cache_ttl(&self) -> Result<Option<Duration>> {
+        let config = match open_superproject() {
+            Ok(repo) => repo.config()?,
+            Err(_) => Config::open_default()?,
+        };
+        match config.get_string("subcopy.cache.ttl") {
+            Ok(ttl) => Ok(Some(parse_duration(&ttl).context("invalid subcopy.cache.ttl")?)),
+            Err(ref err) if err.code() == ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Whether a cached repository should be fetched into
+    fn needs_refresh(&self, repo: &Repository) -> Result<bool> {
+        if let Some(refresh) = self.refresh {
+            return Ok(refresh);
+        }
+        let ttl = match self.cache_ttl()? {
+            Some(ttl) => ttl,
+            None => return Ok(true),
+        };
+        let last_fetched = repo.config()?.get_i64(LAST_FETCHED).ok();
+        let age = last_fetched.and_then(|last| unix_time().checked_sub(last));
+        Ok(age.is_none_or(|age| age as u64 >= ttl.as_secs()))
+    }
+
+    /// Remember when a cached repository was fetched into, for
+    /// `subcopy.cache.ttl`
+    fn mark_fetched(&self, repo: &Repository) -> Result<()> {
+        repo.config()?.set_i64(LAST_FETCHED, unix_time()).context("failed to record fetch time")?;
+        Ok(())
+    }
+
+    /// Point a cache's HEAD at the upstream's default branch, like a
+    /// fresh clone does, and remember it
+    fn set_default_branch(&self, repo: &Repository, branch: &str) -> Result<()> {
+        repo.set_head(branch).context("failed to update HEAD to upstream default branch")?;
+        repo.config()?.set_str(DEFAULT_BRANCH, branch).context("failed to record default branch")?;
+        Ok(())
+    }
+
+    /// The default branch of the upstream a cache is of, like `main`,
+    /// as of when it was last fetched into
+    pub fn default_branch(&self, repo: &Repository) -> Option<String> {
+        let branch = match repo.config().and_then(|config| config.get_string(DEFAULT_BRANCH)) {
+            Ok(branch) => branch,
+            Err(_) => repo.find_reference("HEAD").ok()?.symbolic_target()?.to_owned(),
+        };
+        Some(branch.strip_prefix("refs/heads/").unwrap_or(&branch).to_owned())
+    }
+
+    /// Whether `rev` is a commit that's already in `repo`, so fetching
+    /// can be skipped. Only commit ids count, since branches and tags
+    /// can move upstream.
+    pub fn is_cached(&self, repo: &Repository, rev: &str) -> bool {
+        let is_object_id = (4..=40).contains(&rev.len()) && rev.chars().all(|c| c.is_ascii_hexdigit());
+        self.refresh != Some(true) && is_object_id && repo.revparse_single(rev).and_then(|object| object.peel_to_commit()).is_ok()
+    }
+
+    /// Like `fetch`, but doesn't touch the network when `rev` is
+    /// already cached
+    pub fn fetch_rev(&self, url: &str, rev: Option<&str>) -> Result<Repository> {
+        if let Some(rev) = rev {
+            let repo = self.fetch(url, false)?;
+            if self.is_cached(&repo, rev) {
+                debug!("{} is already cached, not fetching", rev);
+                return Ok(repo);
+            }
+        }
+        self.fetch(url, true)
+    }
+
+    /// How clones and fetches should behave towards the user
+    fn transfer(&self) -> Transfer<'_> {
+        Transfer {
+            progress: self.progress,
+            credentials: &self.credentials,
+        }
+    }
+
+    /// Options for cloning and fetching with libgit2 directly, which
+    /// report the transfer progress
+    fn fetch_options(&self) -> FetchOptions<'static> {
+        backend::fetch_options(&self.transfer())
+    }
+
+    /// Override the location of the manifest. When unset, the
+    /// `subcopy.manifestPath` git config is consulted, falling back
+    /// to `.gitcopies` in the root of the repository.
+    pub fn set_manifest_path(&mut self, path: Option<PathBuf>) {
+        self.manifest_path = path;
+    }
+
+    /// Where upstream repositories are cached
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// The root of the working tree of the repository we're in
+    pub fn workdir(&self) -> Result<PathBuf> {
+        let repo = open_superproject()?;
+        let workdir = repo.workdir().ok_or_else(|| anyhow!("repository is bare and has no workdir"))?;
+        Ok(workdir.to_path_buf())
+    }
+
+    pub fn manifest_path(&self, repo: &Repository) -> Result<PathBuf> {
+        if let Some(path) = &self.manifest_path {
+            return Ok(path.clone());
+        }
+
+        let workdir = repo.workdir().ok_or_else(|| anyhow!("repository is bare and has no workdir"))?;
+        let config = repo.config().context("failed to open repository config")?;
+        match config.get_path("subcopy.manifestPath") {
+            Ok(path) => Ok(workdir.join(path)),
+            Err(ref err) if err.code() == ErrorCode::NotFound => Ok(workdir.join(".gitcopies")),
+            Err(err) => Err(err).context("failed to read subcopy.manifestPath"),
+        }
+    }
+
+    /// Where developers can override the url or rev of subcopies for
+    /// themselves, like to point one at a local fork. It's next to the
+    /// main manifest, with `.local` appended, and shouldn't be
+    /// committed.
+    pub fn local_manifest_path(&self, repo: &Repository) -> Result<PathBuf> {
+        let mut path = self.manifest_path(repo)?.into_os_string();
+        path.push(".local");
+        Ok(PathBuf::from(path))
+    }
+
+    /// Where the blob ids of all vendored files are recorded, next to
+    /// the main manifest with `-lock` appended. Not `.lock`, since
+    /// git takes that name when writing to the manifest.
+    pub fn lock_path(&self, repo: &Repository) -> Result<PathBuf> {
+        let mut path = self.manifest_path(repo)?.into_os_string();
+        path.push("-lock");
+        Ok(PathBuf::from(path))
+    }
+
+    /// Record the current content of subcopies, given by their path
+    /// relative to the root of the working tree, in the lockfile. Does
+    /// nothing if there is no lockfile, unless `create` is set.
+    pub fn lock(&self, repo: &Repository, subcopies: &[(PathBuf, String)], create: bool) -> Result<()> {
+        let path = self.lock_path(repo)?;
+        if !create && !path.exists() {
+            return Ok(());
+        }
+        let workdir = repo.workdir().ok_or_else(|| anyhow!("repository is bare and has no workdir"))?;
+        let mut lock = Lock::open(&path)?;
+        for (local_path, rev) in subcopies {
+            let files = lock::hash_files(&workdir.join(local_path))
+                .with_context(|| format!("failed to hash files of {}", local_path.display()))?;
+            lock.entries.insert(local_path.clone(), LockEntry { rev: rev.clone(), files });
+        }
+        lock.save(&path)
+    }
+
+    /// What the lockfile recorded for a subcopy, if anything
+    pub fn locked(&self, repo: &Repository, local_path: &Path) -> Result<Option<LockEntry>> {
+        Ok(Lock::open(&self.lock_path(repo)?)?.entries.remove(local_path))
+    }
+
+    /// The entries of `.gitcopies.local`, keyed by their path relative
+    /// to the root of the working tree
+    fn local_overrides(&self, repo: &Repository) -> Result<HashMap<String, SubcopyConfigOption>> {
+        let path = self.local_manifest_path(repo)?;
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+        let mut config = Config::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+        parse_manifest(&mut config)
+    }
+
+    /// All manifests in the repository, with the directory their keys
+    /// are relative to. The main manifest comes first, followed by any
+    /// `.gitcopies` in subdirectories, so parts of a monorepo can
+    /// manage their own subcopies. Manifests that are ignored, or that
+    /// were copied in from upstream as part of a subcopy, don't count.
+    fn manifests(&self, repo: &Repository) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let main = self.manifest_path(repo)?;
+        let mut nested = self.nested_manifests(repo)?;
+        nested.insert(0, (main, PathBuf::new()));
+
+        let mut manifests = Vec::new();
+        let mut subcopies: Vec<PathBuf> = Vec::new();
+        for (path, dir) in nested {
+            if subcopies.iter().any(|subcopy| dir.starts_with(subcopy)) {
+                debug!("ignoring {}, it's part of a subcopy", path.display());
+                continue;
+            }
+            if path.exists() {
+                let mut config = Config::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+                subcopies.extend(parse_manifest(&mut config)?.into_values().map(|conf| dir.join(conf.local_path)));
+            }
+            manifests.push((path, dir));
+        }
+        Ok(manifests)
+    }
+
+    /// The `.gitcopies` in subdirectories, other than the main manifest,
+    /// shallowest first
+    fn nested_manifests(&self, repo: &Repository) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let workdir = repo.workdir().ok_or_else(|| anyhow!("repository is bare and has no workdir"))?;
+        if let Some(nested) = self.nested_manifests.lock().expect("nested manifests lock poisoned").get(workdir) {
+            return Ok(nested.clone());
+        }
+        let main_full = self.manifest_path(repo)?.canonicalize().ok();
+
+        let mut nested = Vec::new();
+        // Not min_depth, since entries it skips aren't passed to
+        // filter_entry, and the top-level .git would be searched
+        let walker = WalkDir::new(workdir).into_iter().filter_entry(|entry| {
+            let relative = entry.path().strip_prefix(workdir).unwrap_or_else(|_| entry.path());
+            entry.file_name() != ".git" && !repo.is_path_ignored(relative).unwrap_or(false)
+        });
+        for entry in walker {
+            let entry = entry.context("failed to search for manifests")?;
+            if entry.depth() < 2 || entry.file_name() != ".gitcopies" || !entry.file_type().is_file() {
+                continue;
+            }
+            if main_full.is_some() && entry.path().canonicalize().ok() == main_full {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(workdir).context("walkdir should always have prefix")?;
+            let dir = relative.parent().expect("depth should ensure a parent").to_path_buf();
+            nested.push((entry.path().to_path_buf(), dir));
+        }
+        nested.sort_by_key(|(_, dir)| dir.components().count());
+
+        self.nested_manifests.lock().expect("nested manifests lock poisoned").insert(workdir.to_path_buf(), nested.clone());
+        Ok(nested)
+    }
+
+    /// The manifest a subcopy at `relative` (to the root of the working
+    /// tree) belongs in, and its key there. That's the manifest it's
+    /// already in, or else the one nearest to it.
+    pub fn manifest_for(&self, repo: &Repository, relative: &Path) -> Result<(PathBuf, PathBuf)> {
+        let manifests = self.manifests(repo)?;
+        for (path, dir) in &manifests {
+            if !path.exists() || !relative.starts_with(dir) {
+                continue;
+            }
+            let key = relative.strip_prefix(dir).expect("checked by starts_with above");
+            let mut config = Config::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+            if parse_manifest(&mut config)?.contains_key(path_to_string(key)?) {
+                return Ok((path.clone(), key.to_path_buf()));
+            }
+        }
+
+        let (path, dir) = manifests.into_iter()
+            .filter(|(_, dir)| relative.starts_with(dir) && relative != dir)
+            .max_by_key(|(_, dir)| dir.components().count())
+            .expect("the main manifest contains everything");
+        let key = relative.strip_prefix(&dir).expect("filtered by prefix above").to_path_buf();
+        Ok((path, key))
+    }
+
+    /// Open the manifest a subcopy belongs in, see `manifest_for`
+    fn open_manifest_for(&self, repo: &Repository, relative: &Path) -> Result<(Config, String)> {
+        let (path, key) = self.manifest_for(repo, relative)?;
+        let config = Config::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+        Ok((config, path_to_string(&key)?.to_owned()))
+    }
+
+    /// Turn a source as written by the user or in `.gitcopies` into
+    /// something git can clone, by resolving aliases and shorthands
+    pub fn resolve_url(&self, source: &str) -> Result<String> {
+        if sources::is_alias_name(source) {
+            let repo = open_superproject().ok();
+            if let Some(url) = sources::find_alias(repo.as_ref(), source)? {
+                debug!("resolved alias {} to {}", source, url);
+                return Ok(self.absolute_source(sources::expand(&url).into_owned()));
+            }
+        }
+        Ok(self.absolute_source(sources::expand(source).into_owned()))
+    }
+
+    /// Relative paths as sources are relative to the root of the
+    /// working tree, wherever we're run from. This also gives them the
+    /// same cache as when written as an absolute path.
+    fn absolute_source(&self, url: String) -> String {
+        if !sources::is_relative_path(&url) {
+            return url;
+        }
+        match self.workdir() {
+            Ok(workdir) => {
+                let path = workdir.join(&url);
+                path.canonicalize().unwrap_or(path).to_string_lossy().into_owned()
+            },
+            Err(_) => url,
+        }
+    }
+
+    /// The other way around, a relative path as typed in the current
+    /// directory turned into one relative to the root of the working
+    /// tree, as it's saved in `.gitcopies`. Anything else is returned
+    /// unchanged.
+    pub fn relative_source(&self, url: &str) -> Result<String> {
+        if !sources::is_relative_path(url) || sources::is_alias_name(url) || url.contains("${") {
+            return Ok(url.to_owned());
+        }
+        let path = Path::new(url).canonicalize().with_context(|| format!("failed to find {}", url))?;
+        let workdir = self.workdir()?.canonicalize().context("failed to get full path of repository")?;
+        match relative_to(&path, &workdir) {
+            relative if relative.as_os_str().is_empty() => Ok(String::from(".")),
+            relative => Ok(path_to_string(&relative)?.to_owned()),
+        }
+    }
+
+    pub fn fetch(&self, url: &str, update_existing: bool) -> Result<Repository> {
+        if sources::is_self(url) {
+            return open_superproject().context("failed to open repository");
+        }
+        let url = &*self.resolve_url(url)?;
+        let _span = span::enter("fetch", url);
+        let key = base64::encode_config(&sources::normalize(url), base64::URL_SAFE_NO_PAD);
+        let path = self.cache_dir.join(&key);
+        self.remove_stale_clones(&key)?;
+        self.merge_duplicate_caches(url, &key)?;
+
+        // Caches from before clones were made atomic may be half-written
+        if path.exists() && Repository::open_bare(&path).map_or(true, |repo| repo.head().is_err()) {
+            warn!("removing incomplete cache of {}", url);
+            fs::remove_dir_all(&path).context("failed to remove incomplete cache")?;
+        }
+
+        if let Some(bundle) = sources::bundle_path(url) {
+            let fetched = self.fetched.lock().expect("fetched caches lock poisoned").contains(&key);
+            if !path.exists() || (update_existing && !fetched && self.needs_refresh(&Repository::open_bare(&path)?)?) {
+                info!("Reading bundle {}...", bundle.display());
+                self.import_bundle(url, bundle)?;
+            }
+            return Repository::open_bare(&path).context("failed to open cached bare repository");
+        }
+
+        if path.exists() {
+            let repo = Repository::open_bare(&path).context("failed to open cached bare repository")?;
+
+            let fetched = self.fetched.lock().expect("fetched caches lock poisoned").contains(&key);
+            if update_existing && !fetched && self.needs_refresh(&repo)? {
+                info!("Fetching upstream in existing repository...");
+                sources::ensure_supported(url)?;
+                let default_branch = self.backend.fetch(&sources::expand_env(url)?, &path, &self.transfer())?;
+                self.credentials.approve()?;
+
+                // Follow the upstream's default branch, like a fresh clone does
+                if let Some(branch) = default_branch {
+                    self.set_default_branch(&repo, &branch)?;
+                }
+                self.mark_fetched(&repo)?;
+                self.fetched.lock().expect("fetched caches lock poisoned").insert(key);
+            }
+            Ok(repo)
+        } else {
+            info!("Cloning new repository...");
+            sources::ensure_supported(url)?;
+
+            // Clone next to the cache and move it in place when done, so
+            // an interrupted clone never looks like a complete one
+            fs::create_dir_all(&self.cache_dir).context("failed to create cache directory")?;
+            let partial = Builder::new()
+                .prefix(&format!(".{}.partial", key))
+                .tempdir_in(&self.cache_dir)
+                .context("failed to create temporary directory for cloning")?;
+            self.backend.clone_bare(&sources::expand_env(url)?, url, partial.path(), &self.transfer())?;
+            self.credentials.approve()?;
+            fs::rename(partial.into_path(), &path).context("failed to move clone into the cache")?;
+            let repo = Repository::open_bare(&path).context("failed to open cached bare repository")?;
+            let head = repo.find_reference("HEAD").ok().and_then(|head| head.symbolic_target().map(String::from));
+            if let Some(branch) = head {
+                self.set_default_branch(&repo, &branch)?;
+            }
+            self.mark_fetched(&repo)?;
+            self.fetched.lock().expect("fetched caches lock poisoned").insert(key);
+            Ok(repo)
+        }
+    }
+
+    /// Fetch several upstreams, at most `jobs` at a time, so that later
+    /// calls to `fetch` during this run don't have to. The user is
+    /// never asked for credentials. Returns the errors of the ones that
+    /// failed.
+    pub fn fetch_all(&mut self, urls: &[&str], jobs: usize) -> Vec<(String, anyhow::Error)> {
+        let mut seen = HashSet::new();
+        let urls: Vec<&str> = urls.iter().copied().filter(|url| seen.insert(sources::normalize(url))).collect();
+
+        // Progress bars of fetches running side by side would overwrite
+        // each other, and so would password prompts. Upstreams that need
+        // one fail here, and are asked for when fetched again later.
+        let progress = std::mem::replace(&mut self.progress, false);
+        let credentials = std::mem::replace(&mut self.credentials, Credentials::new(false));
+        let app = &*self;
+        let queue = Mutex::new(urls.into_iter());
+        let errors = Mutex::new(Vec::new());
+        let spans = span::current();
+        thread::scope(|scope| {
+            for _ in 0..jobs.max(1) {
+                scope.spawn(|| {
+                    let _spans = span::enter_all(&spans);
+                    loop {
+                        let url = match queue.lock().expect("fetch queue lock poisoned").next() {
+                            Some(url) => url,
+                            None => break,
+                        };
+                        match app.fetch(url, true) {
+                            Ok(_) => info!("Fetched {}", url),
+                            Err(err) => errors.lock().expect("fetch errors lock poisoned").push((url.to_owned(), err)),
+                        }
+                    }
+                });
+            }
+        });
+        self.progress = progress;
+        self.credentials = credentials;
+        errors.into_inner().expect("fetch errors lock poisoned")
+    }
+
+    /// Push every branch and tag in the cache of an upstream to another
+    /// remote, like an internal mirror. The cache is fetched first, so
+    /// the mirror isn't behind.
+    pub fn mirror(&self, url: &str, to: &str) -> Result<()> {
+        let repo = self.fetch(url, true)?;
+        let mut refspecs = Vec::new();
+        for reference in repo.references().context("failed to list cached refs")? {
+            let reference = reference.context("failed to read cached ref")?;
+            if let Some(name) = reference.name().filter(|name| name.starts_with("refs/heads/") || name.starts_with("refs/tags/")) {
+                refspecs.push(format!("+{0}:{0}", name));
+            }
+        }
+        let refspecs: Vec<&str> = refspecs.iter().map(String::as_str).collect();
+
+        sources::ensure_supported(to)?;
+        let mut remote = repo.remote_anonymous(&sources::expand_env(to)?).context("failed to create anonymous remote")?;
+        let mut rejected = Vec::new();
+        {
+            let mut callbacks = RemoteCallbacks::new();
+            callbacks.credentials(self.credentials.handler());
+            callbacks.push_update_reference(|name, status| {
+                if let Some(status) = status {
+                    rejected.push(format!("{} ({})", name, status));
+                }
+                Ok(())
+            });
+            let mut options = PushOptions::new();
+            options.remote_callbacks(callbacks);
+            remote.push(&refspecs, Some(&mut options)).context("failed to push to mirror")?;
+        }
+        self.credentials.approve()?;
+        ensure!(rejected.is_empty(), "mirror rejected {}", rejected.join(", "));
+        Ok(())
+    }
+
+    /// Write every branch and tag in the cache of an upstream to a git
+    /// bundle, for `import_bundle` on a machine without network access
+    pub fn export_bundle(&self, url: &str, bundle: &Path) -> Result<()> {
+        let repo = self.fetch(url, true)?;
+        let args: [&OsStr; 6] = ["bundle".as_ref(), "create".as_ref(), bundle.as_ref(), "HEAD".as_ref(), "--branches".as_ref(), "--tags".as_ref()];
+        git_in(repo.path(), &args).context("failed to create bundle")
+    }
+
+    /// Fill the cache of an upstream from a git bundle instead of
+    /// fetching, creating it if needed. It counts as fetched, so this
+    /// run and the cache TTL don't fetch again.
+    pub fn import_bundle(&self, url: &str, bundle: &Path) -> Result<()> {
+        let url = &*self.resolve_url(url)?;
+        let key = base64::encode_config(&sources::normalize(url), base64::URL_SAFE_NO_PAD);
+        let path = self.cache_dir.join(&key);
+        self.remove_stale_clones(&key)?;
+        self.merge_duplicate_caches(url, &key)?;
+        let bundle = bundle.canonicalize().with_context(|| format!("failed to find {}", bundle.display()))?;
+
+        if path.exists() {
+            let args: [&OsStr; 5] = ["fetch".as_ref(), "--quiet".as_ref(), bundle.as_ref(), "+refs/heads/*:refs/heads/*".as_ref(), "+refs/tags/*:refs/tags/*".as_ref()];
+            git_in(&path, &args).context("failed to fetch from bundle")?;
+        } else {
+            fs::create_dir_all(&self.cache_dir).context("failed to create cache directory")?;
+            let partial = Builder::new()
+                .prefix(&format!(".{}.partial", key))
+                .tempdir_in(&self.cache_dir)
+                .context("failed to create temporary directory for cloning")?;
+            let status = Command::new("git")
+                .args(["clone", "--bare", "--quiet"])
+                .arg(&bundle)
+                .arg(partial.path())
+                .status()
+                .context("failed to run git")?;
+            ensure!(status.success(), "failed to clone bundle, git exited with {}", status);
+            Repository::open_bare(partial.path()).context("failed to open new clone")?
+                .remote_set_url("origin", url).context("failed to set clone's remote url")?;
+            fs::rename(partial.into_path(), &path).context("failed to move clone into the cache")?;
+        }
+        let repo = Repository::open_bare(&path).context("failed to open cached bare repository")?;
+        self.mark_fetched(&repo)?;
+        self.fetched.lock().expect("fetched caches lock poisoned").insert(key);
+        Ok(())
+    }
+
+    /// Caches used to be keyed by the URL exactly as written, so the
+    /// same repository could be cloned several times. Move one of
+    /// those to the normalized key, and remove the rest.
+    fn merge_duplicate_caches(&self, url: &str, key: &str) -> Result<()> {
+        let entries = match fs::read_dir(&self.cache_dir) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).context("failed to read cache directory"),
+        };
+        let normalized = sources::normalize(url);
+        let path = self.cache_dir.join(key);
+        for entry in entries {
+            let entry = entry.context("failed to read cache directory entry")?;
+            let name = entry.file_name();
+            let cached_url = name.to_str()
+                .and_then(|name| base64::decode_config(name, base64::URL_SAFE_NO_PAD).ok())
+                .and_then(|url| String::from_utf8(url).ok());
+            let cached_url = match cached_url {
+                Some(cached_url) if name != key && sources::normalize(&cached_url) == normalized => cached_url,
+                _ => continue,
+            };
+
+            if path.exists() {
+                debug!("removing duplicate cache of {}", cached_url);
+                fs::remove_dir_all(entry.path()).context("failed to remove duplicate cache")?;
+            } else {
+                debug!("moving cache of {} to {}", cached_url, normalized);
+                fs::rename(entry.path(), &path).context("failed to move cache")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove clones of `key` that were left behind by being killed.
+    /// Only old ones are removed, since new ones may still be running.
+    fn remove_stale_clones(&self, key: &str) -> Result<()> {
+        const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+        let entries = match fs::read_dir(&self.cache_dir) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).context("failed to read cache directory"),
+        };
+        let prefix = format!(".{}.partial", key);
+        for entry in entries {
+            let entry = entry.context("failed to read cache directory entry")?;
+            if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+                continue;
+            }
+            let age = entry.metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok());
+            if age.is_some_and(|age| age > STALE_AFTER) {
+                debug!("removing stale partial clone {}", entry.path().display());
+                fs::remove_dir_all(entry.path()).context("failed to remove stale partial clone")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the object at a path in the tree of an upstream revision.
+    /// An empty path refers to the whole tree.
+    pub fn find_upstream<'r>(&self, repo: &'r Repository, rev: Oid, upstream_path: &Path) -> Result<Object<'r>> {
+        let tree = repo.find_object(rev, None).context("failed to find object at revision")?
+            .peel_to_tree().context("failed to turn object into a tree")?;
+        let upstream_path = normalize_upstream_path(upstream_path);
+        if upstream_path.as_os_str().is_empty() {
+            return Ok(tree.into_object());
+        }
+        let entry = tree.get_path(&upstream_path).context("failed to get path")?;
+        entry.to_object(repo).context("failed to get path's object")
+    }
+
+    pub fn extract(&self, repo: &'_ Repository, rev: Oid, upstream_path: &Path, local_path: &Path, options: &CopyOptions) -> Result<()> {
+        let _span = span::enter("extract", local_path.display());
+        info!("Extracting files...");
+
+        let object = self.find_upstream(repo, rev, upstream_path)?;
+        let url = cached_url(repo);
+        let origin = Origin { url: &url, rev };
+        self.extract_object(repo, &object, origin, &normalize_upstream_path(upstream_path), local_path, options)
+    }
+
+    /// Write a blob or a whole tree to `local_path`, passing files
+    /// through the filters. `upstream_path` is where the object is in
+    /// upstream, at `origin`. Either all files are written or, if
+    /// anything fails, none of them.
+    fn extract_object(
+        &self,
+        repo: &Repository,
+        object: &Object,
+        origin: Origin,
+        upstream_path: &Path,
+        local_path: &Path,
+        options: &CopyOptions,
+    ) -> Result<()> {
+        if object.kind() == Some(ObjectType::Blob) {
+            // A single file is written to exactly the local path, which
+            // lets it be renamed
+            ensure!(!local_path.is_dir(), "{} is a directory, pass the name of the file to copy to", local_path.display());
+        } else {
+            ensure!(!local_path.is_file(), "{} is a file, but upstream is a directory", local_path.display());
+        }
+        if let Ok(tree) = object.peel_to_tree() {
+            let dir = local_path.ancestors().find(|dir| dir.is_dir()).unwrap_or_else(|| Path::new("."));
+            if is_case_insensitive(dir) {
+                let collisions = case_collisions(repo, &tree, options)?;
+                if !collisions.is_empty() {
+                    let listing: Vec<String> = collisions.iter().flatten().map(|path| format!("    {}", path.display())).collect();
+                    bail!(
+                        "upstream has files whose names only differ in case, which this filesystem can't tell apart:\n{}\n\
+                         rename or leave out some of them with --remap or --skip",
+                        listing.join("\n"),
+                    );
+                }
+            }
+        }
+        let staging = Staging::new(local_path)?;
+        self.write_object(repo, object, origin, upstream_path, &staging, options)?;
+        staging.commit()?;
+        if object.kind() == Some(ObjectType::Tree) {
+            create_empty_dirs(local_path, options)?;
+        }
+        Ok(())
+    }
+
+    /// Write an object to `staging`. Files that are already the same in
+    /// its destination aren't written at all, so they keep their
+    /// modification time and build systems don't rebuild them.
+    fn write_object(
+        &self,
+        repo: &Repository,
+        object: &Object,
+        origin: Origin,
+        upstream_path: &Path,
+        staging: &Staging,
+        options: &CopyOptions,
+    ) -> Result<()> {
+        let (staged, local_path) = (&staging.path(), staging.destination());
+        if let Ok(blob) = object.peel_to_blob() {
+            let content = filter::smudge(&options.filters, Some(origin), upstream_path, blob.content())?;
+            if !is_unchanged(local_path, &content) {
+                write_file(staged, &content)?;
+            }
+        } else {
+            let tree = object.peel_to_tree()?;
+            fs::create_dir_all(staged)?;
+            let mut progress = Progress::new(self.progress, "Writing files");
+            let mut written = 0;
+            walk_tree(repo, &tree, |path, entry| {
+                interrupt::check()?;
+
+                // Vendored repositories would confuse git, it'd think the
+                // content is a separate repository
+                if !options.allow_dot_git && path.components().any(|c| c.as_os_str() == ".git") {
+                    if entry.name() == Some(".git") {
+                        warn!("skipping {}, use --allow-dot-git to extract it", upstream_path.join(&path).display());
+                    }
+                    return Ok(());
+                }
+                if skip::is_skipped(&options.skips, &path) {
+                    return Ok(());
+                }
+                // Only one blob is loaded at a time, and let go of as
+                // soon as it's written
+                if entry.kind() == Some(ObjectType::Blob) {
+                    let blob = repo.find_blob(entry.id()).context("failed to find blob")?;
+                    let content = filter::smudge(&options.filters, Some(origin), &upstream_path.join(&path), blob.content())?;
+                    let path = remap::to_local(&options.remap, &path);
+                    if !is_unchanged(&join_inside(local_path, &path)?, &content) {
+                        let path = join_inside(staged, &path)?;
+                        if let Some(parent) = path.parent() {
+                            fs::create_dir_all(parent)?;
+                        }
+                        write_file(&path, &content)?;
+                    }
+
+                    written += 1;
+                    progress.update(written, None, None);
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Read back what `extract` wrote and compare it to what it should
+    /// have written, to catch the filesystem changing content behind
+    /// our back, like line ending conversion, case-insensitive names
+    /// colliding, or a full disk truncating files. Returns the local
+    /// files that don't match.
+    pub fn verify_extracted(&self, repo: &Repository, rev: Oid, upstream_path: &Path, local_path: &Path, options: &CopyOptions) -> Result<Vec<PathBuf>> {
+        let upstream_path = normalize_upstream_path(upstream_path);
+        let object = self.find_upstream(repo, rev, &upstream_path)?;
+        let url = cached_url(repo);
+        let expected = |upstream_path: &Path, blob: &git2::Blob| -> Result<Oid> {
+            if options.filters.is_empty() {
+                return Ok(blob.id());
+            }
+            let content = filter::smudge(&options.filters, Some(Origin { url: &url, rev }), upstream_path, blob.content())?;
+            Ok(Oid::hash_object(ObjectType::Blob, &content)?)
+        };
+        let matches = |path: &Path, expected: Oid| Oid::hash_file(ObjectType::Blob, path).ok() == Some(expected);
+
+        let mut mismatched = Vec::new();
+        if let Ok(blob) = object.peel_to_blob() {
+            if !matches(local_path, expected(&upstream_path, &blob)?) {
+                mismatched.push(local_path.to_path_buf());
+            }
+            return Ok(mismatched);
+        }
+
+        let tree = object.peel_to_tree()?;
+        walk_tree(repo, &tree, |path, entry| {
+            let dot_git = !options.allow_dot_git && path.components().any(|c| c.as_os_str() == ".git");
+            if dot_git || skip::is_skipped(&options.skips, &path) || entry.kind() != Some(ObjectType::Blob) {
+                return Ok(());
+            }
+            let blob = entry.to_object(repo)?.peel_to_blob()?;
+            let local = join_inside(local_path, &remap::to_local(&options.remap, &path))?;
+            if !matches(&local, expected(&upstream_path.join(&path), &blob)?) {
+                mismatched.push(local);
+            }
+            Ok(())
+        })?;
+        Ok(mismatched)
+    }
+
+    /// Recursively list everything under a directory in an upstream
+    /// revision, in pre-order
+    pub fn upstream_entries(&self, repo: &Repository, rev: Oid, upstream_path: &Path) -> Result<Vec<UpstreamEntry>> {
+        let tree = self.find_upstream(repo, rev, upstream_path)?
+            .peel_to_tree().context("upstream path is not a directory")?;
+
+        let mut entries = Vec::new();
+        walk_tree(repo, &tree, |path, entry| {
+            entries.push(UpstreamEntry {
+                path,
+                id: entry.id(),
+                kind: entry.kind(),
+                filemode: entry.filemode(),
+            });
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    /// The total size of all files under `upstream_path` at a
+    /// revision, without reading their content
+    pub fn upstream_size(&self, repo: &Repository, rev: Oid, upstream_path: &Path) -> Result<u64> {
+        let odb = repo.odb().context("failed to open object database")?;
+        let object = self.find_upstream(repo, rev, upstream_path)?;
+        let tree = match object.peel_to_tree() {
+            Ok(tree) => tree,
+            Err(_) => return Ok(odb.read_header(object.id())?.0 as u64),
+        };
+
+        let mut size = 0;
+        walk_tree(repo, &tree, |_, entry| {
+            if entry.kind() == Some(ObjectType::Blob) {
+                size += odb.read_header(entry.id()).context("failed to read object header")?.0 as u64;
+            }
+            Ok(())
+        })?;
+        Ok(size)
+    }
+
+    /// Search all text files under `upstream_path` at a revision for
+    /// lines matching `pattern`. Calls `found` with the file's path in
+    /// the upstream repository, the line number, and the line itself.
+    pub fn grep<F>(&self, repo: &Repository, rev: Oid, upstream_path: &Path, pattern: &Regex, mut found: F) -> Result<()>
+    where
+        F: FnMut(&Path, usize, &str),
+    {
+        let mut search = |path: &Path, object: &Object| {
+            if let Ok(blob) = object.peel_to_blob() {
+                if blob.is_binary() {
+                    return;
+                }
+                let content = String::from_utf8_lossy(blob.content());
+                for (i, line) in content.lines().enumerate() {
+                    if pattern.is_match(line) {
+                        found(path, i + 1, line);
+                    }
+                }
+            }
+        };
+
+        let object = self.find_upstream(repo, rev, upstream_path)?;
+        match object.peel_to_tree() {
+            Ok(tree) => walk_tree(repo, &tree, |path, entry| {
+                search(&upstream_path.join(path), &entry.to_object(repo)?);
+                Ok(())
+            }),
+            Err(_) => {
+                search(upstream_path, &object);
+                Ok(())
+            },
+        }
+    }
+
+    /// Store the current content of a local file or directory as a
+    /// blob or tree in `repo`, laid out like upstream, so git can
+    /// compare it to upstream. Any `.git` directories are left out.
+    /// Filters are undone using `base`, the upstream content the local
+    /// copy was extracted from.
+    pub fn write_local(
+        &self,
+        repo: &Repository,
+        upstream_path: &Path,
+        local_path: &Path,
+        base: Option<&Object>,
+        options: &CopyOptions,
+    ) -> Result<Oid> {
+        let store = |path: &Path, upstream_path: &Path, base: Option<Object>| -> Result<Oid> {
+            if options.filters.is_empty() {
+                return repo.blob_path(path).with_context(|| format!("failed to store {}", path.display()));
+            }
+            let content = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+            let base = base.and_then(|base| base.into_blob().ok());
+            let content = filter::clean(&options.filters, upstream_path, &content, base.as_ref().map(|blob| blob.content()))?;
+            repo.blob(&content).with_context(|| format!("failed to store {}", path.display()))
+        };
+
+        let metadata = fs::symlink_metadata(local_path)
+            .with_context(|| format!("failed to stat {}", local_path.display()))?;
+        if !metadata.is_dir() {
+            return store(local_path, upstream_path, base.cloned());
+        }
+        let base_tree = base.and_then(|base| base.as_tree());
+
+        let mut files = Vec::new();
+        for entry in WalkDir::new(local_path).min_depth(1).into_iter().filter_entry(|e| e.file_name() != ".git") {
+            let entry = entry.context("failed to read directory entry")?;
+            let path = entry.path();
+            let relative = path.strip_prefix(local_path).context("walkdir should always have prefix")?;
+            let relative = &*unicode::precompose(relative);
+            let file_type = entry.file_type();
+
+            let (id, mode) = if file_type.is_dir() {
+                continue;
+            } else if file_type.is_symlink() {
+                let target = fs::read_link(path).context("failed to read symlink")?;
+                (repo.blob(path_to_string(&target)?.as_bytes())?, 0o120000)
+            } else {
+                let metadata = entry.metadata().context("failed to stat file")?;
+                let upstream_relative = remap::to_upstream(&options.remap, relative);
+                let base = base_tree
+                    .and_then(|tree| tree.get_path(&upstream_relative).ok())
+                    .and_then(|entry| entry.to_object(repo).ok());
+                (store(path, &upstream_path.join(&upstream_relative), base)?, filemode(&metadata))
+            };
+            files.push((remap::to_upstream(&options.remap, relative), id, mode));
+        }
+
+        // Skipped files are missing on purpose, so pretend they're
+        // there as they are in upstream. With a frozen file set, so is
+        // any missing file.
+        if let Some(base_tree) = base_tree.filter(|_| !options.skips.is_empty() || options.frozen_file_set) {
+            let present: HashSet<PathBuf> = files.iter().map(|(path, _, _)| path.clone()).collect();
+            walk_tree(repo, base_tree, |path, entry| {
+                let left_out = options.frozen_file_set || skip::is_skipped(&options.skips, &path);
+                if entry.kind() != Some(ObjectType::Tree) && left_out && !present.contains(&path) {
+                    files.push((path, entry.id(), entry.filemode()));
+                }
+                Ok(())
+            })?;
+        }
+        build_tree(repo, files)
+    }
+
+    /// The files of a local copy that differ from upstream at `rev`,
+    /// relative to the local copy
+    pub fn local_changes(&self, repo: &Repository, rev: Oid, upstream_path: &Path, local_path: &Path, options: &CopyOptions) -> Result<Vec<PathBuf>> {
+        let base = self.find_upstream(repo, rev, upstream_path)?;
+        let file_name = || vec![PathBuf::from(local_path.file_name().unwrap_or_default())];
+        if !local_path.exists() {
+            return Ok(file_name());
+        }
+        let ours = self.write_local(repo, upstream_path, local_path, Some(&base), options)?;
+        if base.kind() == Some(ObjectType::Blob) {
+            return Ok(if ours == base.id() { Vec::new() } else { file_name() });
+        }
 
-pub struct App {
-    cache_dir: PathBuf,
-}
-impl App {
-    pub fn new() -> Result<Self> {
-        Ok(Self {
-            cache_dir: dirs::cache_dir().map(|mut path| {
-                path.push(env!("CARGO_PKG_NAME"));
-                path
-            }).ok_or_else(|| anyhow!("can't choose a cache directory"))?,
-        })
+        let diff = repo.diff_tree_to_tree(base.as_tree(), Some(&repo.find_tree(ours)?), None)
+            .context("failed to diff local copy against upstream")?;
+        let mut paths: Vec<PathBuf> = diff.deltas()
+            .filter_map(|delta| delta.new_file().path().or_else(|| delta.old_file().path()))
+            .map(|path| remap::to_local(&options.remap, path))
+            .collect();
+        paths.sort();
+        paths.dedup();
+        Ok(paths)
     }
 
-    pub fn fetch(&self, url: &str, update_existing: bool) -> Result<Repository> {
-        let path = self.cache_dir.join(base64::encode_config(url, base64::URL_SAFE_NO_PAD));
+    /// Diff two versions of a subcopy's content, both trees or both
+    /// blobs. Single files are wrapped in a tree under `name`, so they
+    /// have a path in the diff.
+    pub fn diff_content<'r>(&self, repo: &'r Repository, old: Oid, new: Oid, name: &str) -> Result<Diff<'r>> {
+        let as_tree = |id: Oid| -> Result<Tree> {
+            if repo.find_object(id, None)?.kind() == Some(ObjectType::Tree) {
+                return Ok(repo.find_tree(id)?);
+            }
+            let mut builder = repo.treebuilder(None)?;
+            builder.insert(name, id, 0o100644)?;
+            Ok(repo.find_tree(builder.write()?)?)
+        };
+        repo.diff_tree_to_tree(Some(&as_tree(old)?), Some(&as_tree(new)?), None).context("failed to diff content")
+    }
 
-        if path.exists() {
-            let repo = Repository::open_bare(&path).context("failed to open cached bare repository")?;
+    /// The commits between `old` and `new` that changed anything under
+    /// `upstream_path`, oldest first
+    pub fn upstream_commits(&self, repo: &Repository, old: Oid, new: Oid, upstream_path: &Path) -> Result<Vec<Oid>> {
+        let mut walk = repo.revwalk().context("failed to walk history")?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE);
+        walk.push(new)?;
+        walk.hide(old)?;
 
-            if update_existing {
-                info!("Fetching upstream in existing repository...");
-                repo.remote_anonymous(url).context("failed to create anonymous remote")?
-                    .fetch(&[], None, None).context("failed to fetch from anonymous remote")?;
+        let content_at = |id: Oid| self.find_upstream(repo, id, upstream_path).ok().map(|object| object.id());
+        let mut commits = Vec::new();
+        for id in walk {
+            let id = id?;
+            let commit = repo.find_commit(id)?;
+            let before = commit.parent_ids().next().and_then(content_at);
+            if content_at(id) != before {
+                commits.push(id);
             }
-            Ok(repo)
-        } else {
-            info!("Cloning new repository...");
-            Ok(RepoBuilder::new()
-               .bare(true)
-               .clone(url, &path)
-               .context("failed to clone repository")?)
         }
+        Ok(commits)
     }
 
-    pub fn extract(&self, repo: &'_ Repository, rev: Oid, upstream_path: &Path, local_path: &Path) -> Result<()> {
-        info!("Extracting files...");
+    /// Rank upstream commits by how close their content at
+    /// `upstream_path` is to a local copy made without git-subcopy, by
+    /// counting the local files that are exactly the same in them. The
+    /// best come first, and ties keep the order of `candidates`.
+    pub fn rank_revs(&self, repo: &Repository, candidates: &[Oid], upstream_path: &Path, local_path: &Path) -> Result<Vec<(Oid, usize)>> {
+        let local = lock::hash_files(local_path)?;
 
-        let tree = repo.find_object(rev, None).context("failed to find object at revision")?
-            .peel_to_tree().context("failed to turn object into a tree")?;
-        let entry = tree.get_path(upstream_path).context("failed to get path")?;
-        let object = entry.to_object(&repo).context("failed to get path's object")?;
+        // Most commits don't touch the part of upstream we're looking at
+        let mut scores: HashMap<Oid, usize> = HashMap::new();
+        let mut ranked = Vec::new();
+        for &rev in candidates {
+            let object = match self.find_upstream(repo, rev, upstream_path) {
+                Ok(object) => object,
+                Err(_) => continue,
+            };
+            let score = match scores.get(&object.id()) {
+                Some(&score) => score,
+                None => {
+                    let score = match object.as_tree() {
+                        Some(tree) => local.iter()
+                            .filter(|(path, id)| tree.get_path(path).is_ok_and(|entry| entry.id() == **id))
+                            .count(),
+                        None => local.values().filter(|id| **id == object.id()).count(),
+                    };
+                    scores.insert(object.id(), score);
+                    score
+                },
+            };
+            ranked.push((rev, score));
+        }
+        ranked.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+        Ok(ranked)
+    }
 
-        if let Ok(blob) = object.peel_to_blob() {
-            fs::write(local_path, blob.content()).context("failed to write file")?;
+    /// Rank every commit on any branch or tag of upstream by how close
+    /// it is to a local copy, like `rank_revs`, to find which revision
+    /// code vendored by hand was copied from
+    pub fn detect_rev(&self, repo: &Repository, upstream_path: &Path, local_path: &Path) -> Result<Vec<(Oid, usize)>> {
+        let mut walk = repo.revwalk().context("failed to walk history")?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME);
+        walk.push_glob("refs/heads/*")?;
+        walk.push_glob("refs/tags/*")?;
+        let candidates = walk.collect::<Result<Vec<Oid>, _>>()?;
+        self.rank_revs(repo, &candidates, upstream_path, local_path)
+    }
+
+    /// The commits reachable from `rev` that changed how many times
+    /// `needle` occurs in some file under `upstream_path`, like `git log
+    /// -S`, newest first
+    pub fn pickaxe(&self, repo: &Repository, rev: Oid, upstream_path: &Path, needle: &[u8]) -> Result<Vec<Oid>> {
+        ensure!(!needle.is_empty(), "can't search for an empty string");
+        // Submodules and deleted files have nothing to count
+        let count = |id: Option<Oid>| {
+            id.and_then(|id| repo.find_blob(id).ok())
+                .map_or(0, |blob| blob.content().windows(needle.len()).filter(|window| *window == needle).count())
+        };
+        let content_at = |id: Oid| self.find_upstream(repo, id, upstream_path).ok();
+
+        let mut walk = repo.revwalk().context("failed to walk history")?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME);
+        walk.push(rev)?;
+        let mut commits = Vec::new();
+        for id in walk {
+            let id = id?;
+            let commit = repo.find_commit(id)?;
+            let (before, after) = (commit.parent_ids().next().and_then(content_at), content_at(id));
+            if before.as_ref().map(Object::id) == after.as_ref().map(Object::id) {
+                continue;
+            }
+            let is_blob = |object: &Option<Object>| object.as_ref().is_some_and(|object| object.kind() == Some(ObjectType::Blob));
+            let files: Vec<(Option<Oid>, Option<Oid>)> = if is_blob(&before) || is_blob(&after) {
+                vec![(before.as_ref().map(Object::id), after.as_ref().map(Object::id))]
+            } else {
+                let old = before.and_then(|object| object.into_tree().ok());
+                let new = after.and_then(|object| object.into_tree().ok());
+                let diff = repo.diff_tree_to_tree(old.as_ref(), new.as_ref(), None).context("failed to diff upstream commits")?;
+                diff.deltas().map(|delta| (Some(delta.old_file().id()), Some(delta.new_file().id()))).collect()
+            };
+            if files.into_iter().any(|(old, new)| count(old) != count(new)) {
+                commits.push(id);
+            }
+        }
+        Ok(commits)
+    }
+
+    /// Three-way merge the local changes made since `old_rev` with the
+    /// upstream changes between `old_rev` and `new_rev`, and write the
+    /// result to `local_path`. Nothing is touched on conflicts.
+    pub fn merge_upstream(
+        &self,
+        repo: &Repository,
+        old_rev: Oid,
+        new_rev: Oid,
+        upstream_path: &Path,
+        local_path: &Path,
+        options: &CopyOptions,
+    ) -> Result<Merge> {
+        let base = self.find_upstream(repo, old_rev, upstream_path).context("failed to find old upstream content")?;
+        let theirs = self.find_upstream(repo, new_rev, upstream_path).context("failed to find new upstream content")?;
+        let ours = if local_path.exists() {
+            Some(self.write_local(repo, upstream_path, local_path, Some(&base), options).context("failed to read local content")?)
         } else {
-            let tree = object.peel_to_tree()?;
+            None
+        };
 
-            fs::create_dir_all(local_path)?;
-            let mut error = None;
-            tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
-                let inner = || -> Result<()> {
-                    let object = entry.to_object(&repo)?;
-                    let mut path = local_path.join(dir);
-                    path.push(entry.name().ok_or_else(|| anyhow!("name is not utf-8 encoded"))?);
-
-                    if let Ok(blob) = object.peel_to_blob() {
-                        fs::write(path, blob.content()).context("failed to write file")?;
-                    } else if object.peel_to_tree().is_ok() {
-                        fs::create_dir_all(path)?;
-                    }
-                    Ok(())
-                };
-                match inner() {
-                    Ok(()) => TreeWalkResult::Ok,
-                    Err(err) => {
-                        error = Some(err);
-                        TreeWalkResult::Abort
+        // Merging works on trees, so wrap single files in one
+        let is_file = base.kind() == Some(ObjectType::Blob);
+        let as_tree = |id: Option<Oid>| -> Result<Tree> {
+            let mut builder = repo.treebuilder(None)?;
+            match id {
+                Some(id) if is_file => {
+                    builder.insert("file", id, 0o100644)?;
+                },
+                Some(id) => return Ok(repo.find_tree(id)?),
+                None => (),
+            }
+            Ok(repo.find_tree(builder.write()?)?)
+        };
+        let ours_tree = as_tree(ours)?;
+        let mut index = repo.merge_trees(&as_tree(Some(base.id()))?, &ours_tree, &as_tree(Some(theirs.id()))?, None)
+            .context("failed to merge trees")?;
+
+        if index.has_conflicts() {
+            let mut paths = Vec::new();
+            for conflict in index.conflicts().context("failed to read conflicts")? {
+                let conflict = conflict.context("failed to read conflict")?;
+                if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+                    if is_file {
+                        paths.push(PathBuf::from(local_path.file_name().unwrap_or_default()));
+                    } else {
+                        paths.push(PathBuf::from(String::from_utf8_lossy(&entry.path).into_owned()));
                     }
                 }
-            })?;
-            if let Some(err) = error {
-                return Err(err);
             }
+            return Ok(Merge::Conflicted(paths));
         }
-        Ok(())
+
+        let merged = repo.find_tree(index.write_tree_to(repo).context("failed to write merged tree")?)?;
+        let url = cached_url(repo);
+        let origin = Origin { url: &url, rev: new_rev };
+        if is_file {
+            let entry = merged.get_name("file").ok_or_else(|| anyhow!("file was deleted by upstream"))?;
+            self.extract_object(repo, &entry.to_object(repo)?, origin, upstream_path, local_path, options)?;
+        } else {
+            // Only touch what the merge changed, the rest is already
+            // the way it should be
+            let diff = repo.diff_tree_to_tree(Some(&ours_tree), Some(&merged), None).context("failed to diff merged tree")?;
+            let staging = Staging::new(local_path)?;
+            let mut removed = Vec::new();
+            for delta in diff.deltas() {
+                let file = if delta.status() == Delta::Deleted { delta.old_file() } else { delta.new_file() };
+                let path = file.path().ok_or_else(|| anyhow!("diff has a delta without a path"))?.to_path_buf();
+                if skip::is_skipped(&options.skips, &path)
+                    || (!options.allow_dot_git && path.components().any(|c| c.as_os_str() == ".git"))
+                {
+                    continue;
+                }
+                let local_relative = remap::to_local(&options.remap, &path);
+                if options.frozen_file_set && fs::symlink_metadata(join_inside(local_path, &local_relative)?).is_err() {
+                    continue;
+                }
+                if delta.status() == Delta::Deleted {
+                    removed.push(local_relative);
+                    continue;
+                }
+                // Submodules can't be copied
+                if merged.get_path(&path)?.kind() != Some(ObjectType::Blob) {
+                    continue;
+                }
+                let blob = repo.find_blob(file.id()).context("failed to find merged file")?;
+                let content = filter::smudge(&options.filters, Some(origin), &upstream_path.join(&path), blob.content())?;
+                let staged = join_inside(&staging.path(), &local_relative)?;
+                if let Some(parent) = staged.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                debug!("writing {}", local_relative.display());
+                write_file(&staged, &content)?;
+            }
+            staging.commit()?;
+
+            // Remove what no longer exists
+            for path in removed {
+                remove_local_file(local_path, &path, options)?;
+            }
+            create_empty_dirs(local_path, options)?;
+        }
+        Ok(Merge::Updated)
+    }
+
+    /// Whether any of the paths, relative to the root of the working
+    /// tree, have staged, unstaged, or untracked changes
+    pub fn is_dirty(&self, repo: &Repository, paths: &[&Path]) -> Result<bool> {
+        let mut options = StatusOptions::new();
+        options.include_untracked(true).recurse_untracked_dirs(true);
+        for path in paths {
+            options.pathspec(*path);
+        }
+        let statuses = repo.statuses(Some(&mut options)).context("failed to get repository status")?;
+        Ok(statuses.iter().any(|entry| entry.status() != Status::CURRENT && !entry.status().is_ignored()))
+    }
+
+    /// Stage everything at the paths (relative to the root of the
+    /// working tree), including deletions, and commit it on top of HEAD
+    pub fn commit_paths(&self, repo: &Repository, paths: &[&Path], message: &str) -> Result<Oid> {
+        let mut index = repo.index().context("failed to open index")?;
+        index.add_all(paths, IndexAddOption::DEFAULT, None).context("failed to add to index")?;
+        index.update_all(paths, None).context("failed to update index")?;
+        index.write().context("failed to write index")?;
+        let tree = repo.find_tree(index.write_tree().context("failed to write index to tree")?)?;
+
+        let head = repo.head().context("failed to find head")?
+            .peel_to_commit().context("head wasn't a commit")?;
+        let sign = repo.signature().context("failed to get signature, is user.name and user.email set?")?;
+        repo.commit(Some("HEAD"), &sign, &sign, message, &tree, &[&head])
+            .context("failed to commit")
     }
 
     pub fn canonicalize(&self, repo: &Repository, local_path: &Path) -> Result<PathBuf> {
@@ -115,136 +1784,800 @@ impl App {
         let local_path = local_path.canonicalize().context("failed to find full path to destination directory")?;
         let relative = local_path.strip_prefix(&workdir).context("destination directory not in a repository")?;
 
-        Ok(relative.to_path_buf())
+        Ok(unicode::precompose(relative).into_owned())
+    }
+
+    /// Like `canonicalize`, but the path doesn't have to exist yet.
+    /// Returns `None` if it's outside of the working tree.
+    fn relative_to_workdir(&self, repo: &Repository, path: &Path) -> Result<Option<PathBuf>> {
+        let workdir = repo.workdir().ok_or_else(|| anyhow!("repository is bare and has no workdir"))?
+            .canonicalize().context("failed to find full path to repository workdir")?;
+
+        // Resolve the part of the path that exists
+        let path = env::current_dir().context("failed to get current directory")?.join(path);
+        let mut existing = path.as_path();
+        let mut missing = Vec::new();
+        while !existing.exists() {
+            missing.push(existing.file_name().ok_or_else(|| anyhow!("{} has no existing parent", path.display()))?);
+            existing = existing.parent().ok_or_else(|| anyhow!("{} has no existing parent", path.display()))?;
+        }
+        let mut full = existing.canonicalize().with_context(|| format!("failed to find full path to {}", existing.display()))?;
+        full.extend(missing.iter().rev());
+        Ok(full.strip_prefix(&workdir).ok().map(|relative| unicode::precompose(relative).into_owned()))
+    }
+
+    /// Make sure a destination is inside the working tree, and not
+    /// inside `.git`, before anything is written there
+    pub fn check_destination(&self, local_path: &Path) -> Result<()> {
+        let repo = open_superproject().context("destinations must be inside a git repository")?;
+        let relative = self.relative_to_workdir(&repo, local_path)?
+            .ok_or_else(|| anyhow!("refusing to write to {}, it's outside of the repository", local_path.display()))?;
+
+        let git_dir = repo.path().canonicalize().context("failed to find full path to .git")?;
+        let common_dir = common_dir(&repo)?.canonicalize().context("failed to find full path to .git")?;
+        let workdir = repo.workdir().ok_or_else(|| anyhow!("repository is bare and has no workdir"))?
+            .canonicalize().context("failed to find full path to repository workdir")?;
+        let full = workdir.join(&relative);
+        let inside_git = relative.components().any(|c| c.as_os_str() == ".git")
+            || full.starts_with(git_dir)
+            || full.starts_with(common_dir);
+        ensure!(!inside_git, "refusing to write to {}, it's inside of .git", local_path.display());
+        Ok(())
+    }
+
+    /// Registered subcopies that would contain, or be contained in, a
+    /// subcopy at `local_path`. Updating one of them would clobber the
+    /// other.
+    pub fn overlapping(&self, local_path: &Path) -> Result<Vec<PathBuf>> {
+        let repo = open_superproject()?;
+        let relative = match self.relative_to_workdir(&repo, local_path)? {
+            Some(relative) => relative,
+            None => return Ok(Vec::new()),
+        };
+        let mut overlapping: Vec<PathBuf> = self.list()?.into_values()
+            .map(|conf| conf.local_path)
+            .filter(|other| *other != relative && (other.starts_with(&relative) || relative.starts_with(other)))
+            .collect();
+        overlapping.sort();
+        Ok(overlapping)
+    }
+
+    /// All pairs of registered subcopies where the first contains the
+    /// second
+    pub fn overlaps(&self) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let mut paths: Vec<PathBuf> = self.list()?.into_values().map(|conf| conf.local_path).collect();
+        paths.sort();
+
+        let mut overlaps = Vec::new();
+        for outer in &paths {
+            for inner in &paths {
+                if inner != outer && inner.starts_with(outer) {
+                    overlaps.push((outer.clone(), inner.clone()));
+                }
+            }
+        }
+        Ok(overlaps)
     }
 
     pub fn register(&self, url: &str, rev: Oid, upstream_path: &Path, local_path: &Path) -> Result<()> {
-        let repo = Repository::open_from_env()?;
+        let repo = open_superproject()?;
         let relative = self.canonicalize(&repo, local_path)?;
-        let workdir = repo.workdir().expect("canonicalize has already checked this");
 
-        let relative_str = path_to_string(&relative)?;
+        let (mut config, relative_str) = self.open_manifest_for(&repo, &relative)?;
+
+        let key = path_to_string(&relative)?;
+        let previous = self.list()?.remove(key);
+        let floating = previous.as_ref().and_then(|previous| previous.floating.as_ref());
+
+        // Record who vendored what and when, for audits. Only a new
+        // revision counts as an update.
+        let now = timestamp(unix_time());
+        if previous.is_none() {
+            match repo.signature() {
+                Ok(sign) => {
+                    let who = format!("{} <{}>", sign.name().unwrap_or(""), sign.email().unwrap_or(""));
+                    config.set_str(&format!("subcopy.{}.addedBy", relative_str), &who)?;
+                },
+                Err(err) => debug!("not recording who added {}: {}", key, err),
+            }
+            config.set_str(&format!("subcopy.{}.addedAt", relative_str), &now)?;
+        }
+        if previous.as_ref().is_none_or(|previous| previous.rev.as_deref() != Some(&rev.to_string())) {
+            config.set_str(&format!("subcopy.{}.lastUpdatedAt", relative_str), &now)?;
+        }
+
+        // Keep overridden entries pointing where the developer wanted,
+        // without touching what everyone else uses
+        if let Some(local) = self.local_overrides(&repo)?.remove(key) {
+            let path = self.local_manifest_path(&repo)?;
+            let mut overrides = Config::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+            if local.url.is_some() {
+                overrides.set_str(&format!("subcopy.{}.url", key), url)?;
+            } else {
+                config.set_str(&format!("subcopy.{}.url", relative_str), url)?;
+            }
+            overrides.set_str(&format!("subcopy.{}.rev", key), &rev.to_string())?;
+        } else {
+            config.set_str(&format!("subcopy.{}.url", relative_str), url)?;
+            // Floating revisions stay as written, and only the lockfile
+            // has the commit
+            if floating.is_none() {
+                config.set_str(&format!("subcopy.{}.rev", relative_str), &rev.to_string())?;
+            }
+        }
+        let upstream_path = match normalize_upstream_path(upstream_path) {
+            path if path.as_os_str().is_empty() => PathBuf::from("."),
+            path => path,
+        };
+        config.set_str(&format!("subcopy.{}.upstreamPath", relative_str), path_to_string(&upstream_path)?)?;
+        if let Some(workdir) = repo.workdir() {
+            if let Err(err) = registry::add(workdir) {
+                warn!("failed to add repository to the registry: {:#}", err);
+            }
+        }
+        self.lock(&repo, &[(relative, rev.to_string())], floating.is_some()).context("failed to update lockfile")
+    }
+
+    /// Make a subcopy follow a floating revision like `branch:main`,
+    /// pinned at `rev` for now
+    pub fn set_floating(&self, local_path: &Path, floating: &Floating, rev: Oid) -> Result<()> {
+        let repo = open_superproject()?;
+        let relative = self.canonicalize(&repo, local_path)?;
+        self.set_property(local_path, "rev", Some(&floating.to_string()))?;
+        self.lock(&repo, &[(relative, rev.to_string())], true).context("failed to update lockfile")
+    }
 
-        let mut config = Config::open(&workdir.join(".gitcopies")).context("failed to open .gitcopies")?;
-        config.set_str(&format!("subcopy.{}.url", relative_str), url)?;
-        config.set_str(&format!("subcopy.{}.rev", relative_str), &rev.to_string())?;
-        config.set_str(&format!("subcopy.{}.upstreamPath", relative_str), path_to_string(upstream_path)?)?;
+    /// Save the current content of a subcopy, so it can be rolled back
+    /// to once it's been rewritten. Does nothing if it doesn't exist.
+    pub fn snapshot(&self, repo: &Repository, conf: &SubcopyConfig, local_path: &Path) -> Result<()> {
+        if fs::symlink_metadata(local_path).is_err() {
+            return Ok(());
+        }
+        let id = snapshot::save(repo, &conf.local_path, local_path, &conf.rev)
+            .with_context(|| format!("failed to take a snapshot of {}", conf.local_path.display()))?;
+        debug!("saved snapshot {} of {}", id, conf.local_path.display());
         Ok(())
     }
 
-    pub fn list(&self) -> Result<HashMap<String, SubcopyConfigOption>> {
-        let repo = Repository::open_from_env()?;
-        let workdir = repo.workdir().ok_or_else(|| anyhow!("repository is bare and has no workdir"))?;
-        let mut config = Config::open(&workdir.join(".gitcopies")).context("failed to open .gitcopies")?;
-        let snapshot = config.snapshot().context("failed to take a snapshot of config")?;
+    /// Put back the content and revision a subcopy had before it was
+    /// last rewritten. Rolling back again goes further back.
+    pub fn rollback(&self, local_path: &Path) -> Result<Snapshot> {
+        let repo = open_superproject()?;
+        let conf = self.get(local_path)?;
+        let snapshot = snapshot::latest(&repo, &conf.local_path)?
+            .ok_or_else(|| anyhow!("there's no snapshot of {} to roll back to", conf.local_path.display()))?;
+        snapshot::restore(&repo, &conf.local_path, &snapshot, local_path)?;
+
+        let rev = Oid::from_str(&snapshot.rev).context("snapshot has an invalid revision")?;
+        self.register(&conf.url, rev, &conf.upstream_path, local_path).context("failed to register old rev")?;
+        Ok(snapshot)
+    }
+
+    /// Record the subcopies an operation is about to write to, along
+    /// with the manifests and lockfile, in the journal so `undo` can
+    /// put them back. Does nothing outside of a repository.
+    pub fn journal(&self, operation: &str, local_paths: &[&Path]) -> Result<()> {
+        let repo = match open_superproject() {
+            Ok(repo) => repo,
+            Err(_) => return Ok(()),
+        };
+        let mut paths = Vec::new();
+        for local_path in local_paths {
+            if let Some(relative) = self.relative_to_workdir(&repo, local_path)? {
+                paths.push(self.manifest_for(&repo, &relative)?.0);
+                paths.push(local_path.to_path_buf());
+            }
+        }
+        paths.push(self.manifest_path(&repo)?);
+        paths.push(self.local_manifest_path(&repo)?);
+        paths.push(self.lock_path(&repo)?);
 
-        let mut map: HashMap<String, SubcopyConfigOption> = HashMap::new();
+        let mut relative = Vec::new();
+        for path in paths {
+            if let Some(path) = self.relative_to_workdir(&repo, &path)? {
+                if !relative.contains(&path) {
+                    relative.push(path);
+                }
+            }
+        }
+        journal::record(&repo, operation, &relative).context("failed to write journal")?;
+        Ok(())
+    }
+
+    /// Undo the last operation in the journal, returning it
+    pub fn undo(&self) -> Result<Option<journal::Operation>> {
+        journal::undo(&open_superproject()?)
+    }
+
+    /// Save the copy options of a subcopy to `.gitcopies`, replacing
+    /// what was there
+    pub fn set_options(&self, local_path: &Path, options: &CopyOptions) -> Result<()> {
+        let repo = open_superproject()?;
+        let relative = self.canonicalize(&repo, local_path)?;
+        let (mut config, relative_str) = self.open_manifest_for(&repo, &relative)?;
+        let mut replace = |var: &str, values: Vec<String>| -> Result<()> {
+            let name = format!("subcopy.{}.{}", relative_str, var);
+            match config.remove_multivar(&name, ".*") {
+                Err(ref err) if err.code() == ErrorCode::NotFound => (),
+                result => result?,
+            }
+            for value in values {
+                // libgit2 quotes values with comment characters, but
+                // doesn't escape them
+                let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+                config.set_multivar(&name, "^$", &value)?;
+            }
+            Ok(())
+        };
+        replace("remap", options.remap.iter().map(ToString::to_string).collect())?;
+        replace("filter", options.filters.iter().map(ToString::to_string).collect())?;
+        replace("skip", options.skips.iter().map(ToString::to_string).collect())?;
+        replace("emptyDir", options.empty_dirs.iter().map(|dir| dir.display().to_string()).collect())?;
+
+        let name = format!("subcopy.{}.allowDotGit", relative_str);
+        if options.allow_dot_git {
+            config.set_bool(&name, true)?;
+        } else {
+            match config.remove(&name) {
+                Err(ref err) if err.code() == ErrorCode::NotFound => (),
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Open the manifest a subcopy is in, along with the full name of
+    /// one of its properties there
+    fn open_property(&self, local_path: &Path, name: &str) -> Result<(Config, String)> {
+        ensure!(!name.is_empty() && !name.contains('.'), "invalid property name {:?}", name);
+        let repo = open_superproject()?;
+        let relative = self.relative_to_workdir(&repo, local_path)?
+            .ok_or_else(|| anyhow!("{} is outside of the repository", local_path.display()))?;
+        let (config, relative_str) = self.open_manifest_for(&repo, &relative)?;
+        Ok((config, format!("subcopy.{}.{}", relative_str, name)))
+    }
 
-        for entry in &snapshot.entries(Some(r"^subcopy\..*\.(url|rev|upstreampath)$")).context("failed to iter config entries")? {
+    /// Get a property of a subcopy from the manifest it's in, like
+    /// `description`. Properties like `group` can be there several
+    /// times, so all values are returned.
+    pub fn property(&self, local_path: &Path, name: &str) -> Result<Vec<String>> {
+        let (mut config, name) = self.open_property(local_path, name)?;
+        let snapshot = config.snapshot().context("failed to take a snapshot of config")?;
+        let mut values = Vec::new();
+        for entry in &snapshot.entries(None).context("failed to iter config entries")? {
             let entry = entry.context("failed to read config entry")?;
-            let name = entry.name().ok_or_else(|| anyhow!("entry name was not valid utf-8"))?;
+            if entry.name().is_some_and(|entry_name| entry_name.eq_ignore_ascii_case(&name)) {
+                values.push(entry.value().ok_or_else(|| anyhow!("value of {} was not valid utf-8", name))?.to_owned());
+            }
+        }
+        Ok(values)
+    }
 
-            let withoutend = name.rsplitn(2, '.').nth(1).ok_or_else(|| anyhow!("incomplete subcopy property name"))?;
-            let middle = withoutend.splitn(2, '.').nth(1).ok_or_else(|| anyhow!("incomplete subcopy property name"))?;
-            let slot = map.entry(middle.to_owned()).or_insert_with(|| SubcopyConfigOption {
-                local_path: PathBuf::from(&middle),
-                ..SubcopyConfigOption::default()
-            });
+    /// Set a property of a subcopy in the manifest it's in, replacing
+    /// all its values, or remove it if `value` is `None`
+    pub fn set_property(&self, local_path: &Path, name: &str, value: Option<&str>) -> Result<()> {
+        let (mut config, name) = self.open_property(local_path, name)?;
+        match config.remove_multivar(&name, ".*") {
+            Err(ref err) if err.code() == ErrorCode::NotFound => (),
+            result => result?,
+        }
+        if let Some(value) = value {
+            config.set_str(&name, value)?;
+        }
+        Ok(())
+    }
+
+    /// Add another value to a property that can be there several times,
+    /// like `group`
+    pub fn add_property(&self, local_path: &Path, name: &str, value: &str) -> Result<()> {
+        let (mut config, name) = self.open_property(local_path, name)?;
+        // libgit2 quotes values with comment characters, but doesn't
+        // escape them
+        let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+        config.set_multivar(&name, "^$", &value)?;
+        Ok(())
+    }
 
-            if name.ends_with("url") {
-                slot.url = entry.value().map(String::from);
-            } else if name.ends_with("rev") {
-                slot.rev = entry.value().map(String::from);
-            } else if name.ends_with("upstreampath") {
-                slot.upstream_path = entry.value().map(PathBuf::from);
+    /// All subcopies from all manifests, keyed by their path relative
+    /// to the root of the working tree
+    pub fn list(&self) -> Result<HashMap<String, SubcopyConfigOption>> {
+        self.list_in(&open_superproject()?)
+    }
+
+    /// Like `list`, but in any repository
+    pub fn list_in(&self, repo: &Repository) -> Result<HashMap<String, SubcopyConfigOption>> {
+        let mut map = HashMap::new();
+        for (path, dir) in self.manifests(repo)? {
+            if !path.exists() {
+                continue;
+            }
+            let mut config = Config::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+            for mut conf in parse_manifest(&mut config)?.into_values() {
+                conf.local_path = dir.join(&conf.local_path);
+                let key = path_to_string(&conf.local_path)?.to_owned();
+                if map.contains_key(&key) {
+                    warn!("{} is in more than one manifest, using the one in {}", key, path.display());
+                }
+                map.insert(key, conf);
+            }
+        }
+        for (key, local) in self.local_overrides(repo)? {
+            let conf = match map.get_mut(&key) {
+                Some(conf) => conf,
+                None => {
+                    debug!("ignoring override of {} in .gitcopies.local, it's not a subcopy", key);
+                    continue;
+                },
+            };
+            if local.url.is_some() || local.rev.is_some() {
+                conf.overridden = true;
             }
+            conf.url = local.url.or_else(|| conf.url.take());
+            conf.rev = local.rev.or_else(|| conf.rev.take());
+        }
+
+        let git_config = repo.config().context("failed to open git config")?;
+        for conf in map.values_mut() {
+            filter::configure(&mut conf.options.filters, &git_config)?;
         }
 
+        // What floating revisions were last resolved to is in the
+        // lockfile
+        if map.values().any(|conf| conf.rev.as_deref().is_some_and(floating::is_floating)) {
+            let lock = Lock::open(&self.lock_path(repo)?)?;
+            for conf in map.values_mut() {
+                if let Some(rev) = conf.rev.take_if(|rev| floating::is_floating(rev)) {
+                    conf.floating = Some(rev.parse::<Floating>().with_context(|| format!("invalid rev of {}", conf.local_path.display()))?);
+                    conf.rev = lock.entries.get(&conf.local_path).map(|entry| entry.rev.clone());
+                }
+            }
+        }
         Ok(map)
     }
 
+    /// Check a manifest for mistakes, before it replaces the real one.
+    /// Revisions are looked up in upstream, so this may fetch.
+    pub fn validate_manifest(&self, path: &Path) -> Result<Vec<String>> {
+        let mut config = match Config::open(path) {
+            Ok(config) => config,
+            Err(err) => return Ok(vec![err.message().to_owned()]),
+        };
+        // Parse errors only show up once the file is read
+        let snapshot = match config.snapshot() {
+            Ok(snapshot) => snapshot,
+            Err(err) => return Ok(vec![err.message().to_owned()]),
+        };
+
+        let mut problems = Vec::new();
+        let mut seen = HashSet::new();
+        for entry in &snapshot.entries(None).context("failed to iter config entries")? {
+            let entry = entry.context("failed to read config entry")?;
+            let name = entry.name().unwrap_or("<invalid utf-8>");
+            let (section, var) = name.rsplit_once('.').unwrap_or(("", name));
+            if !section.starts_with("subcopy.") {
+                problems.push(format!("{} is not in a [subcopy \"<path>\"] section", name));
+            } else if !["remap", "filter", "skip"].contains(&var) && !seen.insert(name.to_owned()) {
+                problems.push(format!("{} is set more than once", name));
+            }
+        }
+
+        let configs = match parse_manifest(&mut config) {
+            Ok(configs) => configs,
+            Err(err) => {
+                problems.push(format!("{:#}", err));
+                return Ok(problems);
+            },
+        };
+        let mut configs: Vec<_> = configs.into_values().collect();
+        configs.sort_by(|a, b| a.local_path.cmp(&b.local_path));
+        for conf in configs {
+            let key = conf.local_path.display().to_string();
+            let conf = match conf.complete() {
+                Some(conf) => conf,
+                None => {
+                    problems.push(format!("{} is missing its url, rev, or upstreamPath", key));
+                    continue;
+                },
+            };
+            let found = if floating::is_floating(&conf.rev) {
+                conf.rev.parse::<Floating>().and_then(|floating| {
+                    let repo = self.fetch(&conf.url, true)?;
+                    let rev = floating.resolve(&repo)?;
+                    self.find_upstream(&repo, rev, &conf.upstream_path).context("upstream path not found")?;
+                    Ok(())
+                })
+            } else {
+                self.fetch_rev(&conf.url, Some(&conf.rev)).and_then(|repo| {
+                    let rev = repo.revparse_single(&conf.rev).context("revision not found")?
+                        .peel_to_commit().context("revision is not a commit")?;
+                    self.find_upstream(&repo, rev.id(), &conf.upstream_path).context("upstream path not found")?;
+                    Ok(())
+                })
+            };
+            if let Err(err) = found {
+                problems.push(format!("{}: {:#}", key, err));
+            }
+        }
+        Ok(problems)
+    }
+
     pub fn get(&self, key: &Path) -> Result<SubcopyConfig> {
-        let repo = Repository::open_from_env()?;
-        let key = self.canonicalize(&repo, key)?;
+        let repo = open_superproject()?;
+        // Subcopies can be looked up after being deleted, to get them back
+        let key = self.relative_to_workdir(&repo, key)?
+            .ok_or_else(|| anyhow!("{} is outside of the repository", key.display()))?;
+        let key_str = path_to_string(&key)?;
 
-        let workdir = repo.workdir().ok_or_else(|| anyhow!("repository is bare and has no workdir"))?;
-        let mut config = Config::open(&workdir.join(".gitcopies")).context("failed to open .gitcopies")?;
-        let snapshot = config.snapshot().context("failed to take a snapshot of config")?;
+        let conf = self.list()?.remove(key_str)
+            .ok_or_else(|| anyhow!("{} is not a subcopy", key.display()))?;
+        if let (Some(floating), None) = (&conf.floating, &conf.rev) {
+            bail!("subcopy {} follows {}, but has no commit pinned in the lockfile", key.display(), floating);
+        }
+        conf.complete()
+            .ok_or_else(|| anyhow!("subcopy {} is missing its url, rev, or upstreamPath", key.display()))
+    }
 
-        let key = path_to_string(&key)?;
+    /// Find the subcopy a file or directory belongs to, and where it
+    /// is in upstream. The file doesn't have to exist, so deleted files
+    /// can be looked up too. The innermost subcopy wins if they're
+    /// nested.
+    pub fn owner(&self, path: &Path) -> Result<Option<(SubcopyConfig, PathBuf)>> {
+        let repo = open_superproject()?;
+        let relative = match self.relative_to_workdir(&repo, path)? {
+            Some(relative) => relative,
+            None => return Ok(None),
+        };
 
-        Ok(SubcopyConfig {
-            url: snapshot.get_string(&format!("subcopy.{}.url", key))?,
-            rev: snapshot.get_string(&format!("subcopy.{}.rev", key))?,
-            upstream_path: snapshot.get_path(&format!("subcopy.{}.upstreamPath", key))?,
-        })
+        let owner = self.list()?.into_values()
+            .filter(|conf| relative.starts_with(&conf.local_path))
+            .max_by_key(|conf| conf.local_path.components().count());
+        let conf = match owner.and_then(SubcopyConfigOption::complete) {
+            Some(conf) => conf,
+            None => return Ok(None),
+        };
+
+        let inner = relative.strip_prefix(&conf.local_path).expect("filtered by prefix above");
+        let upstream_file = if inner.as_os_str().is_empty() {
+            conf.upstream_path.clone()
+        } else {
+            conf.upstream_path.join(remap::to_upstream(&conf.options.remap, inner))
+        };
+        Ok(Some((conf, upstream_file)))
     }
 
-    pub fn with_repo<F, T>(&self, url: &str, rev: &str, upstream_path: &Path, local_path: &Path, callback: F) -> Result<T>
+    pub fn with_repo<F, T>(
+        &self,
+        url: &str,
+        rev: &str,
+        upstream_path: &Path,
+        local_path: &Path,
+        options: &CopyOptions,
+        callback: F,
+    ) -> Result<T>
     where
         F: FnOnce(&Repository) -> Result<T>,
     {
-        let tmp = Builder::new().prefix("git-subcopy").tempdir().context("failed to get temporary directory")?;
-        let upstream_repo = {
-            let upstream_bare = self.fetch(url, false).context("failed to fetch source repository")?;
-            let upstream_bare_path = upstream_bare.path().canonicalize().context("failed to get full cache path")?;
-            let upstream_str = path_to_string(&upstream_bare_path)?;
+        let upstream_bare = self.fetch_rev(url, Some(rev)).context("failed to fetch source repository")?;
+        // libgit2 hardlinks the objects of a local clone when it's on the
+        // same filesystem, so checkouts go next to the cache if possible
+        // instead of copying all of upstream's history to /tmp
+        let tmp = Builder::new()
+            .prefix(".checkout-")
+            .tempdir_in(&self.cache_dir)
+            .or_else(|_| Builder::new().prefix("git-subcopy").tempdir())
+            .context("failed to get temporary directory")?;
+        let reusable = tmp.path().starts_with(&self.cache_dir).then(|| self.reusable_checkout(url, local_path)).transpose()?;
+        let upstream_repo = match reusable.as_ref().map(|reusable| self.reuse_checkout(reusable, tmp.path())).transpose()? {
+            Some(Some(repo)) => repo,
+            _ => {
+                let upstream_bare_path = upstream_bare.path().canonicalize().context("failed to get full cache path")?;
+                let upstream_str = path_to_string(&upstream_bare_path)?;
 
-            info!("Cloning cached repo...");
-            Repository::clone(&upstream_str, tmp.path())
-                .context("failed to clone cache of upstream repository")?
+                info!("Cloning cached repo...");
+                RepoBuilder::new()
+                    .clone_local(CloneLocal::Auto)
+                    .fetch_options(self.fetch_options())
+                    .clone(upstream_str, tmp.path())
+                    .context("failed to clone cache of upstream repository")?
+            },
         };
 
-        upstream_repo.remote("upstream", url).context("failed to add upstream remote")?;
+        let url = self.resolve_url(url)?;
+        if upstream_repo.find_remote("upstream").is_ok() {
+            upstream_repo.remote_set_url("upstream", &url).context("failed to set upstream remote url")?;
+        } else {
+            upstream_repo.remote("upstream", &url).context("failed to add upstream remote")?;
+        }
 
         let rev = upstream_repo.revparse_single(rev).context("failed to parse revision")?;
         upstream_repo.reset(&rev, ResetType::Hard, None).context("failed to reset repository")?;
 
         info!("Copying changes...");
-        let upstream_path = tmp.path().join(upstream_path);
+        let upstream_relative = upstream_path;
+        let upstream_path = join_inside(tmp.path(), upstream_path)?;
 
-        if local_path.is_file() {
-            debug!("{} -> {}", local_path.display(), upstream_path.display());
-            fs::copy(local_path, &upstream_path).context("failed to copy file")?;
-        } else {
-            for entry in WalkDir::new(local_path) {
+        // Undo filters based on what's currently in the checkout
+        let copy_in = |from: &Path, to: &Path, upstream_relative: &Path, on_progress: &mut dyn FnMut(usize)| -> Result<()> {
+            debug!("{} -> {}", from.display(), to.display());
+            if options.filters.is_empty() {
+                return copy_file(from, to, on_progress);
+            }
+            let content = fs::read(from).context("failed to read file")?;
+            let base = fs::read(to).ok();
+            let content = filter::clean(&options.filters, upstream_relative, &content, base.as_deref())?;
+            write_file(to, &content)?;
+            fs::set_permissions(to, fs::metadata(from)?.permissions()).context("failed to copy permissions")
+        };
+
+        // Whether this is a single file is up to upstream, the local
+        // copy may have been deleted
+        let is_file = upstream_path.is_file();
+        // Upstream files a frozen file set leaves out, which stay in the
+        // checkout but aren't copied back
+        let mut left_out = HashSet::new();
+        if is_file {
+            if local_path.exists() {
+                let mut progress = Progress::new(self.progress, "Copying file");
+                let mut bytes = 0;
+                copy_in(local_path, &upstream_path, upstream_relative, &mut |written| {
+                    bytes += written;
+                    progress.update(1, None, Some(bytes));
+                })?;
+            }
+        } else if local_path.exists() {
+            let mut progress = Progress::new(self.progress, "Copying files");
+            let mut copied = 0;
+            let mut bytes = 0;
+            for entry in WalkDir::new(local_path).into_iter().filter_entry(|e| e.file_name() != ".git") {
+                interrupt::check()?;
                 let entry = entry.context("failed to read directory entry")?;
 
                 let from = entry.path();
-                let to_relative = entry.path().strip_prefix(local_path).context("walkdir should always have prefix")?;
-                let to = upstream_path.join(to_relative);
+                let to_relative = remap::to_upstream(&options.remap, entry.path().strip_prefix(local_path).context("walkdir should always have prefix")?);
+                let to = join_inside(&upstream_path, &to_relative)?;
 
-                debug!("{} -> {}", from.display(), to.display());
+                // Directories are created as needed, since remapping
+                // may place their content elsewhere
+                if entry.file_type().is_dir() {
+                    continue;
+                }
+                if let Some(parent) = to.parent() {
+                    fs::create_dir_all(parent).context("failed to copy dir")?;
+                }
+                copy_in(from, &to, &upstream_relative.join(&to_relative), &mut |written| {
+                    bytes += written;
+                    progress.update(copied, None, Some(bytes));
+                })?;
+
+                copied += 1;
+                progress.update(copied, None, Some(bytes));
+            }
+
+            // Files deleted locally show up as deleted in the worktree.
+            // With a frozen file set, missing files were left out instead.
+            let mut removed = Vec::new();
+            for entry in WalkDir::new(&upstream_path).into_iter().filter_entry(|e| e.file_name() != ".git") {
+                let entry = entry.context("failed to read directory entry")?;
                 if entry.file_type().is_dir() {
-                    fs::create_dir_all(&to).context("failed to copy dir")?;
-                } else {
-                    fs::copy(from, &to).context("failed to copy file")?;
+                    continue;
+                }
+                let relative = entry.path().strip_prefix(&upstream_path).context("walkdir should always have prefix")?;
+                if !skip::is_skipped(&options.skips, relative) && fs::symlink_metadata(join_inside(local_path, &remap::to_local(&options.remap, relative))?).is_err() {
+                    if options.frozen_file_set {
+                        left_out.insert(relative.to_path_buf());
+                    } else {
+                        removed.push(entry.into_path());
+                    }
                 }
             }
+            for path in removed {
+                fs::remove_file(path).context("failed to remove file")?;
+            }
         }
 
         let ret = callback(&upstream_repo)?;
+        let _span = span::enter("copy-back", local_path.display());
+        // Stopping halfway would leave the subcopy half copied, and lose
+        // the rest along with the checkout
+        let _deferred = interrupt::defer();
+
+        // Whatever was committed in the checkout is what the files are
+        // from now. The url is the cache's, like when extracting.
+        let head = upstream_repo.head().and_then(|head| head.peel_to_commit()).context("failed to find head of checkout")?;
+        let origin_url = cached_url(&upstream_bare);
+        let origin = Origin { url: &origin_url, rev: head.id() };
+        let copy_out = |from: &Path, to: &Path, upstream_relative: &Path, on_progress: &mut dyn FnMut(usize)| -> Result<()> {
+            if options.filters.is_empty() {
+                if !is_same_file(from, to)? {
+                    debug!("{} -> {}", from.display(), to.display());
+                    return copy_file(from, to, on_progress);
+                }
+                return fs::set_permissions(to, fs::metadata(from)?.permissions()).context("failed to copy permissions");
+            }
+            let content = fs::read(from).context("failed to read file")?;
+            let content = filter::smudge(&options.filters, Some(origin), upstream_relative, &content)?;
+            if !is_unchanged(to, &content) {
+                debug!("{} -> {}", from.display(), to.display());
+                write_file(to, &content)?;
+            }
+            fs::set_permissions(to, fs::metadata(from)?.permissions()).context("failed to copy permissions")
+        };
 
-        if upstream_path.is_file() {
-            debug!("{} -> {}", upstream_path.display(), upstream_path.display());
-            fs::copy(&upstream_path, local_path).context("failed to copy file")?;
+        if is_file && fs::symlink_metadata(&upstream_path).is_err() {
+            if fs::symlink_metadata(local_path).is_ok() {
+                debug!("removing {}", local_path.display());
+                fs::remove_file(local_path).context("failed to remove file")?;
+            }
+        } else if upstream_path.is_file() {
+            if let Some(parent) = local_path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                fs::create_dir_all(parent).context("failed to copy dir")?;
+            }
+            let mut progress = Progress::new(self.progress, "Copying file back");
+            let mut bytes = 0;
+            copy_out(&upstream_path, local_path, upstream_relative, &mut |written| {
+                bytes += written;
+                progress.update(1, None, Some(bytes));
+            })?;
         } else {
-            for entry in WalkDir::new(&upstream_path).into_iter().filter_entry(|e| e.file_name().to_str() != Some(".git")) {
+            // What upstream ignores, like build artifacts, stays behind.
+            // Ignored directories are listed as a whole, not file by file.
+            let mut status_options = StatusOptions::new();
+            status_options.include_ignored(true).recurse_ignored_dirs(false);
+            let ignored: HashSet<PathBuf> = upstream_repo.statuses(Some(&mut status_options))
+                .context("failed to get repository status")?
+                .iter()
+                .filter(|entry| entry.status().is_ignored())
+                .filter_map(|entry| entry.path().map(|path| tmp.path().join(path.trim_end_matches('/'))))
+                .collect();
+
+            let mut progress = Progress::new(self.progress, "Copying files back");
+            let mut copied = 0;
+            let mut bytes = 0;
+            for entry in WalkDir::new(&upstream_path).into_iter().filter_entry(|e| e.file_name() != ".git" && !ignored.contains(e.path())) {
                 let entry = entry.context("failed to read directory entry")?;
 
                 let from = entry.path();
-                let to_relative = entry.path().strip_prefix(&upstream_path).context("walkdir should always have prefix")?;
-                let to = local_path.join(to_relative);
+                let from_relative = entry.path().strip_prefix(&upstream_path).context("walkdir should always have prefix")?;
+                let to = join_inside(local_path, &remap::to_local(&options.remap, from_relative))?;
 
-                debug!("{} -> {}", from.display(), to.display());
-                if entry.file_type().is_dir() {
-                    fs::create_dir_all(&to).context("failed to copy dir")?;
-                } else {
-                    fs::copy(from, &to).context("failed to copy file")?;
+                // Directories are created as needed, since remapping
+                // may place their content elsewhere
+                if entry.file_type().is_dir() || skip::is_skipped(&options.skips, from_relative) || left_out.contains(from_relative) {
+                    continue;
+                }
+                if let Some(parent) = to.parent() {
+                    fs::create_dir_all(parent).context("failed to copy dir")?;
+                }
+                copy_out(from, &to, &upstream_relative.join(from_relative), &mut |written| {
+                    bytes += written;
+                    progress.update(copied, None, Some(bytes));
+                })?;
+
+                copied += 1;
+                progress.update(copied, None, Some(bytes));
+            }
+
+            // Everything here was copied in, so what's missing from the
+            // checkout was deleted there. Skipped files never were in it.
+            let mut removed = Vec::new();
+            if local_path.exists() {
+                for entry in WalkDir::new(local_path).into_iter().filter_entry(|e| e.file_name() != ".git") {
+                    let entry = entry.context("failed to read directory entry")?;
+                    if entry.file_type().is_dir() {
+                        continue;
+                    }
+                    let relative = entry.path().strip_prefix(local_path).context("walkdir should always have prefix")?;
+                    let upstream_relative = remap::to_upstream(&options.remap, relative);
+                    if !skip::is_skipped(&options.skips, &upstream_relative) && fs::symlink_metadata(join_inside(&upstream_path, &upstream_relative)?).is_err() {
+                        removed.push(relative.to_path_buf());
+                    }
                 }
             }
+            for path in removed {
+                remove_local_file(local_path, &path, options)?;
+            }
+        }
+
+        // Keep the checkout around for next time, replacing whichever
+        // was kept before
+        if let Some(reusable) = reusable {
+            if reusable.exists() {
+                fs::remove_dir_all(&reusable).context("failed to remove old checkout")?;
+            }
+            fs::create_dir_all(reusable.parent().expect("checkouts are in a directory")).context("failed to create checkouts directory")?;
+            fs::rename(tmp.into_path(), &reusable).context("failed to keep checkout")?;
         }
 
         Ok(ret)
     }
+
+    /// Where the checkout of a subcopy is kept between runs of
+    /// `with_repo`
+    fn reusable_checkout(&self, url: &str, local_path: &Path) -> Result<PathBuf> {
+        let local_path = env::current_dir().context("failed to get current directory")?.join(local_path);
+        let key = format!("{}\0{}", sources::normalize(url), local_path.display());
+        Ok(self.cache_dir.join(".checkouts").join(base64::encode_config(&key, base64::URL_SAFE_NO_PAD)))
+    }
+
+    /// Take the kept checkout, if any, moving it to `path` so no other
+    /// run can use it at the same time. It's brought up to date with
+    /// the cache, and whatever was left in it is cleaned up.
+    fn reuse_checkout(&self, reusable: &Path, path: &Path) -> Result<Option<Repository>> {
+        fs::remove_dir(path).context("failed to remove temporary directory")?;
+        if fs::rename(reusable, path).is_err() {
+            fs::create_dir(path).context("failed to create temporary directory")?;
+            return Ok(None);
+        }
+
+        info!("Reusing checkout...");
+        let repo = Repository::open(path).context("failed to open kept checkout")?;
+        repo.cleanup_state().context("failed to clean up repository state")?;
+        repo.find_remote("origin").context("failed to find origin remote")?
+            .fetch(&["+refs/heads/*:refs/remotes/origin/*", "+refs/tags/*:refs/tags/*"], Some(&mut self.fetch_options()), None)
+            .context("failed to fetch from cache")?;
+
+        // Untracked files would otherwise look like new files of the
+        // subcopy. Ignored ones, like build output, can stay.
+        {
+            let head = repo.head().context("failed to find HEAD")?.peel(ObjectType::Commit).context("HEAD is not a commit")?;
+            repo.reset(&head, ResetType::Hard, None).context("failed to reset repository")?;
+        }
+        let mut status_options = StatusOptions::new();
+        status_options.include_untracked(true);
+        let untracked: Vec<PathBuf> = repo.statuses(Some(&mut status_options))
+            .context("failed to get repository status")?
+            .iter()
+            .filter(|entry| entry.status().is_wt_new())
+            .filter_map(|entry| entry.path().map(|untracked| path.join(untracked)))
+            .collect();
+        for untracked in untracked {
+            if untracked.is_dir() {
+                fs::remove_dir_all(&untracked).context("failed to remove untracked directory")?;
+            } else {
+                fs::remove_file(&untracked).context("failed to remove untracked file")?;
+            }
+        }
+        Ok(Some(repo))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Commit files to a new repository
+    fn commit_files(dir: &Path, files: &[(&str, &str)]) -> Oid {
+        let repo = Repository::init(dir).unwrap();
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"].iter(), IndexAddOption::DEFAULT, None).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sign = git2::Signature::now("Test", "test@example.com").unwrap();
+        repo.commit(Some("HEAD"), &sign, &sign, "Initial commit", &tree, &[]).unwrap()
+    }
+
+    #[test]
+    fn frozen_file_set_leaves_missing_files_out_of_the_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let upstream = dir.path().join("upstream");
+        let rev = commit_files(&upstream, &[("kept", "kept\n"), ("left-out", "left out\n")]);
+        let local = dir.path().join("local");
+        fs::create_dir(&local).unwrap();
+        fs::write(local.join("kept"), "kept\n").unwrap();
+
+        let mut app = App::new().unwrap();
+        app.cache_dir = dir.path().join("cache");
+        let options = CopyOptions { frozen_file_set: true, ..CopyOptions::default() };
+        app.with_repo(path_to_string(&upstream).unwrap(), &rev.to_string(), Path::new(""), &local, &options, |checkout| {
+            let workdir = checkout.workdir().unwrap();
+            assert!(workdir.join("left-out").exists(), "left out file was deleted from the checkout");
+            fs::write(workdir.join("kept"), "changed\n").unwrap();
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(fs::read_to_string(local.join("kept")).unwrap(), "changed\n");
+        assert!(!local.join("left-out").exists(), "left out file was copied back");
+    }
 }
@@ -0,0 +1,121 @@
+//! Finding out which license upstream content is under, from the files
+//! projects conventionally put their license in. Only the common
+//! licenses are recognized, by phrases from their text.
+
+use std::path::{Path, PathBuf};
+
+use git2::{ObjectType, Repository, Tree};
+
+/// Prefixes of the names of license files, compared without case
+const FILE_NAMES: &[&str] = &["license", "licence", "copying", "unlicense"];
+
+/// What copyright lines start with, in any combination
+const COPYRIGHT_MARKERS: &[&str] = &["Copyright", "(c)", "(C)", "\u{a9}"];
+
+/// Phrases identifying a license by its SPDX identifier. The first
+/// match wins, so more specific licenses come first.
+const LICENSES: &[(&str, &[&str])] = &[
+    ("AGPL-3.0", &["GNU AFFERO GENERAL PUBLIC LICENSE"]),
+    ("LGPL-3.0", &["GNU LESSER GENERAL PUBLIC LICENSE", "Version 3"]),
+    ("LGPL-2.1", &["GNU LESSER GENERAL PUBLIC LICENSE", "Version 2.1"]),
+    ("GPL-3.0", &["GNU GENERAL PUBLIC LICENSE", "Version 3"]),
+    ("GPL-2.0", &["GNU GENERAL PUBLIC LICENSE", "Version 2"]),
+    ("Apache-2.0", &["Apache License", "Version 2.0"]),
+    ("MPL-2.0", &["Mozilla Public License Version 2.0"]),
+    ("Unlicense", &["This is free and unencumbered software released into the public domain"]),
+    ("BSD-3-Clause", &["Redistribution and use in source and binary forms", "Neither the name"]),
+    ("BSD-2-Clause", &["Redistribution and use in source and binary forms"]),
+    ("ISC", &["Permission to use, copy, modify, and/or distribute this software for any purpose"]),
+    ("MIT", &["Permission is hereby granted, free of charge"]),
+    ("Zlib", &["This software is provided 'as-is', without any express or implied"]),
+];
+
+/// A license found in upstream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct License {
+    /// The SPDX expression, if the text was recognized. Projects with
+    /// several license files are taken to be dual licensed.
+    pub spdx: Option<String>,
+    /// Where the license is in upstream
+    pub path: PathBuf,
+    /// The copyright lines in the license files, in order and without
+    /// duplicates
+    pub copyright: Vec<String>,
+}
+
+/// Recognize a license by its text
+pub fn identify(text: &str) -> Option<&'static str> {
+    // Line breaks are wherever the project put them
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    LICENSES.iter()
+        .find(|(_, phrases)| phrases.iter().all(|phrase| text.contains(phrase)))
+        .map(|(spdx, _)| *spdx)
+}
+
+/// The lines of a license naming who holds the copyright, like
+/// `Copyright (c) 2019 Someone`. They have to start with a year, which
+/// leaves out templates like `Copyright [yyyy] [name]`. The copyright
+/// of the GNU licenses themselves is left out too.
+pub fn copyright(text: &str) -> Vec<String> {
+    let is_copyright = |line: &str| {
+        let (mut rest, mut marked) = (line, false);
+        while let Some(stripped) = COPYRIGHT_MARKERS.iter().find_map(|marker| rest.strip_prefix(marker)) {
+            rest = stripped.trim_start();
+            marked = true;
+        }
+        marked && rest.starts_with(|c: char| c.is_ascii_digit()) && !rest.contains("Free Software Foundation")
+    };
+    text.lines().map(str::trim).filter(|line| is_copyright(line)).map(String::from).collect()
+}
+
+/// Look for a license file in `upstream_path` and every directory above
+/// it, so content copied from a subdirectory still finds the license
+/// of the project it's from. `root` is the tree of the upstream commit.
+pub fn find(repo: &Repository, root: &Tree, upstream_path: &Path) -> Option<License> {
+    let mut dir = Some(upstream_path);
+    while let Some(current) = dir {
+        let tree = if current.as_os_str().is_empty() {
+            Some(root.clone())
+        } else {
+            root.get_path(current).ok()
+                .filter(|entry| entry.kind() == Some(ObjectType::Tree))
+                .and_then(|entry| repo.find_tree(entry.id()).ok())
+        };
+        if let Some(tree) = tree {
+            let mut candidates: Vec<_> = tree.iter()
+                .filter(|entry| entry.kind() == Some(ObjectType::Blob))
+                .filter_map(|entry| Some((entry.name()?.to_owned(), entry.id())))
+                .filter(|(name, _)| {
+                    let name = name.to_lowercase();
+                    FILE_NAMES.iter().any(|prefix| name.starts_with(prefix))
+                })
+                .collect();
+            candidates.sort();
+            if let Some((name, _)) = candidates.first() {
+                let mut identified = Vec::new();
+                let mut copyrights = Vec::new();
+                for (_, id) in &candidates {
+                    let text = match repo.find_blob(*id) {
+                        Ok(blob) => String::from_utf8_lossy(blob.content()).into_owned(),
+                        Err(_) => continue,
+                    };
+                    if let Some(spdx) = identify(&text).filter(|spdx| !identified.contains(spdx)) {
+                        identified.push(spdx);
+                    }
+                    for line in copyright(&text) {
+                        if !copyrights.contains(&line) {
+                            copyrights.push(line);
+                        }
+                    }
+                }
+                return Some(License {
+                    spdx: if identified.is_empty() { None } else { Some(identified.join(" OR ")) },
+                    path: current.join(name),
+                    copyright: copyrights,
+                });
+            }
+        }
+        dir = current.parent();
+    }
+    None
+}
@@ -0,0 +1,141 @@
+//! The lockfile, `.gitcopies-lock` next to the manifest, records the
+//! blob id of every file in every subcopy as it was last written by
+//! git-subcopy. Comparing against it shows exactly which files were
+//! changed since, without needing upstream or the cache. It uses the
+//! same format as `.gitcopies`, but is written by hand since libgit2
+//! is slow at adding many values to the same variable.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use git2::{Config, ObjectType, Oid};
+use walkdir::WalkDir;
+
+use crate::unicode;
+
+/// What was recorded for one subcopy
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LockEntry {
+    pub rev: String,
+    /// Blob ids of files, relative to the subcopy
+    pub files: BTreeMap<PathBuf, Oid>,
+}
+
+/// How a file differs from the lockfile
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Change {
+    Modified,
+    Deleted,
+    Added,
+}
+
+#[derive(Debug, Default)]
+pub struct Lock {
+    /// Keyed by the local path of the subcopy, relative to the root of
+    /// the repository
+    pub entries: BTreeMap<PathBuf, LockEntry>,
+}
+
+impl Lock {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut lock = Self::default();
+        if !path.exists() {
+            return Ok(lock);
+        }
+        let mut config = Config::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let snapshot = config.snapshot().context("failed to take a snapshot of config")?;
+        for entry in &snapshot.entries(Some(r"^subcopy\..*\.(rev|file)$")).context("failed to iter config entries")? {
+            let entry = entry.context("failed to read config entry")?;
+            let name = entry.name().ok_or_else(|| anyhow!("entry name was not valid utf-8"))?;
+            let value = entry.value().ok_or_else(|| anyhow!("value of {} was not valid utf-8", name))?;
+
+            let (withoutend, var) = name.rsplit_once('.').ok_or_else(|| anyhow!("incomplete lock property name"))?;
+            let (_, middle) = withoutend.split_once('.').ok_or_else(|| anyhow!("incomplete lock property name"))?;
+            let slot = lock.entries.entry(PathBuf::from(middle)).or_default();
+            match var {
+                "rev" => slot.rev = value.to_owned(),
+                _ => {
+                    let (id, file) = value.split_once(' ').ok_or_else(|| anyhow!("{} should be `<blob id> <path>`", name))?;
+                    let id = Oid::from_str(id).with_context(|| format!("invalid blob id in {}", name))?;
+                    slot.files.insert(PathBuf::from(file), id);
+                },
+            }
+        }
+        Ok(lock)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut out = String::from("# Written by git-subcopy, records the content of each subcopy\n");
+        for (local_path, entry) in &self.entries {
+            let _ = writeln!(out, "[subcopy {}]", quote(&local_path.to_string_lossy()));
+            let _ = writeln!(out, "\trev = {}", entry.rev);
+            for (file, id) in &entry.files {
+                let _ = writeln!(out, "\tfile = {}", quote(&format!("{} {}", id, file.to_string_lossy())));
+            }
+        }
+        fs::write(path, out).with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+/// Quote a value for a git config file
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n").replace('\t', "\\t"))
+}
+
+/// Hash every file of a local copy the way git would, relative to it.
+/// `.git` is left out, same as when copying.
+pub fn hash_files(local_path: &Path) -> Result<BTreeMap<PathBuf, Oid>> {
+    let mut files = BTreeMap::new();
+    if local_path.is_file() || fs::symlink_metadata(local_path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        files.insert(PathBuf::new(), hash_file(local_path)?);
+        return Ok(files);
+    }
+    for entry in WalkDir::new(local_path).min_depth(1).into_iter().filter_entry(|e| e.file_name() != ".git") {
+        let entry = entry.context("failed to read directory entry")?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(local_path).context("walkdir should always have prefix")?;
+        files.insert(unicode::precompose(relative).into_owned(), hash_file(entry.path())?);
+    }
+    Ok(files)
+}
+
+fn hash_file(path: &Path) -> Result<Oid> {
+    let metadata = fs::symlink_metadata(path).with_context(|| format!("failed to stat {}", path.display()))?;
+    if metadata.file_type().is_symlink() {
+        let target = fs::read_link(path).context("failed to read symlink")?;
+        return Ok(Oid::hash_object(ObjectType::Blob, target.to_string_lossy().as_bytes())?);
+    }
+    Oid::hash_file(ObjectType::Blob, path).with_context(|| format!("failed to hash {}", path.display()))
+}
+
+/// Compare a local copy against what was recorded, returning the
+/// files that changed, relative to the local copy
+pub fn compare(entry: &LockEntry, local_path: &Path) -> Result<Vec<(PathBuf, Change)>> {
+    let current = match hash_files(local_path) {
+        Ok(current) => current,
+        Err(_) if !local_path.exists() => BTreeMap::new(),
+        Err(err) => return Err(err),
+    };
+    let mut changes = Vec::new();
+    for (file, id) in &entry.files {
+        match current.get(file) {
+            None => changes.push((file.clone(), Change::Deleted)),
+            Some(current) if current != id => changes.push((file.clone(), Change::Modified)),
+            Some(_) => (),
+        }
+    }
+    for file in current.keys() {
+        if !entry.files.contains_key(file) {
+            changes.push((file.clone(), Change::Added));
+        }
+    }
+    changes.sort();
+    Ok(changes)
+}
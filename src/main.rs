@@ -1,36 +1,377 @@
 use std::{
+    collections::{HashMap, HashSet},
     env,
     ffi::OsString,
+    fs,
+    io::{self, IsTerminal, Write},
     iter,
     path::{PathBuf, Path},
-    process::Command,
+    process::{self, Command, ExitStatus},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{ensure, Context, Result};
-use git2::{IndexAddOption, RebaseOptions, Signature};
-use git_subcopy::App;
-use log::info;
-use structopt::StructOpt;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use git2::{build::CheckoutBuilder, BranchType, DiffFormat, DiffOptions, IndexAddOption, ObjectType, Oid, RebaseOptions, Repository, RepositoryState, Signature, Status, StatusOptions};
+use git_subcopy::{diff, filter::{self, Filter}, floating::{self, Floating}, interrupt, journal, license, lock::{self, Change, Lock}, open_superproject, porcelain, registry, remap::{self, Remap}, skip::{self, Skip}, snapshot, sources, span, App, CopyOptions, Merge, SubcopyConfig, SubcopyConfigOption};
+use log::{debug, error, info, warn};
+use regex::Regex;
+use structopt::{clap::ErrorKind, StructOpt};
+use walkdir::WalkDir;
+
+mod export;
+mod import;
+mod picker;
+mod plugin;
+mod report;
+mod size;
+
+use export::Value;
+use picker::Picker;
+use size::Size;
+
+/// How much can be extracted without asking first
+const CONFIRM_SIZE: Size = Size(100 << 20);
+
+/// Ask a yes or no question on the terminal, defaulting to no
+fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).context("failed to read from stdin")?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
 
 #[derive(StructOpt)]
 struct FetchOpts {
-    /// The repository URL to extract files from
+    /// The repository URL to extract files from. Shorthands like
+    /// `github:owner/repo`, `gitlab:group/repo`, `codeberg:owner/repo`,
+    /// and `sr.ht:~owner/repo` are expanded. Can also be the name of
+    /// an alias defined as `subcopy.source.<name>.url` in git config
+    /// or `source.<name>.url` in `.gitsources`, which is kept as-is.
     url: String,
-    /// The commit reference to extract files from
-    rev: String,
-    /// The source destination to extract files from
-    upstream_path: PathBuf,
-    /// The target destination to extract files from
-    local_path: PathBuf,
+    /// The commit reference to extract files from, the source
+    /// destination to extract files from, and the target destination
+    /// to extract files to. The commit reference can be left out to
+    /// use the tip of the upstream's default branch. The content of a
+    /// directory is placed directly in the local path, without the
+    /// directory's own name.
+    #[structopt(value_names = &["rev", "upstream-path", "local-path"], min_values = 1, max_values = 3, required = true)]
+    args: Vec<String>,
+
+    /// Rename paths while extracting, like `src/ -> lib/` to move a
+    /// directory or `README.md -> UPSTREAM_README.md` to rename a
+    /// file. Can be given multiple times, and is remembered in
+    /// `.gitcopies`.
+    #[structopt(long, number_of_values = 1)]
+    remap: Vec<Remap>,
+
+    /// Transform files while extracting: `replace <upstream> <local>`,
+    /// `sub /<regex>/<replacement>/`, `eol lf`, `eol crlf`,
+    /// `strip-header`, `exec <name>` to run the command set in the git
+    /// config as `subcopy.filter.<name>.command`, or `provenance` to add a
+    /// comment saying where each file is from. Applied in order, and
+    /// undone when copying changes back. Can be given multiple times,
+    /// and is remembered in `.gitcopies`.
+    #[structopt(long, number_of_values = 1)]
+    filter: Vec<Filter>,
+
+    /// Leave out upstream files matching a pattern, like `docs/` or
+    /// `*.png`. They aren't counted as deleted when looking for local
+    /// changes. Can be given multiple times, and is remembered in
+    /// `.gitcopies`.
+    #[structopt(long, number_of_values = 1)]
+    skip: Vec<Skip>,
+
+    /// Create this directory in the local copy after extracting, even
+    /// though it's empty, like `logs`. Can be given multiple times, and
+    /// is remembered in `.gitcopies`.
+    #[structopt(long, number_of_values = 1)]
+    empty_dir: Vec<PathBuf>,
+
+    /// Extract `.git` directories found in the upstream content. They
+    /// are left out by default, since git would mistake them for
+    /// nested repositories.
+    #[structopt(long)]
+    allow_dot_git: bool,
+
+    /// Browse the upstream tree and pick what to extract, instead of
+    /// passing the upstream path. Everything picked is placed under
+    /// the local path, keeping the upstream directory layout.
+    #[structopt(short, long)]
+    interactive: bool,
 
     /// Whether or not to overwrite any existing directories. Will
     /// also create parent directories if they don't exist.
     #[structopt(short, long)]
     force: bool,
+
+    /// Fail instead of extracting more than this, like `500M` or `2G`.
+    /// Without it, you're asked to confirm before extracting more than
+    /// 100 MiB.
+    #[structopt(long)]
+    max_size: Option<Size>,
+
+    /// Read every file back after writing it, and fail if it doesn't
+    /// match upstream. Catches filesystems that mangle content, like
+    /// by converting line endings or folding case.
+    #[structopt(long)]
+    verify: bool,
+
+    /// Allow adding a subcopy inside of another, or around one. Updates
+    /// of one would overwrite the other, so only use this if they
+    /// don't actually overlap, like with remapping.
+    #[structopt(long)]
+    allow_nested: bool,
+
+    /// Extract from a revision of this repository instead of an
+    /// upstream, like another branch, using its objects directly. The
+    /// URL is left out, and saved as `.` in `.gitcopies`.
+    #[structopt(long)]
+    from_self: bool,
 }
 
+impl FetchOpts {
+    /// Split the positional arguments into the revision, upstream
+    /// path, and local path. There is no upstream path when picking
+    /// interactively.
+    fn positional(&self) -> Result<(Option<&str>, Option<&Path>, &Path)> {
+        // Without a URL, what was taken for one is the first of them
+        let args: Vec<&str> = if self.from_self {
+            iter::once(&*self.url).chain(self.args.iter().map(String::as_str)).collect()
+        } else {
+            self.args.iter().map(String::as_str).collect()
+        };
+        let (rev, paths) = match (args.len(), self.interactive) {
+            (1, true) | (2, false) => (None, &args[..]),
+            (2, true) | (3, false) => (Some(args[0]), &args[1..]),
+            _ if self.interactive => bail!("expected [rev] <local-path> with --interactive"),
+            _ => bail!("expected [rev] <upstream-path> <local-path>"),
+        };
+        let (upstream_path, local_path) = match *paths {
+            [local_path] => (None, local_path),
+            [upstream_path, local_path] => (Some(Path::new(upstream_path)), local_path),
+            _ => unreachable!(),
+        };
+        Ok((rev, upstream_path, Path::new(local_path)))
+    }
+}
+
+/// Where to look in upstream, for commands that explore it
 #[derive(StructOpt)]
-enum Opt {
+struct BrowseOpts {
+    /// A subcopy, as specified in `.gitcopies`, or the URL of any
+    /// repository.
+    source: String,
+    /// A directory to look in, relative to the subcopy's upstream path
+    /// or the root of the repository.
+    subdir: Option<PathBuf>,
+    /// Look at this revision instead of the one recorded for the
+    /// subcopy, or the upstream's default branch for a URL.
+    #[structopt(long)]
+    rev: Option<String>,
+}
+
+impl BrowseOpts {
+    /// Fetch upstream and find the tree or blob to look at. Returns
+    /// the cache, the id of the object, and the path to it.
+    fn find(&self, app: &App) -> Result<(Repository, Oid, PathBuf)> {
+        let (repo, rev, base) = upstream_source(app, &self.source, self.rev.as_deref())?;
+        let path = match &self.subdir {
+            Some(subdir) => base.join(subdir),
+            None => base,
+        };
+        let id = app.find_upstream(&repo, rev, &path)?.id();
+        Ok((repo, id, path))
+    }
+}
+
+/// What to do about uncommitted changes in files about to be
+/// overwritten
+#[derive(StructOpt)]
+struct WorktreeOpts {
+    /// Overwrite files even if they have uncommitted changes.
+    #[structopt(short, long)]
+    force: bool,
+    /// Stash uncommitted changes first, and pop them again afterwards.
+    #[structopt(long, conflicts_with = "force")]
+    autostash: bool,
+}
+
+impl WorktreeOpts {
+    /// Make sure uncommitted changes at `paths` (relative to the root
+    /// of the working tree) can't be lost, stashing them if asked to.
+    fn prepare<'a>(&self, app: &App, repo: &'a Repository, paths: &[&Path]) -> Result<Autostash<'a>> {
+        let mut autostash = Autostash {
+            repo,
+            paths: paths.iter().map(|path| path.to_path_buf()).collect(),
+            stashed: false,
+        };
+        if self.force || !app.is_dirty(repo, paths)? {
+            return Ok(autostash);
+        }
+        if !self.autostash {
+            let paths: Vec<_> = paths.iter().map(|path| path.display().to_string()).collect();
+            bail!("{} has uncommitted changes, commit them or use --autostash or --force", paths.join(", "));
+        }
+
+        info!("Stashing uncommitted changes...");
+        let mut args = vec![OsString::from("stash"), "push".into(), "--quiet".into(), "--include-untracked".into(), "-m".into(), "git-subcopy autostash".into(), "--".into()];
+        args.extend(paths.iter().map(|path| path.as_os_str().to_owned()));
+        git(repo, &args)?;
+        autostash.stashed = true;
+        Ok(autostash)
+    }
+}
+
+/// Changes stashed by `WorktreeOpts::prepare`, if any. They're popped
+/// by `finish`, or when this is dropped, so a command failing halfway
+/// doesn't leave them in the stash.
+struct Autostash<'a> {
+    repo: &'a Repository,
+    paths: Vec<PathBuf>,
+    stashed: bool,
+}
+
+impl Autostash<'_> {
+    /// Pop the stashed changes. The files at `paths` were probably
+    /// rewritten in the meantime, which git refuses to pop over unless
+    /// they're staged, so they're staged for the duration of the merge.
+    fn finish(mut self) -> Result<()> {
+        self.pop()
+    }
+
+    fn pop(&mut self) -> Result<()> {
+        if !self.stashed {
+            return Ok(());
+        }
+        self.stashed = false;
+        info!("Popping stashed changes...");
+        let pathspec = |cmd: &[&str]| {
+            let mut args: Vec<OsString> = cmd.iter().map(OsString::from).collect();
+            args.push("--".into());
+            args.extend(self.paths.iter().map(|path| path.as_os_str().to_owned()));
+            args
+        };
+        git(self.repo, &pathspec(&["add", "--all"]))?;
+        git(self.repo, &["stash", "pop", "--quiet"]).context("failed to pop stashed changes, they're kept in `git stash list`")?;
+        git(self.repo, &pathspec(&["reset", "-q"]))
+    }
+}
+
+impl Drop for Autostash<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.pop() {
+            error!("{:#}", err);
+        }
+    }
+}
+
+/// Run a git command in the working tree of a repository
+fn git<S: AsRef<std::ffi::OsStr>>(repo: &Repository, args: &[S]) -> Result<()> {
+    let workdir = repo.workdir().ok_or_else(|| anyhow!("repository is bare and has no workdir"))?;
+    let status = Command::new("git")
+        .args(args)
+        .current_dir(workdir)
+        .status()
+        .context("failed to run git")?;
+    ensure!(status.success(), "git exited with {}", status);
+    Ok(())
+}
+
+#[derive(StructOpt)]
+enum CacheCmd {
+    /// Write the cached upstream of a subcopy, every branch and tag, to
+    /// a git bundle, to carry to a machine without network access.
+    ExportBundle {
+        /// The path to the copied content, as specified in
+        /// `.gitcopies`, or the URL of an upstream.
+        source: String,
+        /// Where to write the bundle.
+        #[structopt(short, long)]
+        output: PathBuf,
+    },
+    /// Fill the cache of an upstream from a bundle made by
+    /// `export-bundle`, so commands like `update --no-refresh` work
+    /// offline.
+    ImportBundle {
+        /// The bundle to read.
+        bundle: PathBuf,
+        /// The path to the copied content, as specified in
+        /// `.gitcopies`, or the URL of the upstream the bundle is of.
+        source: String,
+    },
+}
+
+#[derive(StructOpt)]
+struct Opt {
+    /// Read and write the manifest at this path instead of
+    /// `.gitcopies` in the root of the repository. Overrides the
+    /// `subcopy.manifestPath` git config.
+    #[structopt(long, global = true)]
+    gitcopies_file: Option<PathBuf>,
+    /// Only print errors, and skip the explanatory messages printed
+    /// before dropping you into a shell.
+    #[structopt(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print more details about what's going on. Pass twice to see
+    /// even more.
+    #[structopt(short, long, global = true, parse(from_occurrences))]
+    verbose: u8,
+    /// Always fetch from upstream. Otherwise, fetching is skipped when
+    /// the commit needed is already cached, or when the cache was
+    /// fetched within `subcopy.cache.ttl`, like `1h` or `1d`.
+    #[structopt(long, global = true)]
+    refresh: bool,
+    /// Never fetch into caches that already exist, even stale ones.
+    #[structopt(long, global = true, conflicts_with = "refresh")]
+    no_refresh: bool,
+    /// How log messages are written: `text`, or `json` for one object
+    /// per line, along with the command and subcopy they're about.
+    #[structopt(long, global = true, default_value = "text")]
+    log_format: LogFormat,
+
+    #[structopt(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("unknown log format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+/// Write a log message as a JSON object on one line, with the spans
+/// it was logged in
+fn json_log(out: &mut dyn Write, timestamp: &str, record: &log::Record) -> io::Result<()> {
+    let string = |value: &str| porcelain::json_string(value);
+    let spans: Vec<String> = span::current().iter()
+        .map(|(name, value)| format!("{}:{}", string(name), string(value)))
+        .collect();
+    writeln!(
+        out,
+        r#"{{"timestamp":{},"level":{},"target":{},"spans":{{{}}},"message":{}}}"#,
+        string(timestamp),
+        string(&record.level().to_string()),
+        string(record.target()),
+        spans.join(","),
+        string(&record.args().to_string()),
+    )
+}
+
+#[derive(StructOpt)]
+enum Cmd {
     /// Will fetch specific files from a git repository. This does
     /// nothing else other than copying those - it will not add this
     /// to your `.gitcopies` file.
@@ -46,13 +387,415 @@ enum Opt {
         opts: FetchOpts,
     },
     /// List all subcopies according to the `.gitcopies` file.
-    List,
+    List {
+        /// Print tab-separated fields meant for scripts, guaranteed
+        /// not to change between releases. The only version is `v1`,
+        /// which prints the local path, url, revision, and upstream
+        /// path.
+        #[structopt(long, require_equals = true)]
+        porcelain: Option<Option<porcelain::Version>>,
+        /// Only print the local path of each subcopy, one per line.
+        #[structopt(long, conflicts_with_all = &["porcelain", "urls", "revs"])]
+        paths: bool,
+        /// Only print the upstream URLs, once each.
+        #[structopt(long, conflicts_with_all = &["porcelain", "revs"])]
+        urls: bool,
+        /// Only print the local path and revision of each subcopy,
+        /// separated by a tab.
+        #[structopt(long, conflicts_with = "porcelain")]
+        revs: bool,
+        /// Only list subcopies in this group, set as `group` in
+        /// `.gitcopies`.
+        #[structopt(long)]
+        group: Option<String>,
+        /// Also print the description of each subcopy, who added it,
+        /// and when it was added and last updated.
+        #[structopt(long, conflicts_with_all = &["porcelain", "paths", "urls", "revs"])]
+        long: bool,
+    },
+    /// Print everything `.gitcopies` says about a subcopy, including
+    /// who added it and when it was added and last updated.
+    Show {
+        /// The path to the copied content, as specified in
+        /// `.gitcopies`.
+        local_path: PathBuf,
+    },
+    /// List the subcopies of every repository on this machine that
+    /// git-subcopy has added one to, like to find out which vendor code
+    /// from a project.
+    GlobalList {
+        /// Only list subcopies whose url contains this
+        url: Option<String>,
+    },
+    /// Search the upstream content of subcopies, at their recorded
+    /// revisions, for lines matching a regular expression.
+    Grep {
+        /// The regular expression to search for.
+        pattern: String,
+        /// The paths to the copied content, as specified in
+        /// `.gitcopies`. Searches all subcopies by default.
+        local_paths: Vec<PathBuf>,
+        /// Prefix each match with its line number.
+        #[structopt(short = "n", long)]
+        line_number: bool,
+    },
+    /// Find the upstream commits that added or removed a string in a
+    /// subcopy's upstream path, like `git log -S`, newest first.
+    /// Commits that aren't part of the recorded revision yet are marked
+    /// with a `+`.
+    Search {
+        /// The path to the copied content, as specified in
+        /// `.gitcopies`.
+        local_path: PathBuf,
+        /// The string to look for.
+        #[structopt(short = "S")]
+        string: String,
+        /// Search the history of this revision instead of the branch
+        /// the subcopy follows, or upstream's default branch.
+        #[structopt(long)]
+        rev: Option<String>,
+    },
+    /// Find the upstream commit that broke something, by extracting the
+    /// subcopy at upstream commits between a good and a bad revision
+    /// and running a command, like `bisect <path> --good v1 --bad v2 --
+    /// make test`. The command succeeding means the commit is good. The
+    /// local content is put back afterwards.
+    Bisect {
+        /// The path to the copied content, as specified in
+        /// `.gitcopies`.
+        local_path: PathBuf,
+        /// An upstream revision that's known to work.
+        #[structopt(long)]
+        good: String,
+        /// An upstream revision that's known to be broken.
+        #[structopt(long)]
+        bad: String,
+        /// The command testing each commit.
+        #[structopt(last = true, required = true)]
+        command: Vec<OsString>,
+    },
+    /// Find the subcopy a file belongs to, and print where it came
+    /// from. Fails if the file isn't part of any subcopy.
+    Owner {
+        /// The file or directory to look up. It doesn't have to exist.
+        file: PathBuf,
+        /// Print tab-separated fields meant for scripts. The only
+        /// version is `v1`, which prints the local path of the
+        /// subcopy, url, revision, and the path of the file in
+        /// upstream.
+        #[structopt(long, require_equals = true)]
+        porcelain: Option<Option<porcelain::Version>>,
+    },
+    /// Print a file from upstream, without writing anything to disk.
+    Cat {
+        /// A subcopy, as specified in `.gitcopies`, or the URL of any
+        /// repository.
+        source: String,
+        /// The file to print. For a subcopy, it's relative to the
+        /// subcopy's upstream path, and can be left out if the subcopy
+        /// is a single file.
+        upstream_file: Option<PathBuf>,
+        /// Look at this revision instead of the one recorded for the
+        /// subcopy, or the upstream's default branch for a URL.
+        #[structopt(long)]
+        rev: Option<String>,
+    },
+    /// List what's in a directory in upstream, with the mode, type,
+    /// and size of each entry, to see what's there before adding it.
+    Ls {
+        #[structopt(flatten)]
+        opts: BrowseOpts,
+    },
+    /// Show everything under a directory in upstream as an indented
+    /// tree.
+    Tree {
+        #[structopt(flatten)]
+        opts: BrowseOpts,
+    },
+    /// Show what was changed locally in subcopies since they were
+    /// copied from upstream, or with --rev, what updating them would
+    /// bring in. Binary files are only summarized by their size.
+    Diff {
+        /// The paths to the copied content, as specified in
+        /// `.gitcopies`. Shows all subcopies by default.
+        local_paths: Vec<PathBuf>,
+        /// Preview the upstream changes between the recorded revision
+        /// and this one, instead of showing local changes.
+        #[structopt(long)]
+        rev: Option<String>,
+        /// Only show how many lines changed in each file, like `git
+        /// diff --stat`.
+        #[structopt(long)]
+        stat: bool,
+    },
+    /// Summarize subcopies: how many files they vendor and how big they
+    /// are, how many lines were changed locally, how old their
+    /// revisions are, and when they were last updated.
+    Stats {
+        /// The paths to the copied content, as specified in
+        /// `.gitcopies`. Summarizes all subcopies by default.
+        local_paths: Vec<PathBuf>,
+    },
+    /// Print a table of subcopies with their source, revision, local
+    /// changes, available updates, and license, ready to paste into a
+    /// pull request or issue.
+    Report {
+        /// The paths to the copied content, as specified in
+        /// `.gitcopies`. Reports on all subcopies by default.
+        local_paths: Vec<PathBuf>,
+        /// The format of the report. Only `markdown` is supported.
+        #[structopt(long, default_value = "markdown")]
+        format: report::Format,
+    },
+    /// Print everything in `.gitcopies` and `.gitcopies-lock` as JSON,
+    /// YAML or TOML, for other tools to read.
+    Export {
+        /// The paths to the copied content, as specified in
+        /// `.gitcopies`. Exports all subcopies by default.
+        local_paths: Vec<PathBuf>,
+        /// `json`, `yaml` or `toml`.
+        #[structopt(long, default_value = "json")]
+        format: export::Format,
+        /// Write to this file instead of stdout.
+        #[structopt(short, long)]
+        output: Option<PathBuf>,
+        /// Also compare each subcopy with upstream, and include its
+        /// status like `git subcopy status` does.
+        #[structopt(long)]
+        status: bool,
+    },
+    /// Print where subcopies are from, their license, and the copyright
+    /// lines in upstream's license files, for a NOTICE or THIRD_PARTY
+    /// file.
+    Provenance {
+        /// The paths to the copied content, as specified in
+        /// `.gitcopies`. Prints all subcopies by default.
+        local_paths: Vec<PathBuf>,
+    },
+    /// Record the blob id of every file of subcopies in
+    /// `.gitcopies-lock`, creating it if needed. Once it exists, it's
+    /// kept up to date by `add`, `update`, and `rebase`.
+    Lock {
+        /// The paths to the copied content, as specified in
+        /// `.gitcopies`. Locks all subcopies by default.
+        local_paths: Vec<PathBuf>,
+    },
+    /// Compare subcopies against `.gitcopies-lock`, and list each file
+    /// that was modified, deleted, or added since. Works without
+    /// access to upstream.
+    Verify {
+        /// The paths to the copied content, as specified in
+        /// `.gitcopies`. Verifies all subcopies by default.
+        local_paths: Vec<PathBuf>,
+    },
+    /// Check that `.gitcopies-lock` has every subcopy at the revision in
+    /// `.gitcopies`, and that floating revisions like `branch:main`
+    /// still resolve to the commit pinned there, for CI. Fetches the
+    /// upstreams of floating revisions.
+    VerifyLock {
+        /// Also accept a pinned commit that's behind what a floating
+        /// revision resolves to now, so new upstream commits don't
+        /// fail the check.
+        #[structopt(long)]
+        allow_ancestor: bool,
+    },
+    /// Put back the files and revision a subcopy had before `update` or
+    /// `rebase` last rewrote it. Can be repeated to go further back.
+    Rollback {
+        /// The path to the copied content, as specified in `.gitcopies`
+        local_path: PathBuf,
+    },
+    /// Put back the files and `.gitcopies` as they were before the last
+    /// `add`, `fetch`, `update`, `rebase` or `rollback`. Commits made by
+    /// it are left alone. Can be repeated to undo earlier operations.
+    Undo,
+    /// Check `.gitcopies` for problems, like incomplete or overlapping
+    /// subcopies. Also warns about subcopies of exactly the same
+    /// upstream content.
+    Doctor,
+    /// Show which subcopies differ from upstream at their recorded
+    /// revision. Exits with 0 if all are clean, 1 if some have local
+    /// changes, 2 if files are missing, and 3 if `.gitcopies` has
+    /// errors, like incomplete subcopies or revisions that can't be
+    /// found, or if the status can't be shown at all. The most serious
+    /// one wins.
+    Status {
+        /// The paths to the copied content, as specified in
+        /// `.gitcopies`. Shows all subcopies by default.
+        local_paths: Vec<PathBuf>,
+        /// Print tab-separated fields meant for scripts, guaranteed
+        /// not to change between releases. The only version is `v1`,
+        /// which prints the status (`clean`, `modified`, `missing` or
+        /// `invalid`), the local path, and how many files differ.
+        #[structopt(long, require_equals = true)]
+        porcelain: Option<Option<porcelain::Version>>,
+        /// Only show subcopies in this group, set as `group` in
+        /// `.gitcopies`.
+        #[structopt(long)]
+        group: Option<String>,
+    },
+    /// Open `.gitcopies` in your editor. It's only replaced once the
+    /// edited version is valid, with every revision found upstream.
+    Edit,
+    /// Update subcopies to a newer upstream revision, keeping any
+    /// local changes. By default each subcopy follows the `branch`
+    /// (or tag) set in `.gitcopies`, or else the upstream's default
+    /// branch. A subcopy with a `testCommand` is rolled back and shown
+    /// as blocked if the command fails after updating.
+    Update {
+        /// The paths to the copied content, as specified in
+        /// `.gitcopies`.
+        local_paths: Vec<PathBuf>,
+        /// Update every subcopy.
+        #[structopt(short, long, conflicts_with = "local-paths")]
+        all: bool,
+        /// With --all, only update subcopies whose path in `.gitcopies`
+        /// starts with this prefix.
+        #[structopt(long, requires = "all")]
+        prefix: Option<PathBuf>,
+        /// With --all, only update subcopies in this group, set as
+        /// `group` in `.gitcopies`.
+        #[structopt(long, requires = "all")]
+        group: Option<String>,
+        /// Update to this revision instead of following a branch.
+        #[structopt(long, conflicts_with = "all")]
+        rev: Option<String>,
+        /// Keep subcopies with a floating `rev` like `branch:main` at
+        /// the commit pinned in the lockfile, instead of resolving it
+        /// again.
+        #[structopt(long, conflicts_with = "rev")]
+        locked: bool,
+        /// Commit each updated subcopy separately, along with
+        /// `.gitcopies`.
+        #[structopt(long)]
+        commit: bool,
+        /// Instead of committing to the current branch, commit each
+        /// update to its own `subcopy/update-<path>` branch, starting
+        /// from the current commit. Meant for bots opening pull
+        /// requests.
+        #[structopt(long, requires = "commit")]
+        branch_per_entry: bool,
+        /// Also write the summary of what changed to this file, for
+        /// including in a commit message.
+        #[structopt(long)]
+        summary_file: Option<PathBuf>,
+        /// Print one JSON object per subcopy instead of the table and
+        /// summary, and nothing else, for workflows to consume. Each has
+        /// `path`, `status` (`unchanged`, `updated`, `conflicted`,
+        /// `blocked` or `failed`), `old` and `new` revisions,
+        /// `changed_files`, `conflict`, `conflicts` and `error`.
+        #[structopt(long)]
+        json: bool,
+        /// Fetch up to this many upstreams at once before updating.
+        /// Subcopies are still merged one at a time.
+        #[structopt(short, long, default_value = "1")]
+        jobs: usize,
+        #[structopt(flatten)]
+        worktree: WorktreeOpts,
+    },
+    /// Push every branch and tag of the upstreams of subcopies, as they
+    /// are in the cache, to another remote like an internal mirror.
+    Mirror {
+        /// The paths to the copied content, as specified in
+        /// `.gitcopies`.
+        local_paths: Vec<PathBuf>,
+        /// Mirror the upstreams of every subcopy.
+        #[structopt(short, long, conflicts_with = "local-paths")]
+        all: bool,
+        /// Where to push each upstream. `{host}`, `{path}` and `{name}`
+        /// are replaced with its host, its path without `.git`, and the
+        /// last component of that, like
+        /// `https://git.example.com/mirrors/{path}.git`.
+        #[structopt(long)]
+        to: String,
+        /// Also change the subcopies' `url` in `.gitcopies` to the
+        /// mirror.
+        #[structopt(long)]
+        rewrite: bool,
+    },
+    /// Manage the cache of upstream repositories.
+    Cache(CacheCmd),
+    /// Add the subcopies from another vendoring tool's configuration,
+    /// and extract them. `git-vendor` is read from the history of this
+    /// repository, `vendir` from `vendir.yml`, and `copybara` from the
+    /// workflows in `copy.bara.sky`.
+    Import {
+        /// `git-vendor`, `vendir` or `copybara`.
+        #[structopt(long)]
+        from: import::Tool,
+        /// Where the configuration is, if not in the current directory.
+        /// Local paths in it are relative to its directory.
+        file: Option<PathBuf>,
+        /// Overwrite what's already there, like the files git-vendor
+        /// put in place.
+        #[structopt(long)]
+        force: bool,
+    },
+    /// Turn a directory or file that was copied from upstream by hand
+    /// into a subcopy, by finding the upstream commit it matches best.
+    /// What differs from that commit is kept as local changes.
+    Adopt {
+        /// The copied content to adopt.
+        local_path: PathBuf,
+        /// The repository URL it was copied from.
+        url: String,
+        /// Where in upstream it was copied from. Defaults to the root.
+        upstream_path: Option<PathBuf>,
+        /// Only look at these upstream commits, like `v1.0..v2.0` or a
+        /// single revision and its history. Defaults to every commit on
+        /// any branch or tag.
+        #[structopt(long)]
+        hint: Option<String>,
+    },
+    /// Get or set a property of a subcopy in `.gitcopies`, like
+    /// `description`, `branch`, or `testCommand`. Setting `url` or
+    /// `rev` checks that the revision can be found upstream first, but
+    /// leaves the files as they are.
+    Config {
+        /// The path to the copied content, as specified in `.gitcopies`
+        local_path: PathBuf,
+        /// The name of the property
+        name: String,
+        /// The value to set it to, replacing any others. Without one,
+        /// the current values are printed, one per line, or it exits
+        /// with 1 if there are none.
+        value: Option<String>,
+        /// Add the value to the ones already there, for properties like
+        /// `group` that can be given several times.
+        #[structopt(long, requires = "value")]
+        add: bool,
+        /// Remove the property.
+        #[structopt(long, conflicts_with_all = &["value", "add"])]
+        unset: bool,
+    },
+    /// Hold a subcopy back at its revision, like for compatibility, so
+    /// `update --all` skips it. It can still be updated by name.
+    Freeze {
+        /// The path to the copied content, as specified in `.gitcopies`
+        local_path: PathBuf,
+        /// Why it's held back, shown by `list` and `report`.
+        #[structopt(long)]
+        reason: Option<String>,
+    },
+    /// Let `update --all` update a frozen subcopy again.
+    Unfreeze {
+        /// The path to the copied content, as specified in `.gitcopies`
+        local_path: PathBuf,
+    },
     /// Get a shell in a temporary repository with a worktree clearly
-    /// showing how your copy diverges from the upstream.
+    /// showing how your copy diverges from the upstream. The subcopy is
+    /// described by `GIT_SUBCOPY_PATH`, `GIT_SUBCOPY_URL`,
+    /// `GIT_SUBCOPY_REV`, `GIT_SUBCOPY_UPSTREAM_PATH`, and
+    /// `GIT_SUBCOPY_SUPERPROJECT` in the environment.
     Shell {
         /// The path to the copied content, as specified in
         /// `.gitcopies`.
         local_path: PathBuf,
+        /// Run this command instead of an interactive shell, like
+        /// `shell <path> -- cargo test`. Changed files are only copied
+        /// back if it succeeds.
+        #[structopt(last = true)]
+        command: Vec<OsString>,
     },
     /// Update changes on your local copy to be based on a newer
     /// upstream.
@@ -61,72 +804,1972 @@ enum Opt {
         /// `.gitcopies`.
         local_path: PathBuf,
         /// The new revision to be based upon.
-        rev: String,
+        #[structopt(required_unless = "onto")]
+        rev: Option<String>,
+        /// The new revision to be based upon, like `git rebase --onto`.
+        /// Same as passing it as the revision.
+        #[structopt(long, conflicts_with = "rev")]
+        onto: Option<String>,
+        /// The upstream revision your copy is actually based on, if not
+        /// the one in `.gitcopies`. Only what differs from it is
+        /// rebased, like the upstream argument of `git rebase --onto`.
+        #[structopt(long)]
+        base: Option<String>,
+        /// Commit the changes to each file separately, and run `git
+        /// rebase -i` so they can be reordered, squashed, or dropped
+        /// while rebasing.
+        #[structopt(short, long)]
+        interactive: bool,
+        /// Also write the summary of what changed to this file, for
+        /// including in a commit message.
+        #[structopt(long)]
+        summary_file: Option<PathBuf>,
+        #[structopt(flatten)]
+        worktree: WorktreeOpts,
+    }
+}
+
+/// A subcopy to operate on
+struct Entry {
+    /// The path to the content, usable from the current directory
+    path: PathBuf,
+    conf: SubcopyConfig,
+}
+
+/// Look up the given subcopies in `.gitcopies`, or all of them if none
+/// are given
+fn select_entries(app: &App, local_paths: &[PathBuf]) -> Result<Vec<Entry>> {
+    if !local_paths.is_empty() {
+        return local_paths.iter()
+            .map(|path| Ok(Entry { path: path.clone(), conf: app.get(path)? }))
+            .collect();
+    }
+
+    let workdir = app.workdir()?;
+    let current_dir = env::current_dir().context("failed to get current directory")?;
+    let mut entries = Vec::new();
+    for conf in app.list()?.into_values() {
+        let key = conf.local_path.clone();
+        let path = workdir.join(&key);
+        let path = path.strip_prefix(&current_dir).map(Path::to_path_buf).unwrap_or(path);
+        match conf.complete() {
+            Some(conf) => entries.push(Entry { path, conf }),
+            None => warn!("skipping incomplete subcopy {}", key.display()),
+        }
+    }
+    entries.sort_by(|a, b| a.conf.local_path.cmp(&b.conf.local_path));
+    Ok(entries)
+}
+
+/// How a subcopy differs from its recorded upstream revision, and in
+/// how many files: `clean`, `modified`, `missing` or `invalid`
+fn subcopy_status(app: &App, conf: &SubcopyConfigOption, path: &Path) -> (&'static str, usize) {
+    let (url, rev, upstream_path) = match (&conf.url, &conf.rev, &conf.upstream_path) {
+        (Some(url), Some(rev), Some(upstream_path)) => (url, rev, upstream_path),
+        _ => return ("invalid", 0),
+    };
+    if fs::symlink_metadata(path).is_err() {
+        return ("missing", 0);
+    }
+    let changes = app.fetch_rev(url, Some(rev)).and_then(|repo| {
+        let rev = repo.revparse_single(rev)?.peel_to_commit()?.id();
+        app.local_changes(&repo, rev, upstream_path, path, &conf.options)
+    });
+    match changes {
+        Err(err) => {
+            warn!("failed to compare {} with upstream: {:#}", conf.local_path.display(), err);
+            ("invalid", 0)
+        },
+        Ok(changes) if path.is_dir() && changes.iter().any(|file| fs::symlink_metadata(path.join(file)).is_err()) => ("missing", changes.len()),
+        Ok(changes) if !changes.is_empty() => ("modified", changes.len()),
+        Ok(_) => ("clean", 0),
+    }
+}
+
+/// The URL of the upstream of a subcopy, or else `source` as a URL
+fn source_url(app: &App, source: &str) -> String {
+    match open_superproject().ok().and_then(|_| app.get(Path::new(source)).ok()) {
+        Some(conf) => conf.url,
+        None => sources::expand(source).into_owned(),
+    }
+}
+
+/// Look up the upstream of a subcopy, or else treat `source` as a URL,
+/// and fetch it. Returns the cache, the revision, and the path in
+/// upstream other paths are relative to.
+fn upstream_source(app: &App, source: &str, rev: Option<&str>) -> Result<(Repository, Oid, PathBuf)> {
+    let conf = open_superproject().ok().and_then(|_| app.get(Path::new(source)).ok());
+    let (url, rev, base) = match &conf {
+        Some(conf) => (conf.url.clone(), Some(rev.unwrap_or(&conf.rev)), conf.upstream_path.clone()),
+        None => (sources::expand(source).into_owned(), rev, PathBuf::new()),
+    };
+    let repo = app.fetch_rev(&url, rev).context("failed to fetch git repo")?;
+    let rev = repo.revparse_single(rev.unwrap_or("HEAD")).context("failed to parse revision")?
+        .peel_to_commit().context("revision is not a commit")?
+        .id();
+    Ok((repo, rev, base))
+}
+
+/// Variables describing the subcopy, set for commands run inside of
+/// its temporary repository so prompts and scripts can tell
+fn subcopy_env(app: &App, conf: &SubcopyConfig) -> Result<Vec<(&'static str, OsString)>> {
+    Ok(vec![
+        ("GIT_SUBCOPY_PATH", conf.local_path.clone().into_os_string()),
+        ("GIT_SUBCOPY_URL", OsString::from(&conf.url)),
+        ("GIT_SUBCOPY_REV", OsString::from(&conf.rev)),
+        ("GIT_SUBCOPY_UPSTREAM_PATH", conf.upstream_path.clone().into_os_string()),
+        ("GIT_SUBCOPY_SUPERPROJECT", app.workdir()?.into_os_string()),
+    ])
+}
+
+/// Variables git uses to find a repository. They point to the
+/// superproject when we're run from a hook, or by git in a linked
+/// worktree, and mustn't leak into temporary repositories.
+const GIT_REPO_ENV: &[&str] = &[
+    "GIT_DIR",
+    "GIT_WORK_TREE",
+    "GIT_COMMON_DIR",
+    "GIT_INDEX_FILE",
+    "GIT_OBJECT_DIRECTORY",
+    "GIT_ALTERNATE_OBJECT_DIRECTORIES",
+    "GIT_PREFIX",
+];
+
+/// A command that runs in the working tree of a temporary repository
+fn temp_repo_command<S: AsRef<std::ffi::OsStr>>(program: S, workdir: &Path, vars: Vec<(&'static str, OsString)>) -> Command {
+    let mut command = Command::new(program);
+    for var in GIT_REPO_ENV {
+        command.env_remove(var);
+    }
+    command.envs(vars).current_dir(workdir);
+    command
+}
+
+/// Commit each changed file in the worktree separately on top of
+/// `parent`, so they can be handled one by one in an interactive
+/// rebase. Returns the last commit.
+fn commit_per_file(repo: &Repository, parent: git2::Commit, sign: &Signature) -> Result<Oid> {
+    let mut options = StatusOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(true);
+    let mut paths: Vec<(PathBuf, bool)> = repo.statuses(Some(&mut options)).context("failed to get status")?
+        .iter()
+        .filter_map(|entry| {
+            let deleted = entry.status().contains(Status::WT_DELETED);
+            entry.path().map(|path| (PathBuf::from(path), deleted))
+        })
+        .collect();
+    paths.sort();
+
+    let mut index = repo.index().context("failed to open index")?;
+    let mut parent = parent;
+    for (path, deleted) in paths {
+        if deleted {
+            index.remove_path(&path).context("failed to remove from index")?;
+        } else {
+            index.add_path(&path).context("failed to add to index")?;
+        }
+        let tree = repo.find_tree(index.write_tree().context("failed to write index to tree")?)?;
+        let message = format!("{} {}", if deleted { "Remove" } else { "Change" }, path.display());
+        let id = repo.commit(Some("HEAD"), sign, sign, &message, &tree, &[&parent]).context("failed to commit changes")?;
+        parent = repo.find_commit(id)?;
+    }
+    index.write().context("failed to write index")?;
+    Ok(parent.id())
+}
+
+/// Describe what moving a subcopy from `old` to `new` brought in
+fn summarize(
+    app: &App,
+    conf: &SubcopyConfig,
+    old: Oid,
+    new: Oid,
+    local_changes: &[PathBuf],
+    conflicts: Option<&[PathBuf]>,
+) -> Result<String> {
+    let repo = app.fetch_rev(&conf.url, Some(&new.to_string()))?;
+    let short = |id: Oid| id.to_string()[..7].to_owned();
+
+    let mut summary = format!("{}: {} -> {}\n", conf.local_path.display(), short(old), short(new));
+    let commits = app.upstream_commits(&repo, old, new, &conf.upstream_path)?;
+    summary.push_str(&format!("  Upstream commits ({}):\n", commits.len()));
+    for id in commits {
+        let commit = repo.find_commit(id)?;
+        summary.push_str(&format!("    {} {}\n", short(id), commit.summary().unwrap_or("")));
+    }
+    if !local_changes.is_empty() {
+        summary.push_str("  Kept local changes to:\n");
+        for path in local_changes {
+            summary.push_str(&format!("    {}\n", path.display()));
+        }
+    }
+    match conflicts {
+        Some([]) | None => (),
+        Some(conflicts) => {
+            summary.push_str("  Conflicts, left for `git subcopy rebase`:\n");
+            for path in conflicts {
+                summary.push_str(&format!("    {}\n", path.display()));
+            }
+        },
+    }
+    Ok(summary)
+}
+
+/// Save what changed in the worktree of a shell session to
+/// `.git/subcopy/<path>/last-session.patch` and print a summary, so
+/// the changes are still around if copying them back goes wrong
+fn save_session_patch(conf: &SubcopyConfig, repo: &Repository) -> Result<()> {
+    let head = repo.head().context("failed to find HEAD")?.peel_to_tree().context("HEAD is not a commit")?;
+    let mut options = DiffOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(true).show_untracked_content(true).show_binary(true);
+    if !conf.upstream_path.as_os_str().is_empty() && conf.upstream_path != Path::new(".") {
+        options.pathspec(&conf.upstream_path);
+    }
+    let diff = repo.diff_tree_to_workdir_with_index(Some(&head), Some(&mut options)).context("failed to diff worktree")?;
+    let attributes = diff::Attributes::from_tree(repo, &head)?;
+    let differ = diff::Differ {
+        repo,
+        attributes: &attributes,
+        upstream_path: Path::new(""),
+        skips: &[],
+        display: &Path::to_path_buf,
+    };
+    let changes = differ.changes(&diff)?;
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    // Unlike `diff::print_patch`, this has everything `git apply` needs,
+    // like file modes and the content of binary files
+    let mut patch = Vec::new();
+    diff.print(DiffFormat::Patch, |_, _, line| {
+        if matches!(line.origin(), '+' | '-' | ' ') {
+            patch.push(line.origin() as u8);
+        }
+        patch.extend_from_slice(line.content());
+        true
+    }).context("failed to print patch")?;
+
+    let superproject = open_superproject()?;
+    let path = superproject.path().join("subcopy").join(&conf.local_path).join("last-session.patch");
+    fs::create_dir_all(path.parent().expect("patch is in a directory")).context("failed to create patch directory")?;
+    fs::write(&path, patch).context("failed to write patch")?;
+
+    eprintln!("Saved the changes of this session to {}", path.display());
+    diff::print_stat(&changes, &mut io::stderr())?;
+    Ok(())
+}
+
+/// Make sure nothing was left half-done in a temporary repository,
+/// before its files are copied back. Errors skip the copy-back.
+fn ensure_finished(repo: &Repository, status: ExitStatus) -> Result<()> {
+    ensure!(status.success(), "the shell or command exited with {}, so nothing was copied back", status);
+    match repo.state() {
+        RepositoryState::Clean => Ok(()),
+        RepositoryState::Rebase | RepositoryState::RebaseInteractive | RepositoryState::RebaseMerge => {
+            bail!("the rebase wasn't finished, so nothing was copied back. Run the rebase again and use `git rebase --continue` until it's done")
+        },
+        state => bail!("the repository was left in the middle of an operation ({:?}), so nothing was copied back", state),
+    }
+}
+
+/// How many files there are in a subcopy, and their total size
+fn vendored_size(local_path: &Path) -> Result<(usize, u64)> {
+    let (mut files, mut size) = (0, 0);
+    if !local_path.exists() {
+        return Ok((files, size));
+    }
+    for entry in WalkDir::new(local_path).into_iter().filter_entry(|e| e.file_name() != ".git") {
+        let entry = entry.context("failed to read directory entry")?;
+        if !entry.file_type().is_dir() {
+            files += 1;
+            size += entry.metadata().context("failed to stat file")?.len();
+        }
+    }
+    Ok((files, size))
+}
+
+/// When a subcopy was last changed by git-subcopy, according to the
+/// journal, or otherwise the last commit that changed it
+fn last_updated(repo: &Repository, journal: &[journal::Operation], key: &Path) -> Result<Option<i64>> {
+    let journaled = journal.iter().rev()
+        .find(|operation| operation.name.split(' ').skip(1).any(|path| Path::new(path) == key));
+    if let Some(operation) = journaled {
+        return Ok(Some(repo.find_commit(operation.id)?.time().seconds()));
+    }
+
+    let mut walk = repo.revwalk().context("failed to walk history")?;
+    if walk.push_head().is_err() {
+        return Ok(None);
+    }
+    walk.simplify_first_parent();
+    for id in walk {
+        let commit = repo.find_commit(id?)?;
+        let at = |tree: git2::Tree| tree.get_path(key).ok().map(|entry| entry.id());
+        let parent = commit.parent(0).ok().map(|parent| parent.tree()).transpose()?.and_then(at);
+        if at(commit.tree()?) != parent {
+            return Ok(Some(commit.time().seconds()));
+        }
+    }
+    Ok(None)
+}
+
+/// A duration in seconds as a rough human readable age, like `3 days`
+fn age(seconds: i64) -> String {
+    const UNITS: &[(&str, i64)] = &[
+        ("year", 365 * 24 * 60 * 60),
+        ("month", 30 * 24 * 60 * 60),
+        ("day", 24 * 60 * 60),
+        ("hour", 60 * 60),
+        ("minute", 60),
+    ];
+    for (unit, length) in UNITS {
+        let count = seconds / length;
+        if count > 0 {
+            return format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" });
+        }
+    }
+    "moments".to_owned()
+}
+
+/// How an operation on subcopies is shown in the journal
+fn describe(operation: &str, local_paths: &[&Path]) -> String {
+    let mut description = operation.to_owned();
+    for local_path in local_paths {
+        description.push(' ');
+        description.push_str(&local_path.to_string_lossy());
+    }
+    description
+}
+
+/// The editor to use, chosen the same way git does
+fn editor(repo: &Repository) -> OsString {
+    env::var_os("GIT_EDITOR")
+        .or_else(|| repo.config().ok()?.get_string("core.editor").ok().map(OsString::from))
+        .or_else(|| env::var_os("VISUAL"))
+        .or_else(|| env::var_os("EDITOR"))
+        .unwrap_or_else(|| OsString::from("vi"))
+}
+
+/// What happened to a subcopy during `update`
+enum Outcome {
+    Unchanged,
+    Updated,
+    Conflicted(Vec<PathBuf>),
+    /// The test command failed, so the update was rolled back
+    Blocked,
+}
+
+/// Update a single subcopy, returning the old and new revision
+fn update(app: &App, entry: &Entry, rev: Option<&str>) -> Result<(Oid, Oid, Outcome)> {
+    let conf = &entry.conf;
+    // The latest upstream revision is only needed when none is given
+    let repo = match rev {
+        Some(_) => app.fetch_rev(&conf.url, rev),
+        None => app.fetch(&conf.url, true),
+    }.context("failed to fetch git repo")?;
+
+    let old = repo.revparse_single(&conf.rev).context("failed to parse recorded revision")?.id();
+    let new = match (rev, &conf.floating) {
+        (None, Some(floating)) => floating.resolve(&repo)?,
+        _ => {
+            let target = rev.or(conf.branch.as_deref()).unwrap_or("HEAD");
+            repo.revparse_single(target).with_context(|| format!("failed to parse revision {}", target))?
+                .peel_to_commit().context("revision is not a commit")?
+                .id()
+        },
+    };
+
+    if old == new {
+        return Ok((old, new, Outcome::Unchanged));
+    }
+
+    app.snapshot(&open_superproject()?, conf, &entry.path)?;
+    match app.merge_upstream(&repo, old, new, &conf.upstream_path, &entry.path, &conf.options)? {
+        Merge::Conflicted(paths) => Ok((old, new, Outcome::Conflicted(paths))),
+        Merge::Updated => {
+            app.register(&conf.url, new, &conf.upstream_path, &entry.path).context("failed to register new rev")?;
+            if let Some(command) = &conf.test_command {
+                if !run_test_command(app, conf, new, command)? {
+                    warn!("test command of {} failed, rolling back to {}", conf.local_path.display(), &old.to_string()[..7]);
+                    app.rollback(&entry.path).context("failed to roll back update")?;
+                    return Ok((old, new, Outcome::Blocked));
+                }
+            }
+            Ok((old, new, Outcome::Updated))
+        },
+    }
+}
+
+/// Run `subcopy.<path>.testCommand` in the root of the working tree,
+/// after updating to `new`. Its output goes to stderr, so it doesn't
+/// mix with ours.
+fn run_test_command(app: &App, conf: &SubcopyConfig, new: Oid, command: &str) -> Result<bool> {
+    info!("Testing {}...", conf.local_path.display());
+    let mut vars = subcopy_env(app, conf)?;
+    vars.retain(|(name, _)| *name != "GIT_SUBCOPY_REV");
+    vars.push(("GIT_SUBCOPY_REV", OsString::from(new.to_string())));
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(vars)
+        .current_dir(app.workdir()?)
+        .stdout(io::stderr())
+        .status()
+        .with_context(|| format!("failed to run test command {:?}", command))?;
+    Ok(status.success())
+}
+
+/// Describe the result of updating a subcopy as a JSON object on one
+/// line, for `update --json`
+fn update_json(app: &App, entry: &Entry, result: &Result<(Oid, Oid, Outcome)>) -> String {
+    let string = |value: &str| porcelain::json_string(value);
+    let list = |paths: &[PathBuf]| {
+        let items: Vec<String> = paths.iter().map(|path| string(&path.to_string_lossy())).collect();
+        format!("[{}]", items.join(","))
+    };
+    let conf = &entry.conf;
+    let (status, old, new, conflicts, error) = match result {
+        Ok((old, new, outcome)) => {
+            let (status, conflicts) = match outcome {
+                Outcome::Unchanged => ("unchanged", &[][..]),
+                Outcome::Updated => ("updated", &[][..]),
+                Outcome::Conflicted(paths) => ("conflicted", &paths[..]),
+                Outcome::Blocked => ("blocked", &[][..]),
+            };
+            (status, string(&old.to_string()), string(&new.to_string()), conflicts, "null".to_owned())
+        },
+        Err(err) => ("failed", "null".to_owned(), "null".to_owned(), &[][..], string(&format!("{:#}", err))),
+    };
+    let changed = match result {
+        Ok((old, new, Outcome::Updated)) => changed_files(app, conf, *old, *new).unwrap_or_else(|err| {
+            warn!("failed to list changed files of {}: {:#}", conf.local_path.display(), err);
+            Vec::new()
+        }),
+        _ => Vec::new(),
+    };
+    format!(
+        "{{\"path\":{},\"status\":{},\"old\":{},\"new\":{},\"changed_files\":{},\"conflict\":{},\"conflicts\":{},\"error\":{}}}",
+        string(&conf.local_path.to_string_lossy()),
+        string(status),
+        old,
+        new,
+        list(&changed),
+        !conflicts.is_empty(),
+        list(conflicts),
+        error,
+    )
+}
+
+/// The local files that upstream changed between two revisions
+fn changed_files(app: &App, conf: &SubcopyConfig, old: Oid, new: Oid) -> Result<Vec<PathBuf>> {
+    let repo = app.fetch_rev(&conf.url, Some(&new.to_string()))?;
+    let old = app.find_upstream(&repo, old, &conf.upstream_path)?;
+    let new = app.find_upstream(&repo, new, &conf.upstream_path)?;
+    if old.kind() == Some(ObjectType::Blob) {
+        return Ok(vec![conf.local_path.clone()]);
     }
+    let diff = app.diff_content(&repo, old.id(), new.id(), "")?;
+    let mut paths: Vec<PathBuf> = diff.deltas()
+        .filter_map(|delta| delta.new_file().path().or_else(|| delta.old_file().path()))
+        .filter(|path| !skip::is_skipped(&conf.options.skips, path))
+        .map(|path| conf.local_path.join(remap::to_local(&conf.options.remap, path)))
+        .collect();
+    paths.sort();
+    Ok(paths)
 }
 
-fn main() -> Result<()> {
-    env_logger::init_from_env(
+/// The lockfile relative to the root of the working tree, if there is one
+fn existing_lock_path(app: &App, repo: &Repository) -> Result<Option<PathBuf>> {
+    let lock_path = app.lock_path(repo)?;
+    Ok(if lock_path.exists() { Some(app.canonicalize(repo, &lock_path)?) } else { None })
+}
+
+/// Update a subcopy and commit the result, optionally on a separate
+/// branch which is then left for the user
+fn update_and_commit(
+    app: &App,
+    repo: &Repository,
+    entry: &Entry,
+    rev: Option<&str>,
+    manifest: &Path,
+    branch_per_entry: bool,
+) -> Result<(Oid, Oid, Outcome)> {
+    let head = repo.head().context("failed to find head")?;
+    let start_ref = head.name().filter(|_| head.is_branch()).map(String::from);
+    let start = head.peel_to_commit().context("head wasn't a commit")?;
+
+    let key = &entry.conf.local_path;
+    let branch = format!("subcopy/update-{}", key.to_string_lossy().replace('/', "-"));
+    if branch_per_entry {
+        repo.branch(&branch, &start, true).context("failed to create branch")?;
+        repo.set_head(&format!("refs/heads/{}", branch)).context("failed to switch branch")?;
+    }
+
+    let result = update(app, entry, rev).and_then(|result| {
+        if let (old, new, Outcome::Updated) = &result {
+            let message = format!(
+                "Update {} to {}\n\nUpstream: {}\nOld revision: {}\nNew revision: {}\n",
+                key.display(),
+                &new.to_string()[..7],
+                entry.conf.url,
+                old,
+                new,
+            );
+            let lock_path = existing_lock_path(app, repo)?;
+            let mut paths = vec![key.as_path(), manifest];
+            paths.extend(lock_path.as_deref());
+            app.commit_paths(repo, &paths, &message).context("failed to commit update")?;
+        }
+        Ok(result)
+    });
+
+    if branch_per_entry {
+        // Get back to where we started, leaving the update on its
+        // branch. Only what the update touched is checked out, anything
+        // else the user is working on stays as it is.
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force().path(key).path(manifest);
+        if let Some(lock_path) = existing_lock_path(app, repo)? {
+            checkout.path(lock_path);
+        }
+        repo.checkout_tree(start.as_object(), Some(&mut checkout)).context("failed to check out original commit")?;
+        match &start_ref {
+            Some(name) => repo.set_head(name),
+            None => repo.set_head_detached(start.id()),
+        }.context("failed to switch back to original branch")?;
+
+        if !matches!(result, Ok((_, _, Outcome::Updated))) {
+            repo.find_branch(&branch, BranchType::Local)?.delete().context("failed to delete unused branch")?;
+        }
+    }
+    result
+}
+
+fn main() {
+    // Returning first drops everything, cleaning up temporary state
+    if let Err(err) = run() {
+        eprintln!("Error: {:?}", err);
+        process::exit(if interrupt::is_interrupted() { interrupt::EXIT_STATUS } else { 1 });
+    }
+}
+
+fn run() -> Result<()> {
+    let matches = match Opt::clap().get_matches_from_safe(env::args_os()) {
+        Ok(matches) => matches,
+        Err(err) => match (err.kind, err.info.as_ref().and_then(|info| info.first())) {
+            (ErrorKind::InvalidSubcommand, Some(name))
+            | (ErrorKind::UnrecognizedSubcommand, Some(name))
+            | (ErrorKind::UnknownArgument, Some(name)) => {
+                let args: Vec<_> = env::args_os().collect();
+                match plugin::run(&args, name)? {
+                    Some(code) => process::exit(code),
+                    None => err.exit(),
+                }
+            },
+            _ => err.exit(),
+        },
+    };
+    let opt = Opt::from_clap(&matches);
+
+    let level = match (opt.quiet, opt.verbose) {
+        (true, _) => "git_subcopy=error",
+        (false, 0) => "git_subcopy=info",
+        (false, 1) => "git_subcopy=debug",
+        (false, _) => "git_subcopy=trace",
+    };
+    let mut logger = env_logger::Builder::from_env(
         env_logger::Env::new()
-            .default_filter_or("git_subcopy=info")
+            .default_filter_or(level)
     );
+    if opt.log_format == LogFormat::Json {
+        logger.format(|buf, record| {
+            let timestamp = buf.timestamp().to_string();
+            json_log(buf, &timestamp, record)
+        });
+    }
+    logger.init();
+    interrupt::install();
+    let _span = span::enter("command", matches.subcommand_name().unwrap_or_default());
+
+    // Explanatory messages for humans, skipped when running quietly
+    let banner = |lines: &[&str]| {
+        if !opt.quiet {
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+    };
+
+    let mut app = App::new()?;
+    app.set_manifest_path(opt.gitcopies_file.clone());
+    app.set_refresh(match (opt.refresh, opt.no_refresh) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    });
+    // Progress bars would garble logs meant for machines
+    app.set_progress(!opt.quiet && opt.log_format == LogFormat::Text && io::stdout().is_terminal() && io::stderr().is_terminal());
+    app.set_interactive(io::stdin().is_terminal() && env::var_os("GIT_TERMINAL_PROMPT").is_none_or(|prompt| prompt != "0"));
 
-    let opt = Opt::from_args();
-    let app = App::new()?;
+    match &opt.cmd {
+        Cmd::Fetch { opts }
+        | Cmd::Add { opts } => {
+            let url = if opts.from_self {
+                String::from(".")
+            } else {
+                app.relative_source(&sources::expand(&opts.url))?
+            };
+            let (rev, upstream_path, local_path) = opts.positional()?;
+            let repo = app.fetch_rev(&url, rev).context("failed to fetch git repo")?;
 
-    match &opt {
-        Opt::Fetch { opts }
-        | Opt::Add { opts } => {
-            let repo = app.fetch(&opts.url, true).context("failed to fetch git repo")?;
+            let mut options = CopyOptions {
+                remap: opts.remap.clone(),
+                filters: opts.filter.clone(),
+                skips: opts.skip.clone(),
+                empty_dirs: opts.empty_dir.clone(),
+                allow_dot_git: opts.allow_dot_git,
+                ..CopyOptions::default()
+            };
+            let git_config = match open_superproject() {
+                Ok(superproject) => superproject.config(),
+                Err(_) => git2::Config::open_default(),
+            }.context("failed to open git config")?;
+            filter::configure(&mut options.filters, &git_config)?;
+            let floating = match rev {
+                Some(rev) if floating::is_floating(rev) => Some(rev.parse::<Floating>()?),
+                _ => None,
+            };
+            let rev = match &floating {
+                Some(floating) => floating.resolve(&repo)?,
+                None => repo.revparse_single(rev.unwrap_or("HEAD")).context("failed to parse revision")?.id(),
+            };
 
-            ensure!(!opts.local_path.exists() || opts.force, "this could overwrite files, use --force if you're sure");
+            let picked = match upstream_path {
+                Some(upstream_path) => vec![(upstream_path.to_path_buf(), local_path.to_path_buf())],
+                None => {
+                    let entries = app.upstream_entries(&repo, rev, Path::new("")).context("failed to list upstream tree")?;
+                    match Picker::new(entries).run()? {
+                        Some(picked) => picked.into_iter()
+                            .map(|upstream_path| {
+                                let local_path = local_path.join(&upstream_path);
+                                (upstream_path, local_path)
+                            })
+                            .collect(),
+                        None => return Ok(()),
+                    }
+                },
+            };
 
-            let rev = repo.revparse_single(&opts.rev).context("failed to parse revision")?.id();
-            app.extract(&repo, rev, &opts.upstream_path, &opts.local_path).context("failed to extract files")?;
+            for (_, local_path) in &picked {
+                app.check_destination(local_path)?;
+                ensure!(!local_path.exists() || opts.force, "this could overwrite files, use --force if you're sure");
 
-            if let Opt::Add { .. } = &opt {
-                app.register(&opts.url, rev, &opts.upstream_path, &opts.local_path).context("failed to register to .gitcopies")?;
+                if let Cmd::Add { .. } = &opt.cmd {
+                    let overlapping = app.overlapping(local_path)?;
+                    if let Some(other) = overlapping.first().filter(|_| !opts.allow_nested) {
+                        bail!("{} overlaps with the subcopy {}, use --allow-nested if you're sure", local_path.display(), other.display());
+                    }
+                }
+            }
+
+            // Catch typos like a monorepo's root before filling the disk
+            let mut size = 0;
+            for (upstream_path, _) in &picked {
+                size += app.upstream_size(&repo, rev, upstream_path).context("failed to compute size")?;
+            }
+            let size = Size(size);
+            match opts.max_size {
+                Some(max_size) => ensure!(size <= max_size, "refusing to extract {}, more than --max-size {}", size, max_size),
+                None if size > CONFIRM_SIZE => {
+                    ensure!(io::stdin().is_terminal(), "refusing to extract {} without confirmation, pass --max-size to allow it", size);
+                    if !confirm(&format!("This will extract {}, continue?", size))? {
+                        return Ok(());
+                    }
+                },
+                None => (),
+            }
+            let operation = if let Cmd::Add { .. } = &opt.cmd { "add" } else { "fetch" };
+            let local_paths: Vec<&Path> = picked.iter().map(|(_, local_path)| local_path.as_path()).collect();
+            app.journal(&describe(operation, &local_paths), &local_paths)?;
+            for (upstream_path, local_path) in &picked {
+                if opts.interactive {
+                    if let Some(parent) = local_path.parent() {
+                        fs::create_dir_all(parent).context("failed to create parent directories")?;
+                    }
+                }
+                app.extract(&repo, rev, upstream_path, local_path, &options).context("failed to extract files")?;
+                if opts.verify {
+                    let mismatched = app.verify_extracted(&repo, rev, upstream_path, local_path, &options).context("failed to verify files")?;
+                    for path in &mismatched {
+                        error!("{} doesn't match upstream", path.display());
+                    }
+                    ensure!(mismatched.is_empty(), "{} file(s) were changed while writing them", mismatched.len());
+                }
+
+                if let Cmd::Add { .. } = &opt.cmd {
+                    app.register(&url, rev, upstream_path, local_path).context("failed to register to .gitcopies")?;
+                    app.set_options(local_path, &options).context("failed to register to .gitcopies")?;
+                    if let Some(floating) = &floating {
+                        app.set_floating(local_path, floating, rev).context("failed to register to .gitcopies")?;
+                    }
+                }
             }
         },
-        Opt::List => {
+        Cmd::List { porcelain, paths, urls, revs, group, long } => {
             let configs = app.list()?;
+            let mut configs: Vec<_> = configs.values()
+                .filter(|conf| group.as_ref().is_none_or(|group| conf.groups.contains(group)))
+                .collect();
+            configs.sort_by(|a, b| a.local_path.cmp(&b.local_path));
 
-            for conf in configs.values() {
-                let url = conf.url.as_ref().map(|p| &**p).unwrap_or("<unknown>");
-                let rev = conf.rev.as_ref().map(|p| &**p).unwrap_or("<unknown>");
-                let upstream_path = conf.upstream_path.as_ref().map(|p| &**p).unwrap_or_else(|| Path::new("<unknown>"));
+            if *paths {
+                for conf in configs {
+                    println!("{}", conf.local_path.display());
+                }
+                return Ok(());
+            }
+            if *urls {
+                let mut seen = HashSet::new();
+                for url in configs.iter().filter_map(|conf| conf.url.as_deref()) {
+                    if seen.insert(url) {
+                        println!("{}", url);
+                    }
+                }
+                return Ok(());
+            }
+            if *revs {
+                for conf in configs {
+                    println!("{}\t{}", conf.local_path.display(), conf.rev.as_deref().unwrap_or(""));
+                }
+                return Ok(());
+            }
+
+            if let Some(porcelain::Version::V1) = porcelain.map(Option::unwrap_or_default) {
+                for conf in configs {
+                    println!("{}", porcelain::record(&[
+                        &conf.local_path.to_string_lossy(),
+                        conf.url.as_deref().unwrap_or(""),
+                        conf.rev.as_deref().unwrap_or(""),
+                        &conf.upstream_path.as_deref().unwrap_or_else(|| Path::new("")).to_string_lossy(),
+                    ]));
+                }
+                return Ok(());
+            }
+
+            for conf in configs {
+                let url = conf.url.as_deref().unwrap_or("<unknown>");
+                let rev = conf.rev.as_deref().unwrap_or("<unknown>");
+                let upstream_path = conf.upstream_path.as_deref().unwrap_or_else(|| Path::new("<unknown>"));
                 let local_path = &conf.local_path;
-                println!("{} = Cloned from {}:{}, revision {}", local_path.display(), url, upstream_path.display(), rev);
+                let overridden = if conf.overridden { " (overridden in .gitcopies.local)" } else { "" };
+                let frozen = match (conf.frozen, &conf.frozen_reason) {
+                    (true, Some(reason)) => format!(" (frozen: {})", reason),
+                    (true, None) => " (frozen)".to_owned(),
+                    (false, _) => String::new(),
+                };
+                println!("{} = Cloned from {}:{}, revision {}{}{}", local_path.display(), url, upstream_path.display(), rev, overridden, frozen);
+                if *long {
+                    if let Some(description) = &conf.description {
+                        println!("    {}", description);
+                    }
+                    println!("    added by {} at {}", conf.added_by.as_deref().unwrap_or("<unknown>"), conf.added_at.as_deref().unwrap_or("<unknown>"));
+                    println!("    last updated at {}", conf.last_updated_at.as_deref().unwrap_or("<unknown>"));
+                }
+            }
+        },
+        Cmd::Show { local_path } => {
+            let conf = app.get(local_path)?;
+            let overridden = if conf.overridden { " (overridden in .gitcopies.local)" } else { "" };
+            println!("{}", conf.local_path.display());
+            if let Some(description) = &conf.description {
+                println!("    description: {}", description);
+            }
+            println!("    url: {}{}", conf.url, overridden);
+            match &conf.floating {
+                Some(floating) => println!("    rev: {} (pinned at {}){}", floating, conf.rev, overridden),
+                None => println!("    rev: {}{}", conf.rev, overridden),
+            }
+            println!("    upstreamPath: {}", conf.upstream_path.display());
+            if let Some(branch) = &conf.branch {
+                println!("    branch: {}", branch);
+            }
+            for group in &conf.groups {
+                println!("    group: {}", group);
+            }
+            if let Some(test_command) = &conf.test_command {
+                println!("    testCommand: {}", test_command);
+            }
+            if conf.frozen {
+                println!("    frozen: true");
+            }
+            if let Some(reason) = &conf.frozen_reason {
+                println!("    frozenReason: {}", reason);
+            }
+            for remap in &conf.options.remap {
+                println!("    remap: {}", remap);
+            }
+            for filter in &conf.options.filters {
+                println!("    filter: {}", filter);
+            }
+            for skip in &conf.options.skips {
+                println!("    skip: {}", skip);
+            }
+            for dir in &conf.options.empty_dirs {
+                println!("    emptyDir: {}", dir.display());
+            }
+            if conf.options.allow_dot_git {
+                println!("    allowDotGit: true");
+            }
+            if conf.options.frozen_file_set {
+                println!("    frozenFileSet: true");
+            }
+            println!("    addedBy: {}", conf.added_by.as_deref().unwrap_or("<unknown>"));
+            println!("    addedAt: {}", conf.added_at.as_deref().unwrap_or("<unknown>"));
+            println!("    lastUpdatedAt: {}", conf.last_updated_at.as_deref().unwrap_or("<unknown>"));
+        },
+        Cmd::GlobalList { url: filter } => {
+            for workdir in registry::repositories()? {
+                let configs = match Repository::open(&workdir).map_err(Into::into).and_then(|repo| app.list_in(&repo)) {
+                    Ok(configs) => configs,
+                    Err(err) => {
+                        warn!("failed to list subcopies of {}: {:#}", workdir.display(), err);
+                        continue;
+                    },
+                };
+                let mut configs: Vec<_> = configs.values()
+                    .filter(|conf| filter.as_ref().is_none_or(|filter| conf.url.as_ref().is_some_and(|url| url.contains(filter.as_str()))))
+                    .collect();
+                configs.sort_by(|a, b| a.local_path.cmp(&b.local_path));
+                for conf in configs {
+                    let url = conf.url.as_deref().unwrap_or("<unknown>");
+                    let rev = conf.rev.as_deref().unwrap_or("<unknown>");
+                    let upstream_path = conf.upstream_path.as_deref().unwrap_or_else(|| Path::new("<unknown>"));
+                    let local_path = workdir.join(&conf.local_path);
+                    println!("{} = Cloned from {}:{}, revision {}", local_path.display(), url, upstream_path.display(), rev);
+                }
+            }
+        },
+        Cmd::Grep { pattern, local_paths, line_number } => {
+            let pattern = Regex::new(pattern).context("invalid pattern")?;
+
+            let entries = select_entries(&app, local_paths)?;
+
+            for Entry { conf, .. } in entries {
+                let repo = app.fetch_rev(&conf.url, Some(&conf.rev)).context("failed to fetch git repo")?;
+                let rev = repo.revparse_single(&conf.rev).context("failed to parse revision")?.id();
+                let local_path = &conf.local_path;
+
+                app.grep(&repo, rev, &conf.upstream_path, &pattern, |path, number, line| {
+                    if *line_number {
+                        println!("{}:{}:{}:{}", local_path.display(), path.display(), number, line);
+                    } else {
+                        println!("{}:{}:{}", local_path.display(), path.display(), line);
+                    }
+                })?;
+            }
+        },
+        Cmd::Search { local_path, string, rev } => {
+            let conf = app.get(local_path)?;
+            let repo = match rev {
+                Some(_) => app.fetch_rev(&conf.url, rev.as_deref()),
+                None => app.fetch(&conf.url, true),
+            }.context("failed to fetch git repo")?;
+            let recorded = repo.revparse_single(&conf.rev).context("failed to parse recorded revision")?.id();
+            let target = rev.as_deref().or(conf.branch.as_deref()).unwrap_or("HEAD");
+            let tip = repo.revparse_single(target).with_context(|| format!("failed to parse revision {}", target))?
+                .peel_to_commit().context("revision is not a commit")?
+                .id();
+
+            for id in app.pickaxe(&repo, tip, &conf.upstream_path, string.as_bytes())? {
+                let commit = repo.find_commit(id)?;
+                let included = id == recorded || repo.graph_descendant_of(recorded, id)?;
+                println!("{} {} {}", if included { " " } else { "+" }, &id.to_string()[..7], commit.summary().unwrap_or(""));
+            }
+        },
+        Cmd::Bisect { local_path, good, bad, command } => {
+            let conf = app.get(local_path)?;
+            let superproject = open_superproject()?;
+            app.fetch_rev(&conf.url, Some(good)).context("failed to fetch git repo")?;
+            let repo = app.fetch_rev(&conf.url, Some(bad)).context("failed to fetch git repo")?;
+            let commit = |rev: &str| -> Result<Oid> {
+                Ok(repo.revparse_single(rev).with_context(|| format!("failed to parse revision {}", rev))?
+                    .peel_to_commit().context("revision is not a commit")?
+                    .id())
+            };
+            let (good, bad) = (commit(good)?, commit(bad)?);
+            ensure!(repo.graph_descendant_of(bad, good)?, "the good revision has to be an ancestor of the bad one");
+            let commits = app.upstream_commits(&repo, good, bad, &conf.upstream_path)?;
+            ensure!(!commits.is_empty(), "upstream didn't change {} between the good and bad revision", conf.upstream_path.display());
+
+            // A snapshot puts back the local content even if we're killed
+            // halfway, with `rollback`
+            let existed = fs::symlink_metadata(local_path).is_ok();
+            app.snapshot(&superproject, &conf, local_path)?;
+            let vars = subcopy_env(&app, &conf)?;
+            let is_good = |id: Oid| -> Result<bool> {
+                info!("Testing {}...", id);
+                match fs::symlink_metadata(local_path) {
+                    Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(local_path)?,
+                    Ok(_) => fs::remove_file(local_path)?,
+                    Err(_) => (),
+                }
+                app.extract(&repo, id, &conf.upstream_path, local_path, &conf.options).context("failed to extract files")?;
+                let (program, args) = command.split_first().expect("structopt requires a command");
+                let status = Command::new(program).args(args).envs(vars.clone()).status()
+                    .with_context(|| format!("failed to run {}", program.to_string_lossy()))?;
+                Ok(status.success())
+            };
+
+            // The last commit has the same content as the bad revision,
+            // so it's known to be bad
+            let (mut low, mut high) = (0, commits.len() - 1);
+            let mut result = Ok(());
+            while low < high {
+                let middle = (low + high) / 2;
+                match is_good(commits[middle]) {
+                    Ok(true) => low = middle + 1,
+                    Ok(false) => high = middle,
+                    Err(err) => {
+                        result = Err(err);
+                        break;
+                    },
+                }
+            }
+
+            if existed {
+                let snapshot = snapshot::latest(&superproject, &conf.local_path)?
+                    .ok_or_else(|| anyhow!("the snapshot of {} disappeared", conf.local_path.display()))?;
+                snapshot::restore(&superproject, &conf.local_path, &snapshot, local_path)
+                    .context("failed to put back local content")?;
+            } else if fs::symlink_metadata(local_path).is_ok() {
+                fs::remove_dir_all(local_path).or_else(|_| fs::remove_file(local_path)).context("failed to remove extracted content")?;
+            }
+            result?;
+
+            let first_bad = repo.find_commit(commits[low])?;
+            println!("The first bad upstream commit is {} {}", first_bad.id(), first_bad.summary().unwrap_or(""));
+        },
+        Cmd::Owner { file, porcelain } => {
+            let (conf, upstream_file) = app.owner(file)?
+                .ok_or_else(|| anyhow!("{} is not part of any subcopy", file.display()))?;
+
+            if let Some(porcelain::Version::V1) = porcelain.map(Option::unwrap_or_default) {
+                println!("{}", porcelain::record(&[
+                    &conf.local_path.to_string_lossy(),
+                    &conf.url,
+                    &conf.rev,
+                    &upstream_file.to_string_lossy(),
+                ]));
+                return Ok(());
+            }
+
+            println!("{} belongs to {}", file.display(), conf.local_path.display());
+            println!("    url:           {}", conf.url);
+            println!("    upstream path: {}", upstream_file.display());
+            println!("    revision:      {}", conf.rev);
+        },
+        Cmd::Cat { source, upstream_file, rev } => {
+            let (repo, rev, base) = upstream_source(&app, source, rev.as_deref())?;
+            let path = match upstream_file {
+                Some(file) => base.join(file),
+                None => base,
+            };
+            let blob = app.find_upstream(&repo, rev, &path)?
+                .peel_to_blob().with_context(|| format!("{} is not a file", path.display()))?;
+
+            let mut stdout = io::stdout();
+            if let Err(err) = stdout.write_all(blob.content()).and_then(|()| stdout.flush()) {
+                // Piping into something like `head` is fine
+                if err.kind() != io::ErrorKind::BrokenPipe {
+                    return Err(err).context("failed to write to stdout");
+                }
+            }
+        },
+        Cmd::Ls { opts } => {
+            let (repo, id, path) = opts.find(&app)?;
+            let object = repo.find_object(id, None)?;
+            let odb = repo.odb().context("failed to open object database")?;
+
+            let entries: Vec<(String, Oid, ObjectType, i32)> = match object.as_tree() {
+                Some(tree) => tree.iter()
+                    .filter_map(|entry| Some((entry.name()?.to_owned(), entry.id(), entry.kind()?, entry.filemode())))
+                    .collect(),
+                None => {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                    vec![(name, object.id(), ObjectType::Blob, 0o100644)]
+                },
+            };
+            for (name, id, kind, mode) in entries {
+                let size = match kind {
+                    ObjectType::Blob => odb.read_header(id).context("failed to read object header")?.0.to_string(),
+                    _ => String::from("-"),
+                };
+                let suffix = if kind == ObjectType::Tree { "/" } else { "" };
+                println!("{:06o} {:<6} {:>10}  {}{}", mode, kind, size, name, suffix);
+            }
+        },
+        Cmd::Tree { opts } => {
+            let (repo, id, path) = opts.find(&app)?;
+
+            let root = if path.as_os_str().is_empty() { String::from(".") } else { path.display().to_string() };
+            println!("{}", root);
+            // A single file has nothing under it
+            let tree = repo.find_tree(id).ok();
+            if let Some(tree) = tree {
+                tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+                    let depth = dir.matches('/').count();
+                    let suffix = if entry.kind() == Some(ObjectType::Tree) { "/" } else { "" };
+                    println!("{}{}{}", "    ".repeat(depth + 1), entry.name().unwrap_or("<invalid utf-8>"), suffix);
+                    git2::TreeWalkResult::Ok
+                }).context("failed to walk tree")?;
+            }
+        },
+        Cmd::Diff { local_paths, rev, stat } => {
+            let mut changes = Vec::new();
+            for entry in select_entries(&app, local_paths)? {
+                let _span = span::enter("entry", entry.conf.local_path.display());
+                let conf = &entry.conf;
+                let repo = app.fetch_rev(&conf.url, Some(rev.as_deref().unwrap_or(&conf.rev))).context("failed to fetch git repo")?;
+                let recorded = repo.revparse_single(&conf.rev).context("failed to parse recorded revision")?
+                    .peel_to_commit().context("recorded revision is not a commit")?;
+                let base = app.find_upstream(&repo, recorded.id(), &conf.upstream_path)?;
+
+                let (attributes_from, new) = match rev {
+                    Some(rev) => {
+                        let commit = repo.revparse_single(rev).with_context(|| format!("failed to parse revision {}", rev))?
+                            .peel_to_commit().context("revision is not a commit")?;
+                        let new = app.find_upstream(&repo, commit.id(), &conf.upstream_path)?.id();
+                        (commit, new)
+                    },
+                    None if !entry.path.exists() => {
+                        warn!("{} was deleted", conf.local_path.display());
+                        continue;
+                    },
+                    None => {
+                        let ours = app.write_local(&repo, &conf.upstream_path, &entry.path, Some(&base), &conf.options)
+                            .context("failed to read local content")?;
+                        (recorded, ours)
+                    },
+                };
+
+                let name = conf.local_path.file_name().unwrap_or_default().to_string_lossy();
+                let diff = app.diff_content(&repo, base.id(), new, &name)?;
+                let attributes = diff::Attributes::from_tree(&repo, &attributes_from.tree()?)?;
+                let is_file = base.kind() == Some(ObjectType::Blob);
+                let display = |path: &Path| if is_file {
+                    conf.local_path.clone()
+                } else {
+                    conf.local_path.join(remap::to_local(&conf.options.remap, path))
+                };
+                let differ = diff::Differ {
+                    repo: &repo,
+                    attributes: &attributes,
+                    upstream_path: if is_file { conf.upstream_path.parent().unwrap_or(Path::new("")) } else { &conf.upstream_path },
+                    skips: if is_file { &[] } else { &conf.options.skips },
+                    display: &display,
+                };
+                changes.extend(differ.changes(&diff)?);
+            }
+
+            let mut out = Vec::new();
+            if *stat {
+                diff::print_stat(&changes, &mut out)?;
+            } else {
+                diff::print_patch(&mut changes, &mut out)?;
+            }
+            let mut stdout = io::stdout();
+            if let Err(err) = stdout.write_all(&out).and_then(|()| stdout.flush()) {
+                if err.kind() != io::ErrorKind::BrokenPipe {
+                    return Err(err).context("failed to write to stdout");
+                }
+            }
+        },
+        Cmd::Stats { local_paths } => {
+            let superproject = open_superproject()?;
+            let journal = journal::operations(&superproject)?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs() as i64).unwrap_or(0);
+            let ago = |time: i64| format!("{} ago", age(now - time));
+
+            let entries = select_entries(&app, local_paths)?;
+            let (mut total_files, mut total_size) = (0, 0);
+            let mut rows = Vec::new();
+            for entry in &entries {
+                let _span = span::enter("entry", entry.conf.local_path.display());
+                let conf = &entry.conf;
+                let (files, size) = vendored_size(&entry.path)?;
+                total_files += files;
+                total_size += size;
+
+                let upstream = app.fetch_rev(&conf.url, Some(&conf.rev)).and_then(|repo| {
+                    let commit = repo.revparse_single(&conf.rev)?.peel_to_commit()?;
+                    let base = app.find_upstream(&repo, commit.id(), &conf.upstream_path)?;
+                    let diverged = if entry.path.exists() {
+                        let ours = app.write_local(&repo, &conf.upstream_path, &entry.path, Some(&base), &conf.options)?;
+                        let name = conf.local_path.file_name().unwrap_or_default().to_string_lossy();
+                        let stats = app.diff_content(&repo, base.id(), ours, &name)?.stats()?;
+                        format!("+{} -{}", stats.insertions(), stats.deletions())
+                    } else {
+                        "deleted".to_owned()
+                    };
+                    Ok((diverged, ago(commit.time().seconds())))
+                });
+                let (diverged, rev_age) = upstream.unwrap_or_else(|err| {
+                    warn!("failed to look at upstream of {}: {:#}", conf.local_path.display(), err);
+                    ("?".to_owned(), "?".to_owned())
+                });
+                let updated = last_updated(&superproject, &journal, &conf.local_path)?
+                    .map(ago)
+                    .unwrap_or_else(|| "never".to_owned());
+                rows.push((files, Size(size).to_string(), diverged, rev_age, updated, &conf.local_path));
+            }
+
+            println!("{} subcopies, {} files, {} vendored", entries.len(), total_files, Size(total_size));
+            println!();
+            println!("{:>6}  {:>10}  {:<13}  {:<16}  {:<16}  PATH", "FILES", "SIZE", "DIVERGED", "REVISION FROM", "LAST UPDATED");
+            for (files, size, diverged, rev_age, updated, path) in rows {
+                println!("{:>6}  {:>10}  {:<13}  {:<16}  {:<16}  {}", files, size, diverged, rev_age, updated, path.display());
+            }
+        },
+        Cmd::Report { local_paths, format } => {
+            let mut rows = Vec::new();
+            for entry in select_entries(&app, local_paths)? {
+                let _span = span::enter("entry", entry.conf.local_path.display());
+                let conf = &entry.conf;
+                let repo = app.fetch(&conf.url, true).context("failed to fetch git repo")?;
+                let recorded = repo.revparse_single(&conf.rev).context("failed to parse recorded revision")?
+                    .peel_to_commit().context("recorded revision is not a commit")?;
+                let short = |id: Oid| id.to_string()[..7].to_owned();
+                let plural = |count: usize, noun: &str| format!("{} {}{}", count, noun, if count == 1 { "" } else { "s" });
+
+                let drift = if entry.path.exists() {
+                    match app.local_changes(&repo, recorded.id(), &conf.upstream_path, &entry.path, &conf.options)?.len() {
+                        0 => "none".to_owned(),
+                        count => plural(count, "file"),
+                    }
+                } else {
+                    "deleted".to_owned()
+                };
+
+                let target = match &conf.floating {
+                    Some(floating) => floating.to_string(),
+                    None => conf.branch.as_deref().unwrap_or("HEAD").to_owned(),
+                };
+                let latest = match &conf.floating {
+                    Some(floating) => floating.resolve(&repo).and_then(|id| Ok(repo.find_commit(id)?)),
+                    None => repo.revparse_single(&target).and_then(|object| object.peel_to_commit()).map_err(Into::into),
+                };
+                let mut updates = match latest {
+                    Ok(latest) => match app.upstream_commits(&repo, recorded.id(), latest.id(), &conf.upstream_path)?.len() {
+                        0 => "up to date".to_owned(),
+                        count => match conf.branch.clone().or_else(|| conf.floating.as_ref().map(ToString::to_string)).or_else(|| app.default_branch(&repo)) {
+                            Some(branch) => format!("{} on `{}`, up to `{}`", plural(count, "commit"), branch, short(latest.id())),
+                            None => format!("{}, up to `{}`", plural(count, "commit"), short(latest.id())),
+                        },
+                    },
+                    Err(err) => {
+                        warn!("failed to find {} in upstream of {}: {:#}", target, conf.local_path.display(), err);
+                        "unknown".to_owned()
+                    },
+                };
+
+                if conf.frozen {
+                    match &conf.frozen_reason {
+                        Some(reason) => updates.push_str(&format!(" (held back: {})", reason)),
+                        None => updates.push_str(" (held back)"),
+                    }
+                }
+
+                let license = match license::find(&repo, &recorded.tree()?, &conf.upstream_path) {
+                    Some(license::License { spdx: Some(spdx), .. }) => spdx,
+                    Some(license) => format!("unknown, see `{}`", license.path.display()),
+                    None => "none found".to_owned(),
+                };
+
+                let mut rev = short(recorded.id());
+                if let Some(branch) = &conf.branch {
+                    rev.push_str(&format!(" ({})", branch));
+                }
+                let mut source = sources::Url::parse(&conf.url).redacted();
+                if !conf.upstream_path.as_os_str().is_empty() {
+                    source.push_str(&format!(":{}", conf.upstream_path.display()));
+                }
+                rows.push(report::Row {
+                    path: conf.local_path.display().to_string(),
+                    source,
+                    rev,
+                    drift,
+                    updates,
+                    license,
+                });
+            }
+            rows.sort_by(|a, b| a.path.cmp(&b.path));
+            print!("{}", report::render(*format, &rows));
+        },
+        Cmd::Provenance { local_paths } => {
+            for (i, entry) in select_entries(&app, local_paths)?.iter().enumerate() {
+                let conf = &entry.conf;
+                let repo = app.fetch_rev(&conf.url, Some(&conf.rev)).context("failed to fetch git repo")?;
+                let commit = repo.revparse_single(&conf.rev).context("failed to parse recorded revision")?
+                    .peel_to_commit().context("recorded revision is not a commit")?;
+                let license = license::find(&repo, &commit.tree()?, &conf.upstream_path);
+
+                if i > 0 {
+                    println!();
+                }
+                println!("{}", conf.local_path.display());
+                println!("Source: {}", sources::Url::parse(&conf.url).redacted());
+                if !conf.upstream_path.as_os_str().is_empty() {
+                    println!("Path: {}", conf.upstream_path.display());
+                }
+                println!("Revision: {}", commit.id());
+                match &license {
+                    Some(license) => {
+                        println!("License: {} ({})", license.spdx.as_deref().unwrap_or("unknown"), license.path.display());
+                        for line in &license.copyright {
+                            println!("{}", line);
+                        }
+                    },
+                    None => println!("License: none found"),
+                }
             }
         },
-        Opt::Shell { local_path } => {
+        Cmd::Lock { local_paths } => {
+            let superproject = open_superproject()?;
+            let subcopies: Vec<_> = select_entries(&app, local_paths)?.into_iter()
+                .map(|entry| (entry.conf.local_path, entry.conf.rev))
+                .collect();
+            app.lock(&superproject, &subcopies, true)?;
+        },
+        Cmd::VerifyLock { allow_ancestor } => {
+            let superproject = open_superproject()?;
+            let mut lock = Lock::open(&app.lock_path(&superproject)?)?;
+            let mut configs: Vec<_> = app.list()?.into_values().collect();
+            configs.sort_by(|a, b| a.local_path.cmp(&b.local_path));
+
+            let short = |rev: &str| rev[..rev.len().min(7)].to_owned();
+            let mut problems = Vec::new();
+            for conf in configs {
+                let key = conf.local_path.display();
+                let locked = match lock.entries.remove(&conf.local_path) {
+                    Some(locked) => locked.rev,
+                    None => {
+                        problems.push(format!("{}: not in the lockfile", key));
+                        continue;
+                    },
+                };
+                let (floating, url) = match (&conf.floating, &conf.url) {
+                    (Some(floating), Some(url)) => (floating, url),
+                    (Some(_), None) => {
+                        problems.push(format!("{}: missing its url", key));
+                        continue;
+                    },
+                    (None, _) => {
+                        if conf.rev.as_deref() != Some(&*locked) {
+                            let rev = conf.rev.as_deref().unwrap_or("<unknown>");
+                            problems.push(format!("{}: rev is {}, but the lockfile has {}", key, short(rev), short(&locked)));
+                        }
+                        continue;
+                    },
+                };
+                let matches = app.fetch(url, true).and_then(|repo| {
+                    let resolved = floating.resolve(&repo)?;
+                    let pinned = Oid::from_str(&locked).context("lockfile has an invalid revision")?;
+                    let ok = resolved == pinned || (*allow_ancestor && repo.graph_descendant_of(resolved, pinned)?);
+                    Ok((ok, resolved))
+                });
+                match matches {
+                    Ok((true, _)) => (),
+                    Ok((false, resolved)) => problems.push(format!(
+                        "{}: {} resolves to {}, but the lockfile has {}",
+                        key, floating, short(&resolved.to_string()), short(&locked),
+                    )),
+                    Err(err) => problems.push(format!("{}: {:#}", key, err)),
+                }
+            }
+            for path in lock.entries.keys() {
+                problems.push(format!("{}: in the lockfile, but not in .gitcopies", path.display()));
+            }
+
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            ensure!(problems.is_empty(), "{} problem(s) with the lockfile", problems.len());
+        },
+        Cmd::Verify { local_paths } => {
+            let superproject = open_superproject()?;
+            let mut changed = 0;
+            for entry in select_entries(&app, local_paths)? {
+                let _span = span::enter("entry", entry.conf.local_path.display());
+                let key = &entry.conf.local_path;
+                let locked = match app.locked(&superproject, key)? {
+                    Some(locked) => locked,
+                    None => {
+                        println!("?? {} (not in lockfile)", key.display());
+                        changed += 1;
+                        continue;
+                    },
+                };
+                for (file, change) in lock::compare(&locked, &entry.path)? {
+                    let status = match change {
+                        Change::Modified => " M",
+                        Change::Deleted => " D",
+                        Change::Added => "??",
+                    };
+                    // Single files are recorded with an empty path
+                    let path = if file.as_os_str().is_empty() { key.clone() } else { key.join(file) };
+                    println!("{} {}", status, path.display());
+                    changed += 1;
+                }
+            }
+            ensure!(changed == 0, "{} file(s) differ from the lockfile", changed);
+        },
+        Cmd::Rollback { local_path } => {
+            app.journal(&describe("rollback", &[local_path]), &[local_path])?;
+            let snapshot = app.rollback(local_path)?;
+            info!("Rolled {} back to {}", local_path.display(), &snapshot.rev[..7]);
+        },
+        Cmd::Undo => match app.undo()? {
+            Some(operation) => info!("Undid {}", operation.name),
+            None => bail!("there's nothing to undo"),
+        },
+        Cmd::Doctor => {
+            let workdir = app.workdir()?;
+            let mut problems = Vec::new();
+
+            let mut configs: Vec<_> = app.list()?.into_values().collect();
+            configs.sort_by(|a, b| a.local_path.cmp(&b.local_path));
+            for conf in &configs {
+                let path = conf.local_path.display();
+                if conf.url.is_none() || conf.rev.is_none() || conf.upstream_path.is_none() {
+                    problems.push(format!("{} is missing its url, rev, or upstreamPath", path));
+                }
+                if !workdir.join(&conf.local_path).exists() {
+                    problems.push(format!("{} doesn't exist", path));
+                }
+                if conf.overridden {
+                    // Fine while hacking, but CI should build what's committed
+                    let message = format!("{} is overridden in .gitcopies.local", path);
+                    if env::var_os("CI").is_some() {
+                        problems.push(message);
+                    } else {
+                        warn!("{}", message);
+                    }
+                }
+            }
+            // Not a problem, but updating them one by one is easy to forget
+            let mut identical: Vec<(_, Vec<_>)> = Vec::new();
+            for conf in &configs {
+                if let (Some(url), Some(rev), Some(upstream_path)) = (&conf.url, &conf.rev, &conf.upstream_path) {
+                    let source = (sources::normalize(url), rev, upstream_path);
+                    match identical.iter_mut().find(|(other, _)| *other == source) {
+                        Some((_, paths)) => paths.push(conf.local_path.display()),
+                        None => identical.push((source, vec![conf.local_path.display()])),
+                    }
+                }
+            }
+            for ((url, rev, upstream_path), paths) in identical.iter().filter(|(_, paths)| paths.len() > 1) {
+                let paths: Vec<_> = paths.iter().map(ToString::to_string).collect();
+                warn!("{} all vendor {}@{}:{}", paths.join(", "), url, rev, upstream_path.display());
+            }
+            for (outer, inner) in app.overlaps()? {
+                problems.push(format!("{} is inside of {}, updating them could overwrite each other", inner.display(), outer.display()));
+            }
+
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            ensure!(problems.is_empty(), "found {} problem(s)", problems.len());
+            banner(&["No problems found"]);
+        },
+        Cmd::Export { local_paths, format, output, status } => {
+            let superproject = open_superproject()?;
+            let workdir = app.workdir()?;
+            let mut lock = Lock::open(&app.lock_path(&superproject)?)?;
+            let wanted = local_paths.iter()
+                .map(|path| app.get(path).map(|conf| conf.local_path))
+                .collect::<Result<HashSet<_>>>()?;
+            let mut configs: Vec<_> = app.list()?.into_values()
+                .filter(|conf| wanted.is_empty() || wanted.contains(&conf.local_path))
+                .collect();
+            configs.sort_by(|a, b| a.local_path.cmp(&b.local_path));
+
+            let path_string = |path: &Path| path.to_string_lossy().into_owned();
+            let mut subcopies = Vec::new();
+            for conf in configs {
+                let options = &conf.options;
+                let mut fields = vec![
+                    ("path".to_owned(), Value::string(path_string(&conf.local_path))),
+                    ("url".to_owned(), Value::optional(conf.url.clone())),
+                    ("rev".to_owned(), Value::optional(conf.rev.clone())),
+                    ("floating".to_owned(), Value::optional(conf.floating.as_ref().map(ToString::to_string))),
+                    ("upstreamPath".to_owned(), Value::optional(conf.upstream_path.as_deref().map(path_string))),
+                    ("branch".to_owned(), Value::optional(conf.branch.clone())),
+                    ("testCommand".to_owned(), Value::optional(conf.test_command.clone())),
+                    ("groups".to_owned(), Value::strings(conf.groups.clone())),
+                    ("frozen".to_owned(), Value::Bool(conf.frozen)),
+                    ("frozenReason".to_owned(), Value::optional(conf.frozen_reason.clone())),
+                    ("description".to_owned(), Value::optional(conf.description.clone())),
+                    ("addedBy".to_owned(), Value::optional(conf.added_by.clone())),
+                    ("addedAt".to_owned(), Value::optional(conf.added_at.clone())),
+                    ("lastUpdatedAt".to_owned(), Value::optional(conf.last_updated_at.clone())),
+                    ("overridden".to_owned(), Value::Bool(conf.overridden)),
+                    ("remap".to_owned(), Value::strings(options.remap.iter().map(ToString::to_string))),
+                    ("filter".to_owned(), Value::strings(options.filters.iter().map(ToString::to_string))),
+                    ("skip".to_owned(), Value::strings(options.skips.iter().map(ToString::to_string))),
+                    ("emptyDir".to_owned(), Value::strings(options.empty_dirs.iter().map(|dir| path_string(dir)))),
+                    ("allowDotGit".to_owned(), Value::Bool(options.allow_dot_git)),
+                    ("frozenFileSet".to_owned(), Value::Bool(options.frozen_file_set)),
+                ];
+                if *status {
+                    let (status, changes) = subcopy_status(&app, &conf, &workdir.join(&conf.local_path));
+                    fields.push(("status".to_owned(), Value::string(status)));
+                    fields.push(("changedFiles".to_owned(), Value::Number(changes as u64)));
+                }
+                let locked = lock.entries.remove(&conf.local_path).map_or(Value::Null, |locked| {
+                    let files = locked.files.iter()
+                        .map(|(file, id)| (path_string(file), Value::string(id.to_string())))
+                        .collect();
+                    Value::Table(vec![
+                        ("rev".to_owned(), Value::string(locked.rev)),
+                        ("files".to_owned(), Value::Table(files)),
+                    ])
+                });
+                fields.push(("lock".to_owned(), locked));
+                subcopies.push(Value::Table(fields));
+            }
+
+            let document = export::render(*format, &[("subcopies".to_owned(), Value::List(subcopies))]);
+            match output {
+                Some(output) => fs::write(output, document).with_context(|| format!("failed to write {}", output.display()))?,
+                None => print!("{}", document),
+            }
+        },
+        Cmd::Status { local_paths, porcelain, group } => {
+            // Exit codes 1 and 2 mean something here, so failing has to
+            // look different from the usual 1
+            let found = (|| -> Result<_> {
+                let configs = app.list()?;
+                let workdir = app.workdir()?;
+                let current_dir = env::current_dir().context("failed to get current directory")?;
+                let wanted = local_paths.iter()
+                    .map(|path| app.get(path).map(|conf| conf.local_path))
+                    .collect::<Result<HashSet<_>>>()?;
+                let mut configs: Vec<_> = configs.into_values()
+                    .filter(|conf| wanted.is_empty() || wanted.contains(&conf.local_path))
+                    .filter(|conf| group.as_ref().is_none_or(|group| conf.groups.contains(group)))
+                    .collect();
+                configs.sort_by(|a, b| a.local_path.cmp(&b.local_path));
+                Ok((configs, workdir, current_dir))
+            })();
+            let (configs, workdir, current_dir) = match found {
+                Ok(found) => found,
+                Err(err) => {
+                    error!("{:#}", err);
+                    process::exit(3);
+                },
+            };
+
+            let mut code = 0;
+            let mut statuses = Vec::new();
+            for conf in configs {
+                let key = conf.local_path.clone();
+                let path = workdir.join(&key);
+                let path = path.strip_prefix(&current_dir).map(Path::to_path_buf).unwrap_or(path);
+                let (status, changes) = subcopy_status(&app, &conf, &path);
+                code = code.max(match status {
+                    "invalid" => 3,
+                    "missing" => 2,
+                    "modified" => 1,
+                    _ => 0,
+                });
+                statuses.push((status, key, changes));
+            }
+
+            if let Some(porcelain::Version::V1) = porcelain.map(Option::unwrap_or_default) {
+                for (status, key, changes) in &statuses {
+                    println!("{}", porcelain::record(&[status, &key.to_string_lossy(), &changes.to_string()]));
+                }
+            } else {
+                println!("{:<8}  {:>5}  PATH", "STATUS", "FILES");
+                for (status, key, changes) in &statuses {
+                    println!("{:<8}  {:>5}  {}", status, changes, key.display());
+                }
+            }
+            if code != 0 {
+                process::exit(code);
+            }
+        },
+        Cmd::Edit => {
+            let superproject = open_superproject()?;
+            let manifest = app.manifest_path(&superproject)?;
+            let original = match fs::read(&manifest) {
+                Ok(content) => content,
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(err) => return Err(err).with_context(|| format!("failed to read {}", manifest.display())),
+            };
+
+            // Keep the file name, so editors recognize the format
+            let tmp = tempfile::Builder::new().prefix("git-subcopy").tempdir().context("failed to get temporary directory")?;
+            let edited = tmp.path().join(manifest.file_name().unwrap_or_else(|| ".gitcopies".as_ref()));
+            fs::write(&edited, &original).context("failed to write temporary manifest")?;
+
+            let editor = editor(&superproject);
+            loop {
+                let mut script = editor.clone();
+                script.push(" \"$@\"");
+                let status = interrupt::foreground(|| Command::new("sh").arg("-c").arg(script).arg(&editor).arg(&edited).status())
+                    .with_context(|| format!("failed to run editor {}", editor.to_string_lossy()))?;
+                ensure!(status.success(), "editor exited with {}, {} left unchanged", status, manifest.display());
+
+                let problems = app.validate_manifest(&edited)?;
+                if problems.is_empty() {
+                    break;
+                }
+                for problem in &problems {
+                    error!("{}", problem);
+                }
+                if !io::stdin().is_terminal() || !confirm("Edit again?")? {
+                    bail!("{} left unchanged", manifest.display());
+                }
+            }
+
+            let content = fs::read(&edited).context("failed to read edited manifest")?;
+            if content == original {
+                info!("No changes made");
+            } else {
+                fs::write(&manifest, content).with_context(|| format!("failed to write {}", manifest.display()))?;
+            }
+        },
+        Cmd::Update { local_paths, all, prefix, group, rev, locked, commit, branch_per_entry, summary_file, json, jobs, worktree } => {
+            ensure!(*all || !local_paths.is_empty(), "specify the subcopies to update, or --all");
+            ensure!(*jobs > 0, "--jobs must be at least 1");
+
+            let mut entries = select_entries(&app, local_paths)?;
+            if let Some(prefix) = prefix {
+                entries.retain(|entry| entry.conf.local_path.starts_with(prefix));
+            }
+            if let Some(group) = group {
+                entries.retain(|entry| entry.conf.groups.contains(group));
+            }
+            if *all {
+                entries.retain(|entry| {
+                    if entry.conf.frozen {
+                        info!("Skipping frozen {}", entry.conf.local_path.display());
+                    }
+                    !entry.conf.frozen
+                });
+            }
+
+            let superproject = open_superproject()?;
+            let paths: Vec<&Path> = entries.iter().map(|entry| &*entry.conf.local_path).collect();
+            let autostash = worktree.prepare(&app, &superproject, &paths)?;
+            let full_paths: Vec<&Path> = entries.iter().map(|entry| &*entry.path).collect();
+            app.journal(&describe("update", &paths), &full_paths)?;
+            let mut manifests = Vec::new();
+            for entry in &entries {
+                let (manifest, _) = app.manifest_for(&superproject, &entry.conf.local_path)?;
+                manifests.push(app.canonicalize(&superproject, &manifest)?);
+            }
+            if *commit {
+                for (entry, manifest) in entries.iter().zip(&manifests) {
+                    ensure!(
+                        !app.is_dirty(&superproject, &[&entry.conf.local_path, manifest])?,
+                        "{} or {} has uncommitted changes, commit or stash them before using --commit",
+                        entry.conf.local_path.display(),
+                        manifest.display(),
+                    );
+                }
+            }
+
+            // Fetching is what's slow and it only touches the cache, so
+            // it can happen in parallel. Failures, like upstreams needing
+            // a password, are reported once that subcopy's update tries
+            // again.
+            if *jobs > 1 && rev.is_none() {
+                let urls: Vec<&str> = entries.iter().map(|entry| &*entry.conf.url).collect();
+                for (url, err) in app.fetch_all(&urls, *jobs) {
+                    debug!("failed to fetch {} in parallel: {:#}", url, err);
+                }
+            }
+
+            let mut results = Vec::new();
+            let mut summaries = Vec::new();
+            for (entry, manifest) in entries.iter().zip(&manifests) {
+                let _span = span::enter("entry", entry.conf.local_path.display());
+                info!("Updating {}...", entry.conf.local_path.display());
+
+                // Local changes have to be looked at before they're merged
+                let conf = &entry.conf;
+                let local_changes = app.fetch_rev(&conf.url, Some(&conf.rev))
+                    .and_then(|repo| {
+                        let old = repo.revparse_single(&conf.rev)?.id();
+                        app.local_changes(&repo, old, &conf.upstream_path, &entry.path, &conf.options)
+                    })
+                    .unwrap_or_else(|err| {
+                        warn!("failed to find local changes of {}: {:#}", conf.local_path.display(), err);
+                        Vec::new()
+                    });
+
+                // Updating to the pinned commit is what keeps it there
+                let rev = match &conf.floating {
+                    Some(_) if *locked => Some(&*conf.rev),
+                    _ => rev.as_deref(),
+                };
+                let result = if *commit {
+                    update_and_commit(&app, &superproject, entry, rev, manifest, *branch_per_entry)
+                } else {
+                    update(&app, entry, rev)
+                };
+                match &result {
+                    Err(err) => error!("failed to update {}: {:#}", entry.conf.local_path.display(), err),
+                    Ok((_, _, Outcome::Unchanged | Outcome::Blocked)) => (),
+                    Ok((old, new, outcome)) => {
+                        let conflicts = match outcome {
+                            Outcome::Conflicted(paths) => Some(&paths[..]),
+                            _ => None,
+                        };
+                        summaries.push(summarize(&app, conf, *old, *new, &local_changes, conflicts)?);
+                    },
+                }
+                results.push((entry, result));
+            }
+
+            if *json {
+                let mut failed = 0;
+                for (entry, result) in &results {
+                    if !matches!(result, Ok((_, _, Outcome::Unchanged | Outcome::Updated))) {
+                        failed += 1;
+                    }
+                    println!("{}", update_json(&app, entry, result));
+                }
+                autostash.finish()?;
+                if let Some(path) = summary_file {
+                    fs::write(path, summaries.join("\n")).with_context(|| format!("failed to write {}", path.display()))?;
+                }
+                ensure!(failed == 0, "{} of {} subcopies could not be updated", failed, results.len());
+                return Ok(());
+            }
+
+            let short = |id: &Oid| id.to_string()[..7].to_owned();
+            let mut failed = 0;
+            println!("{:<10}  {:<7}  {:<7}  PATH", "STATUS", "OLD", "NEW");
+            for (entry, result) in &results {
+                let (status, old, new) = match result {
+                    Ok((old, _, Outcome::Unchanged)) => ("unchanged", short(old), short(old)),
+                    Ok((old, new, Outcome::Updated)) => ("updated", short(old), short(new)),
+                    Ok((old, new, Outcome::Conflicted(_))) => ("conflicted", short(old), short(new)),
+                    Ok((old, new, Outcome::Blocked)) => ("blocked", short(old), short(new)),
+                    Err(_) => ("failed", "-".to_owned(), "-".to_owned()),
+                };
+                if status != "unchanged" && status != "updated" {
+                    failed += 1;
+                }
+                println!("{:<10}  {:<7}  {:<7}  {}", status, old, new, entry.conf.local_path.display());
+            }
+            for (entry, result) in &results {
+                if let Ok((_, new, Outcome::Conflicted(paths))) = result {
+                    println!();
+                    println!("Conflicts in {}:", entry.conf.local_path.display());
+                    for path in paths {
+                        println!("    {}", path.display());
+                    }
+                    println!("Resolve them with `git subcopy rebase {} {}`", entry.path.display(), new);
+                }
+            }
+            autostash.finish()?;
+            if !summaries.is_empty() {
+                let summary = summaries.join("\n");
+                banner(&["", summary.trim_end()]);
+                if let Some(path) = summary_file {
+                    fs::write(path, &summary).with_context(|| format!("failed to write {}", path.display()))?;
+                }
+            }
+            ensure!(failed == 0, "{} of {} subcopies could not be updated", failed, results.len());
+        },
+        Cmd::Mirror { local_paths, all, to, rewrite } => {
+            ensure!(*all || !local_paths.is_empty(), "specify the subcopies to mirror, or --all");
+
+            // Entries from the same upstream share a push
+            let mut pushed: HashMap<String, bool> = HashMap::new();
+            let mut failed = 0;
+            for entry in select_entries(&app, local_paths)? {
+                let _span = span::enter("entry", entry.conf.local_path.display());
+                let conf = &entry.conf;
+                let url = app.resolve_url(&conf.url)?;
+                let mirror = sources::mirror_url(to, &url);
+                let ok = match pushed.get(&sources::normalize(&url)) {
+                    Some(&ok) => ok,
+                    None => {
+                        info!("Pushing {} to {}...", sources::Url::parse(&url).redacted(), sources::Url::parse(&mirror).redacted());
+                        let ok = match app.mirror(&conf.url, &mirror) {
+                            Ok(()) => true,
+                            Err(err) => {
+                                error!("failed to mirror {}: {:#}", conf.local_path.display(), err);
+                                false
+                            },
+                        };
+                        pushed.insert(sources::normalize(&url), ok);
+                        ok
+                    },
+                };
+                if !ok {
+                    failed += 1;
+                    continue;
+                }
+                if *rewrite {
+                    let rev = Oid::from_str(&conf.rev).with_context(|| format!("recorded revision of {} isn't a commit id", conf.local_path.display()))?;
+                    app.register(&mirror, rev, &conf.upstream_path, &entry.path)?;
+                }
+                println!("{}  {}", conf.local_path.display(), sources::Url::parse(&mirror).redacted());
+            }
+            ensure!(failed == 0, "{} subcopies could not be mirrored", failed);
+        },
+        Cmd::Cache(CacheCmd::ExportBundle { source, output }) => {
+            app.export_bundle(&source_url(&app, source), output)?;
+        },
+        Cmd::Cache(CacheCmd::ImportBundle { bundle, source }) => {
+            app.import_bundle(&source_url(&app, source), bundle)?;
+        },
+        Cmd::Import { from, file, force } => {
+            let (imported, base) = match from.default_file() {
+                None => {
+                    ensure!(file.is_none(), "git-vendor is read from the history of this repository, not a file");
+                    let superproject = open_superproject()?;
+                    let mut walk = superproject.revwalk().context("failed to walk history")?;
+                    walk.push_head()?;
+                    let mut messages = Vec::new();
+                    for id in walk {
+                        messages.push(superproject.find_commit(id?)?.message().unwrap_or("").to_owned());
+                    }
+                    let workdir = app.workdir()?;
+                    let current_dir = env::current_dir().context("failed to get current directory")?;
+                    let base = workdir.strip_prefix(&current_dir).map(Path::to_path_buf).unwrap_or(workdir);
+                    (import::git_vendor(messages.iter().map(String::as_str)), base)
+                },
+                Some(default) => {
+                    let file = file.as_deref().unwrap_or(default);
+                    let content = fs::read_to_string(file).with_context(|| format!("failed to read {}", file.display()))?;
+                    let imported = match from {
+                        import::Tool::Vendir => import::vendir(&content)?,
+                        _ => import::copybara(&content)?,
+                    };
+                    (imported, file.parent().unwrap_or_else(|| Path::new("")).to_path_buf())
+                },
+            };
+            ensure!(!imported.is_empty(), "found nothing to import");
+
+            let local_paths: Vec<PathBuf> = imported.iter().map(|entry| base.join(&entry.local_path)).collect();
+            for local_path in &local_paths {
+                app.check_destination(local_path)?;
+                ensure!(app.get(local_path).is_err(), "{} is already a subcopy", local_path.display());
+                ensure!(fs::symlink_metadata(local_path).is_err() || *force, "{} already exists, use --force to overwrite it", local_path.display());
+            }
+            let paths: Vec<&Path> = local_paths.iter().map(PathBuf::as_path).collect();
+            app.journal(&describe("import", &paths), &paths)?;
+            for (entry, local_path) in imported.iter().zip(&local_paths) {
+                let url = app.relative_source(&sources::expand(&entry.url))?;
+                let repo = app.fetch_rev(&url, entry.rev.as_deref()).context("failed to fetch git repo")?;
+                let target = entry.rev.as_deref().unwrap_or("HEAD");
+                let rev = repo.revparse_single(target).with_context(|| format!("failed to parse revision {}", target))?
+                    .peel_to_commit().context("revision is not a commit")?
+                    .id();
+                let options = CopyOptions {
+                    skips: entry.skips.clone(),
+                    ..CopyOptions::default()
+                };
+                app.extract(&repo, rev, &entry.upstream_path, local_path, &options).context("failed to extract files")?;
+                app.register(&url, rev, &entry.upstream_path, local_path).context("failed to register to .gitcopies")?;
+                app.set_options(local_path, &options).context("failed to register to .gitcopies")?;
+                println!("Imported {} from {}:{} at {}", local_path.display(), url, entry.upstream_path.display(), &rev.to_string()[..7]);
+            }
+        },
+        Cmd::Adopt { local_path, url, upstream_path, hint } => {
+            ensure!(fs::symlink_metadata(local_path).is_ok(), "{} doesn't exist", local_path.display());
+            ensure!(app.get(local_path).is_err(), "{} is already a subcopy", local_path.display());
+            let upstream_path = upstream_path.as_deref().unwrap_or_else(|| Path::new(""));
+            let url = app.relative_source(&sources::expand(url))?;
+            let repo = app.fetch(&url, true).context("failed to fetch git repo")?;
+
+            let ranked = match hint {
+                Some(hint) => {
+                    let mut walk = repo.revwalk().context("failed to walk history")?;
+                    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME);
+                    if hint.contains("..") {
+                        walk.push_range(hint).context("failed to parse revision range")?;
+                    } else {
+                        walk.push(repo.revparse_single(hint).context("failed to parse revision")?.peel_to_commit()?.id())?;
+                    }
+                    let candidates = walk.collect::<Result<Vec<Oid>, _>>()?;
+                    app.rank_revs(&repo, &candidates, upstream_path, local_path)?
+                },
+                None => app.detect_rev(&repo, upstream_path, local_path)?,
+            };
+
+            let files = lock::hash_files(local_path)?.len();
+            let (rev, matched) = ranked
+                .into_iter()
+                .next()
+                .filter(|&(_, matched)| matched > 0)
+                .ok_or_else(|| anyhow!("no upstream commit has any of the files in {}", local_path.display()))?;
+            app.journal(&describe("adopt", &[local_path]), &[local_path])?;
+            app.register(&url, rev, upstream_path, local_path).context("failed to register to .gitcopies")?;
+
+            let commit = repo.find_commit(rev)?;
+            println!("Adopted {} at {} {}", local_path.display(), rev, commit.summary().unwrap_or(""));
+            println!("{} of {} files match upstream", matched, files);
+            let changes = app.local_changes(&repo, rev, upstream_path, local_path, &CopyOptions::default())?;
+            if !changes.is_empty() {
+                println!("Kept as local changes:");
+                for path in changes {
+                    println!("    {}", path.display());
+                }
+            }
+        },
+        Cmd::Config { local_path, name, value, add, unset } => {
+            let conf = app.get(local_path)?;
+            let is_source = name.eq_ignore_ascii_case("url") || name.eq_ignore_ascii_case("rev");
+            match value {
+                // Where a subcopy is from is recorded along with the
+                // lockfile and audit fields, the way any new source is
+                Some(value) if is_source => {
+                    ensure!(!*add, "{} can only have one value", name);
+                    // Saved the same way `add` saves it, with shorthands
+                    // expanded and relative paths from the root
+                    let (url, rev) = if name.eq_ignore_ascii_case("url") {
+                        (app.relative_source(&sources::expand(value))?, &*conf.rev)
+                    } else {
+                        (conf.url.clone(), &**value)
+                    };
+                    let url = &*url;
+                    let repo = app.fetch_rev(url, Some(rev)).context("failed to fetch git repo")?;
+                    if floating::is_floating(rev) {
+                        let floating = rev.parse::<Floating>()?;
+                        let id = floating.resolve(&repo)?;
+                        app.register(url, id, &conf.upstream_path, local_path)?;
+                        app.set_floating(local_path, &floating, id)?;
+                    } else {
+                        let id = repo.revparse_single(rev).and_then(|object| object.peel_to_commit())
+                            .with_context(|| format!("failed to find revision {}", rev))?.id();
+                        if conf.floating.is_some() && name.eq_ignore_ascii_case("rev") {
+                            // No longer following anything
+                            app.set_property(local_path, "rev", Some(&id.to_string()))?;
+                        }
+                        app.register(url, id, &conf.upstream_path, local_path)?;
+                    }
+                },
+                Some(value) if *add => app.add_property(local_path, name, value)?,
+                Some(value) => app.set_property(local_path, name, Some(value))?,
+                None if *unset => {
+                    ensure!(!is_source, "a subcopy can't be without a {}", name);
+                    app.set_property(local_path, name, None)?;
+                },
+                None => {
+                    let values = app.property(local_path, name)?;
+                    if values.is_empty() {
+                        process::exit(1);
+                    }
+                    for value in values {
+                        println!("{}", value);
+                    }
+                },
+            }
+        },
+        Cmd::Freeze { local_path, reason } => {
+            app.get(local_path)?;
+            app.set_property(local_path, "frozen", Some("true"))?;
+            app.set_property(local_path, "frozenReason", reason.as_deref())?;
+        },
+        Cmd::Unfreeze { local_path } => {
+            app.get(local_path)?;
+            app.set_property(local_path, "frozen", None)?;
+            app.set_property(local_path, "frozenReason", None)?;
+        },
+        Cmd::Shell { local_path, command } => {
             let conf = app.get(local_path)?;
             let shell = env::var_os("SHELL").unwrap_or_else(|| OsString::from("/bin/sh"));
+            let vars = subcopy_env(&app, &conf)?;
+
+            app.with_repo(&conf.url, &conf.rev, &conf.upstream_path, local_path, &conf.options, |repo| {
+                let workdir = repo.workdir().expect("created repo shouldn't be a bare repo");
+                if let [program, args @ ..] = &command[..] {
+                    let status = interrupt::foreground(|| temp_repo_command(program, workdir, vars).args(args).status())
+                        .with_context(|| format!("failed to run {}", program.to_string_lossy()))?;
+                    if let Err(err) = save_session_patch(&conf, repo) {
+                        warn!("failed to save the changes of this session: {:#}", err);
+                    }
+                    ensure_finished(repo, status)?;
+                    return Ok(());
+                }
 
-            app.with_repo(&conf.url, &conf.rev, &conf.upstream_path, local_path, |repo| {
-                println!("You are now in a shell inside of a temporary git repository.");
-                println!("The upstream code is commited, and your changes in the worktree.");
-                println!("When you exit this shell, your changed files will be copied back.");
-                println!("=================================================================");
-                Command::new(shell)
-                    .current_dir(repo.workdir().expect("created repo shouldn't be a bare repo"))
-                    .status()?;
+                banner(&[
+                    "You are now in a shell inside of a temporary git repository.",
+                    "The upstream code is commited, and your changes in the worktree.",
+                    "When you exit this shell, your changed files will be copied back.",
+                    "Exit with an error, like `exit 1`, to leave them out.",
+                    "=================================================================",
+                ]);
+                let status = interrupt::foreground(|| temp_repo_command(shell, workdir, vars).status())?;
+                if let Err(err) = save_session_patch(&conf, repo) {
+                    warn!("failed to save the changes of this session: {:#}", err);
+                }
+                ensure_finished(repo, status)?;
                 Ok(())
             })?;
         },
-        Opt::Rebase { local_path, rev } => {
+        Cmd::Rebase { local_path, rev, onto, base, interactive, summary_file, worktree } => {
             let conf = app.get(local_path)?;
+            let superproject = open_superproject()?;
+            let autostash = worktree.prepare(&app, &superproject, &[&conf.local_path])?;
             let shell = env::var_os("SHELL").unwrap_or_else(|| OsString::from("/bin/sh"));
+            let vars = subcopy_env(&app, &conf)?;
+            let rev = rev.as_deref().or(onto.as_deref()).expect("structopt requires rev or --onto");
+            let base = base.as_deref().unwrap_or(&conf.rev);
 
-            let rev = app.with_repo(&conf.url, &conf.rev, &conf.upstream_path, local_path, |repo| {
-                repo.find_remote("upstream").expect("remote 'upstream' should be set at this point")
-                    .fetch(&[], None, None)?;
+            let cache = app.fetch_rev(&conf.url, Some(base)).context("failed to fetch git repo")?;
+            let old = cache.revparse_single(base).context("failed to parse base revision")?.id();
+            app.snapshot(&superproject, &conf, local_path)?;
+            app.journal(&describe("rebase", &[&conf.local_path]), &[local_path])?;
 
-                let onto_rev = repo.revparse_single(&rev).context("failed to parse specified upstream revision")?;
+            let result = app.with_repo(&conf.url, base, &conf.upstream_path, local_path, &conf.options, |repo| {
+                if !app.is_cached(repo, rev) {
+                    // Go through the cache, which knows how to expand
+                    // any variables in the url
+                    app.fetch_rev(&conf.url, Some(rev)).context("failed to fetch git repo")?;
+                    repo.find_remote("origin").expect("repository cloned from the cache should have an origin")
+                        .fetch(&[], None, None)?;
+                }
+
+                let onto_rev = repo.revparse_single(rev).context("failed to parse specified upstream revision")?;
                 let onto_commit = repo.find_annotated_commit(onto_rev.id()).context("failed to find commit for revision")?;
 
                 let head = repo.head().context("failed to find head")?
                     .peel_to_commit().context("head wasn't a commit")?;
+                let workdir = repo.workdir().expect("created repo shouldn't be a bare repo");
+
+                if *interactive {
+                    let sign = Signature::now("git-subcopy", "there's nobody to blame this time").context("failed to create signature")?;
+                    let base = head.id();
+                    commit_per_file(repo, head, &sign)?;
+
+                    // libgit2 can't rebase interactively, so leave it to git
+                    info!("Rebasing...");
+                    let mut rebase = temp_repo_command("git", workdir, vars.clone());
+                    rebase.args(["rebase", "-i", "--onto", &onto_rev.id().to_string(), &base.to_string()]);
+                    let status = interrupt::foreground(|| rebase.status()).context("failed to run git rebase")?;
+                    if status.success() {
+                        return Ok(onto_rev.id());
+                    }
+
+                    banner(&[
+                        "The rebase stopped. You're dropped into a shell to finish it.",
+                        "Run `git status` to see rebase progress, and",
+                        "`git rebase --continue` to continue the rebase.",
+                        "==============================================================",
+                    ]);
+                    let status = interrupt::foreground(|| temp_repo_command(shell, workdir, vars).status())?;
+                    ensure_finished(repo, status)?;
+                    return Ok(onto_rev.id());
+                }
 
                 let tree_id = {
                     let mut index = repo.index().context("failed to open index")?;
@@ -147,17 +2790,30 @@ fn main() -> Result<()> {
                         .inmemory(false)
                 ))?;
 
-                println!("A rebase is started. You're dropped into a shell to finish it.");
-                println!("Run `git status` to see rebase progress, and");
-                println!("`git rebase --continue` to continue the rebase.");
-                println!("==============================================================");
-                Command::new(shell)
-                    .current_dir(repo.workdir().expect("created repo shouldn't be a bare repo"))
-                    .status()?;
+                banner(&[
+                    "A rebase is started. You're dropped into a shell to finish it.",
+                    "Run `git status` to see rebase progress, and",
+                    "`git rebase --continue` to continue the rebase.",
+                    "==============================================================",
+                ]);
+                let status = interrupt::foreground(|| temp_repo_command(shell, workdir, vars).status())?;
+                ensure_finished(repo, status)?;
                 Ok(onto_rev.id())
-            })?;
+            });
+            let result = result.and_then(|rev| {
+                app.register(&conf.url, rev, &conf.upstream_path, local_path).context("failed to register new rev")?;
+                Ok(rev)
+            });
+            autostash.finish()?;
+            let rev = result?;
 
-            app.register(&conf.url, rev, &conf.upstream_path, &local_path).context("failed to register new rev")?;
+            let cache = app.fetch_rev(&conf.url, Some(&rev.to_string())).context("failed to fetch git repo")?;
+            let local_changes = app.local_changes(&cache, rev, &conf.upstream_path, local_path, &conf.options)?;
+            let summary = summarize(&app, &conf, old, rev, &local_changes, None)?;
+            banner(&["", summary.trim_end()]);
+            if let Some(path) = summary_file {
+                fs::write(path, &summary).with_context(|| format!("failed to write {}", path.display()))?;
+            }
         }
     }
     Ok(())
@@ -0,0 +1,163 @@
+//! A small line-based browser for picking paths out of an upstream
+//! tree, used by `add --interactive`. Deliberately doesn't need any
+//! terminal magic, so it works over any kind of stdin.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use git2::ObjectType;
+use git_subcopy::UpstreamEntry;
+
+const HELP: &str = "\
+Commands:
+  <n>       select or unselect entry n
+  o <n>     expand or collapse directory n
+  /<text>   only show paths fuzzy matching text, or all paths if empty
+  d         done, extract the selected paths
+  q         quit without extracting anything";
+
+struct Node {
+    path: PathBuf,
+    is_dir: bool,
+}
+
+pub struct Picker {
+    nodes: Vec<Node>,
+    expanded: HashSet<PathBuf>,
+    selected: BTreeSet<PathBuf>,
+    filter: Option<String>,
+}
+
+/// Whether all characters of `query` appear in order in `text`,
+/// ignoring case
+fn fuzzy_match(query: &str, text: &str) -> bool {
+    let mut text = text.chars().flat_map(char::to_lowercase);
+    query.chars()
+        .flat_map(char::to_lowercase)
+        .all(|q| text.any(|t| t == q))
+}
+
+impl Picker {
+    pub fn new(entries: Vec<UpstreamEntry>) -> Self {
+        Self {
+            nodes: entries.into_iter()
+                .map(|entry| Node {
+                    is_dir: entry.kind == Some(ObjectType::Tree),
+                    path: entry.path,
+                })
+                .collect(),
+            expanded: HashSet::new(),
+            selected: BTreeSet::new(),
+            filter: None,
+        }
+    }
+
+    /// Returns the selected ancestor (or self) of a path, if any
+    fn selected_ancestor<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        path.ancestors().find(|ancestor| self.selected.contains(*ancestor))
+    }
+
+    fn visible(&self) -> Vec<&Node> {
+        self.nodes.iter()
+            .filter(|node| match &self.filter {
+                Some(filter) => fuzzy_match(filter, &node.path.to_string_lossy()),
+                None => node.path.ancestors()
+                    .skip(1)
+                    .take_while(|ancestor| !ancestor.as_os_str().is_empty())
+                    .all(|ancestor| self.expanded.contains(ancestor)),
+            })
+            .collect()
+    }
+
+    fn print(&self, visible: &[&Node]) {
+        println!();
+        for (i, node) in visible.iter().enumerate() {
+            let mark = match self.selected_ancestor(&node.path) {
+                Some(ancestor) if ancestor == node.path => "[x]",
+                Some(_) => "[-]",
+                None => "[ ]",
+            };
+            let suffix = if node.is_dir { "/" } else { "" };
+            if self.filter.is_some() {
+                println!("{} {:>4}  {}{}", mark, i + 1, node.path.display(), suffix);
+            } else {
+                let depth = node.path.components().count() - 1;
+                let name = node.path.file_name().unwrap_or_default().to_string_lossy();
+                let expand = match (node.is_dir, self.expanded.contains(&node.path)) {
+                    (true, true) => "v ",
+                    (true, false) => "> ",
+                    (false, _) => "  ",
+                };
+                println!("{} {:>4}  {}{}{}{}", mark, i + 1, "  ".repeat(depth), expand, name, suffix);
+            }
+        }
+        if !self.selected.is_empty() {
+            println!("{} selected", self.selected.len());
+        }
+    }
+
+    fn toggle(&mut self, path: PathBuf) {
+        if self.selected.remove(&path) {
+            return;
+        }
+        if let Some(ancestor) = self.selected_ancestor(&path) {
+            println!("Already included by {}", ancestor.display());
+            return;
+        }
+        // Selecting a directory includes everything inside it
+        self.selected.retain(|selected| !selected.starts_with(&path));
+        self.selected.insert(path);
+    }
+
+    /// Let the user browse until they're done. Returns `None` if they
+    /// quit instead.
+    pub fn run(mut self) -> Result<Option<Vec<PathBuf>>> {
+        println!("{}", HELP);
+
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            let visible = self.visible();
+            self.print(&visible);
+
+            print!("> ");
+            io::stdout().flush().context("failed to flush stdout")?;
+            let line = match lines.next() {
+                Some(line) => line.context("failed to read from stdin")?,
+                None => return Ok(None),
+            };
+            let line = line.trim();
+
+            let index = |arg: &str| {
+                arg.trim().parse::<usize>().ok()
+                    .and_then(|i| i.checked_sub(1))
+                    .and_then(|i| visible.get(i))
+                    .map(|node| (node.path.clone(), node.is_dir))
+            };
+
+            if line == "d" {
+                return Ok(Some(self.selected.into_iter().collect()));
+            } else if line == "q" {
+                return Ok(None);
+            } else if let Some(filter) = line.strip_prefix('/') {
+                self.filter = Some(filter.to_owned()).filter(|f| !f.is_empty());
+            } else if let Some(arg) = line.strip_prefix("o ") {
+                match index(arg) {
+                    Some((path, true)) => if !self.expanded.remove(&path) {
+                        self.expanded.insert(path);
+                    },
+                    Some((_, false)) => println!("Not a directory"),
+                    None => println!("No such entry"),
+                }
+            } else if let Some((path, _)) = index(line) {
+                self.toggle(path);
+            } else {
+                println!("{}", HELP);
+            }
+        }
+    }
+}
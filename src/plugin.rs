@@ -0,0 +1,55 @@
+//! Subcommands that aren't built in, like git's. `git subcopy frobnicate`
+//! runs `git-subcopy-frobnicate` from `PATH` with the arguments after
+//! it, so extensions don't have to be part of this crate. Where things
+//! are is in the environment: `GIT_SUBCOPY_CACHE_DIR`, and in a
+//! repository `GIT_SUBCOPY_SUPERPROJECT` and `GIT_SUBCOPY_MANIFEST`.
+
+use std::{ffi::OsString, io, path::PathBuf, process::Command};
+
+use anyhow::{Context, Result};
+use git_subcopy::{open_superproject, App};
+
+/// Run the plugin for the subcommand `name`, given all arguments we
+/// were run with. Returns its exit code, or `None` if there's no such
+/// plugin.
+pub fn run(args: &[OsString], name: &str) -> Result<Option<i32>> {
+    if name.is_empty() || name.starts_with('-') || name.contains(['/', '\\']) {
+        return Ok(None);
+    }
+    let position = match args.iter().skip(1).position(|arg| arg == name) {
+        Some(i) => i + 1,
+        None => return Ok(None),
+    };
+
+    // Only global options may come before the subcommand. Anything else
+    // means `name` is an argument to a built-in, like in `git subcopy
+    // help frobnicate`.
+    let mut manifest_path = None;
+    let mut global = args[1..position].iter();
+    while let Some(arg) = global.next() {
+        if arg == "--gitcopies-file" {
+            manifest_path = global.next().map(PathBuf::from);
+        } else if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--gitcopies-file=")) {
+            manifest_path = Some(PathBuf::from(path));
+        } else if !arg.to_string_lossy().starts_with('-') {
+            return Ok(None);
+        }
+    }
+    let mut app = App::new()?;
+    app.set_manifest_path(manifest_path);
+
+    let program = format!("git-subcopy-{}", name);
+    let mut command = Command::new(&program);
+    command.args(&args[position + 1..]).env("GIT_SUBCOPY_CACHE_DIR", app.cache_dir());
+    if let Ok(repo) = open_superproject() {
+        if let Some(workdir) = repo.workdir() {
+            command.env("GIT_SUBCOPY_SUPERPROJECT", workdir);
+            command.env("GIT_SUBCOPY_MANIFEST", app.manifest_path(&repo)?);
+        }
+    }
+    match command.status() {
+        Ok(status) => Ok(Some(status.code().unwrap_or(1))),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("failed to run {}", program)),
+    }
+}
@@ -0,0 +1,68 @@
+//! Stable output for scripts, similar to git's `--porcelain`
+//! modes. Every record is one line of tab-separated fields, escaped so
+//! that no field ever contains a literal tab or newline. A released
+//! version never changes format - anything new gets a new version.
+//! The same goes for `--json` output, except that fields may be added.
+
+use std::{borrow::Cow, str::FromStr};
+
+use anyhow::{anyhow, Error};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Version {
+    #[default]
+    V1,
+}
+impl FromStr for Version {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(Version::V1),
+            _ => Err(anyhow!("unknown porcelain version {:?}, expected v1", s)),
+        }
+    }
+}
+
+/// Escape backslashes, tabs, and newlines using C-like escapes
+pub fn escape(field: &str) -> Cow<'_, str> {
+    if !field.contains(['\\', '\t', '\n', '\r']) {
+        return Cow::Borrowed(field);
+    }
+    let mut escaped = String::with_capacity(field.len() + 2);
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Build a record out of unescaped fields. Missing values are written
+/// as empty fields.
+pub fn record(fields: &[&str]) -> String {
+    fields.iter().map(|field| escape(field)).collect::<Vec<_>>().join("\t")
+}
+
+/// Quote a string as JSON, for `--json` output
+pub fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,106 @@
+//! A minimal progress bar drawn on stderr, for the slow parts like
+//! cloning and copying files. Does nothing unless enabled, which the
+//! CLI only does when it's attached to a terminal.
+
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+const WIDTH: usize = 30;
+const INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Progress {
+    enabled: bool,
+    label: &'static str,
+    last_draw: Option<Instant>,
+    line_len: usize,
+    /// The newest line, if it wasn't drawn because of throttling
+    pending: Option<String>,
+}
+
+fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.1} GiB", b as f64 / (1 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / (1 << 10) as f64),
+        b => format!("{} B", b),
+    }
+}
+
+impl Progress {
+    pub fn new(enabled: bool, label: &'static str) -> Self {
+        Self {
+            enabled,
+            label,
+            last_draw: None,
+            line_len: 0,
+            pending: None,
+        }
+    }
+
+    /// Change what's being shown, for when one operation has several
+    /// phases
+    pub fn set_label(&mut self, label: &'static str) {
+        if self.label != label {
+            self.finish();
+            self.label = label;
+        }
+    }
+
+    /// Redraw the bar, at most every so often. Without a total, only a
+    /// counter is shown.
+    pub fn update(&mut self, current: usize, total: Option<usize>, bytes: Option<usize>) {
+        if !self.enabled {
+            return;
+        }
+        let mut line = format!("{}: ", self.label);
+        match total {
+            Some(total) if total > 0 => {
+                let filled = current.min(total) * WIDTH / total;
+                line.push_str(&format!("[{}{}] {}/{}", "#".repeat(filled), " ".repeat(WIDTH - filled), current, total));
+            },
+            _ => line.push_str(&current.to_string()),
+        }
+        if let Some(bytes) = bytes {
+            line.push_str(&format!(", {}", format_bytes(bytes)));
+        }
+
+        let done = total == Some(current);
+        if !done && self.last_draw.is_some_and(|last| last.elapsed() < INTERVAL) {
+            self.pending = Some(line);
+            return;
+        }
+        self.draw(line);
+    }
+
+    fn draw(&mut self, line: String) {
+        self.last_draw = Some(Instant::now());
+        self.pending = None;
+
+        // Pad with spaces to erase the end of a longer previous line
+        let padding = self.line_len.saturating_sub(line.len());
+        self.line_len = line.len();
+        let mut stderr = io::stderr();
+        let _ = write!(stderr, "\r{}{}", line, " ".repeat(padding));
+        let _ = stderr.flush();
+    }
+
+    /// End the line, leaving the last state visible
+    pub fn finish(&mut self) {
+        if let Some(line) = self.pending.take() {
+            self.draw(line);
+        }
+        if self.enabled && self.last_draw.is_some() {
+            eprintln!();
+        }
+        self.last_draw = None;
+        self.line_len = 0;
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
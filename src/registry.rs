@@ -0,0 +1,56 @@
+//! The repositories on this machine with subcopies, so all of them can
+//! be searched at once. It's a list of working trees, one per line, in
+//! the user's data directory. Repositories are added when a subcopy is
+//! registered in them, and forgotten once they no longer exist.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+fn registry_path() -> Result<PathBuf> {
+    let dir = dirs::data_dir().ok_or_else(|| anyhow!("can't choose a data directory"))?;
+    Ok(dir.join(env!("CARGO_PKG_NAME")).join("registry"))
+}
+
+fn read(path: &Path) -> Result<Vec<PathBuf>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content.lines().filter(|line| !line.is_empty()).map(PathBuf::from).collect()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+/// Remember a repository by its working tree, if it isn't already
+pub fn add(workdir: &Path) -> Result<()> {
+    let workdir = workdir.canonicalize().context("failed to get full path of repository")?;
+    let path = registry_path()?;
+    if read(&path)?.contains(&workdir) {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context("failed to create data directory")?;
+    }
+    let mut file = OpenOptions::new().append(true).create(true).open(&path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    writeln!(file, "{}", workdir.display()).context("failed to write registry")?;
+    Ok(())
+}
+
+/// The working trees of all remembered repositories that still exist
+pub fn repositories() -> Result<Vec<PathBuf>> {
+    let path = registry_path()?;
+    let all = read(&path)?;
+    let (existing, gone): (Vec<_>, Vec<_>) = all.into_iter().partition(|workdir| workdir.join(".git").exists());
+    if !gone.is_empty() {
+        let mut content = String::new();
+        for workdir in &existing {
+            content.push_str(&format!("{}\n", workdir.display()));
+        }
+        fs::write(&path, content).with_context(|| format!("failed to write {}", path.display()))?;
+    }
+    Ok(existing)
+}
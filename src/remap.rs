@@ -0,0 +1,86 @@
+//! Rules for laying out upstream content differently locally, written
+//! in `.gitcopies` as `remap = <upstream> -> <local>`. A rule where
+//! the upstream side ends with `/` moves a whole directory, otherwise
+//! the rule renames a single file. Rules are tried in order and the
+//! first match wins, both when extracting and when copying changes
+//! back.
+//!
+//! Neither side can be empty, as a rule for the root would match every
+//! path on the way back, not just the ones it moved. With `src/ ->`,
+//! a local `README` would be copied back as `src/README`. To leave out
+//! a prefix, copy from a narrower upstream path instead.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Error, Result};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Remap {
+    upstream: PathBuf,
+    local: PathBuf,
+    directory: bool,
+}
+
+impl FromStr for Remap {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (upstream, local) = match s.split_once("->") {
+            Some(sides) => sides,
+            None => bail!("remap rule {:?} should look like `<upstream> -> <local>`", s),
+        };
+        let (upstream, local) = (upstream.trim(), local.trim());
+        let is_dir = |side: &str| side.ends_with('/');
+
+        if upstream.trim_end_matches('/').is_empty() || local.trim_end_matches('/').is_empty() {
+            bail!("remap rule {:?} has an empty side, which would match everything", s);
+        }
+        if is_dir(upstream) != is_dir(local) {
+            bail!("remap rule {:?} maps between a file and a directory", s);
+        }
+        Ok(Self {
+            upstream: PathBuf::from(upstream.trim_end_matches('/')),
+            local: PathBuf::from(local.trim_end_matches('/')),
+            directory: is_dir(upstream),
+        })
+    }
+}
+
+impl fmt::Display for Remap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let side = |path: &Path| if self.directory { format!("{}/", path.display()) } else { path.display().to_string() };
+        write!(f, "{} -> {}", side(&self.upstream), side(&self.local))
+    }
+}
+
+impl Remap {
+    fn apply(&self, from: &Path, to: &Path, path: &Path) -> Option<PathBuf> {
+        if self.directory {
+            path.strip_prefix(from).ok()
+                .filter(|rest| !rest.as_os_str().is_empty())
+                .map(|rest| to.join(rest))
+        } else if path == from {
+            Some(to.to_path_buf())
+        } else {
+            None
+        }
+    }
+}
+
+/// Map a path relative to the upstream path to where it's stored locally
+pub fn to_local(rules: &[Remap], path: &Path) -> PathBuf {
+    rules.iter()
+        .find_map(|rule| rule.apply(&rule.upstream, &rule.local, path))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Map a path relative to the local path back to where it is upstream
+pub fn to_upstream(rules: &[Remap], path: &Path) -> PathBuf {
+    rules.iter()
+        .find_map(|rule| rule.apply(&rule.local, &rule.upstream, path))
+        .unwrap_or_else(|| path.to_path_buf())
+}
@@ -0,0 +1,69 @@
+//! Reports on all subcopies, for pasting into pull requests or
+//! tracking issues. The output only depends on the state of the
+//! subcopies, and rows are always sorted by path, so two reports can
+//! be diffed to see what changed.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Markdown,
+}
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "markdown" | "md" => Ok(Format::Markdown),
+            _ => Err(anyhow!("unknown report format {:?}, expected markdown", s)),
+        }
+    }
+}
+
+/// One subcopy in a report
+pub struct Row {
+    pub path: String,
+    pub source: String,
+    pub rev: String,
+    pub drift: String,
+    pub updates: String,
+    pub license: String,
+}
+
+const HEADERS: [&str; 6] = ["Path", "Source", "Revision", "Local changes", "Updates", "License"];
+
+/// Make a value safe to put in a table cell
+fn cell(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|").replace('\n', " ")
+}
+
+pub fn render(format: Format, rows: &[Row]) -> String {
+    match format {
+        Format::Markdown => markdown(rows),
+    }
+}
+
+fn markdown(rows: &[Row]) -> String {
+    let mut out = String::from("| ");
+    out.push_str(&HEADERS.join(" | "));
+    out.push_str(" |\n|");
+    out.push_str(&HEADERS.iter().map(|_| " --- |").collect::<String>());
+    out.push('\n');
+    for row in rows {
+        let cells = [
+            format!("`{}`", cell(&row.path)),
+            cell(&row.source),
+            format!("`{}`", cell(&row.rev)),
+            cell(&row.drift),
+            cell(&row.updates),
+            cell(&row.license),
+        ];
+        out.push_str("| ");
+        out.push_str(&cells.join(" | "));
+        out.push_str(" |\n");
+    }
+    out
+}
@@ -0,0 +1,44 @@
+//! Human readable byte sizes, like `500M` or `2G`, for `--max-size`
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, Error, Result};
+
+const UNITS: &[(&str, u64)] = &[
+    ("K", 1 << 10),
+    ("M", 1 << 20),
+    ("G", 1 << 30),
+    ("T", 1 << 40),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Size(pub u64);
+
+impl FromStr for Size {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let upper = s.trim().to_uppercase();
+        let digits = upper.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let unit = upper[digits.len()..].trim_end_matches("IB").trim_end_matches('B');
+
+        let multiplier = match unit {
+            "" => 1,
+            _ => UNITS.iter()
+                .find(|(name, _)| *name == unit)
+                .map(|(_, multiplier)| *multiplier)
+                .ok_or_else(|| anyhow!("unknown size unit in {:?}, expected K, M, G, or T", s))?,
+        };
+        let number: f64 = digits.trim().parse().map_err(|_| anyhow!("invalid size {:?}", s))?;
+        Ok(Size((number * multiplier as f64) as u64))
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match UNITS.iter().rev().find(|(_, multiplier)| self.0 >= *multiplier) {
+            Some((name, multiplier)) => write!(f, "{:.1} {}iB", self.0 as f64 / *multiplier as f64, name),
+            None => write!(f, "{} B", self.0),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Upstream files that are never vendored, written in `.gitcopies` as
+//! `skip = <pattern>`. Patterns work like a small subset of
+//! `.gitignore`: `*` and `?` match within a path component and `**`
+//! across them, a pattern with a `/` in the middle is relative to the
+//! root of the subcopy while one without matches at any depth, and a
+//! trailing `/` only matches directories. Paths are matched as they
+//! are in upstream, before remapping.
+
+use std::{fmt, path::Path, str::FromStr};
+
+use anyhow::{bail, Error, Result};
+use regex::Regex;
+
+#[derive(Clone, Debug)]
+pub struct Skip {
+    pattern: String,
+    regex: Regex,
+}
+
+impl FromStr for Skip {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let pattern = s.trim();
+        let (body, directory) = match pattern.strip_suffix('/') {
+            Some(body) => (body, true),
+            None => (pattern, false),
+        };
+        let anchored = body.contains('/');
+        let body = body.trim_start_matches('/');
+        if body.is_empty() {
+            bail!("skip pattern {:?} doesn't match anything", s);
+        }
+
+        let mut regex = String::from(if anchored { "^" } else { "(^|/)" });
+        let mut chars = body.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        regex.push_str("(.*/)?");
+                    } else {
+                        regex.push_str(".*");
+                    }
+                },
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        // Naming a directory matches everything inside of it, and a
+        // trailing slash means it has to be a directory
+        regex.push_str(if directory { "/" } else { "(/|$)" });
+
+        Ok(Self {
+            pattern: pattern.to_owned(),
+            regex: Regex::new(&regex)?,
+        })
+    }
+}
+
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+impl Skip {
+    /// Whether a file, relative to the upstream path of the subcopy, is
+    /// skipped by this pattern
+    pub fn matches(&self, path: &Path) -> bool {
+        self.regex.is_match(&path.to_string_lossy())
+    }
+}
+
+/// Whether a file is skipped by any of the patterns
+pub fn is_skipped(skips: &[Skip], path: &Path) -> bool {
+    skips.iter().any(|skip| skip.matches(path))
+}
@@ -0,0 +1,216 @@
+//! Snapshots of subcopies, taken before their files are rewritten so
+//! they can be rolled back. They're commits in the superproject under
+//! `refs/subcopy/backups/`, each with the previous snapshot of the same
+//! subcopy as parent, and the content as `content` in their tree.
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use git2::{ObjectType, Oid, Repository, Signature, TreeBuilder, TreeEntry};
+use walkdir::WalkDir;
+
+/// The name of the entry in a snapshot's tree holding the content
+const CONTENT: &str = "content";
+
+/// The line in a snapshot's message recording the revision
+const REVISION: &str = "Revision: ";
+
+/// A snapshot of a subcopy
+pub struct Snapshot {
+    pub id: Oid,
+    pub rev: String,
+}
+
+/// The ref holding snapshots of a subcopy, given by its path relative
+/// to the root of the working tree
+fn ref_name(key: &Path) -> String {
+    let key = base64::encode_config(key.to_string_lossy().as_bytes(), base64::URL_SAFE_NO_PAD);
+    format!("refs/subcopy/backups/{}", key)
+}
+
+/// Save the content of a subcopy, as it was at revision `rev`
+pub fn save(repo: &Repository, key: &Path, local_path: &Path, rev: &str) -> Result<Oid> {
+    let mut root = repo.treebuilder(None)?;
+    write_path(repo, &mut root, CONTENT, local_path)?;
+    let tree = repo.find_tree(root.write()?)?;
+
+    let name = ref_name(key);
+    let parent = repo.refname_to_id(&name).ok().map(|id| repo.find_commit(id)).transpose()?;
+    let sign = Signature::now("git-subcopy", "there's nobody to blame this time")?;
+    let message = format!("Snapshot of {}\n\n{}{}\n", key.display(), REVISION, rev);
+    let id = repo.commit(None, &sign, &sign, &message, &tree, &parent.iter().collect::<Vec<_>>())
+        .context("failed to commit snapshot")?;
+    repo.reference(&name, id, true, &format!("subcopy: snapshot of {}", key.display()))
+        .context("failed to save snapshot ref")?;
+    Ok(id)
+}
+
+/// Save a file or directory as `name` in a tree. Does nothing if it
+/// doesn't exist.
+pub(crate) fn write_path(repo: &Repository, builder: &mut TreeBuilder, name: &str, path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            let tree = write_tree(repo, path)?;
+            builder.insert(name, tree, 0o040000)?;
+        },
+        Ok(metadata) => {
+            let (id, mode) = write_file(repo, path, &metadata)?;
+            builder.insert(name, id, mode)?;
+        },
+        Err(_) => (),
+    }
+    Ok(())
+}
+
+fn write_tree(repo: &Repository, dir: &Path) -> Result<Oid> {
+    let mut builder = repo.treebuilder(None)?;
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))? {
+        let entry = entry.context("failed to read directory entry")?;
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        let name = entry.file_name();
+        let name = name.to_str().ok_or_else(|| anyhow!("{} is not valid utf-8", entry.path().display()))?;
+        if entry.file_type()?.is_dir() {
+            let tree = write_tree(repo, &entry.path())?;
+            insert_unless_empty(repo, &mut builder, name, tree)?;
+        } else {
+            let (id, mode) = write_file(repo, &entry.path(), &entry.metadata()?)?;
+            builder.insert(name, id, mode)?;
+        }
+    }
+    Ok(builder.write()?)
+}
+
+/// Git can't store empty directories, so leave them out
+fn insert_unless_empty(repo: &Repository, builder: &mut TreeBuilder, name: &str, tree: Oid) -> Result<()> {
+    if !repo.find_tree(tree)?.is_empty() {
+        builder.insert(name, tree, 0o040000)?;
+    }
+    Ok(())
+}
+
+/// Save a file, or where a symlink points, along with the mode to
+/// store it with
+fn write_file(repo: &Repository, path: &Path, metadata: &fs::Metadata) -> Result<(Oid, i32)> {
+    if metadata.file_type().is_symlink() {
+        let target = fs::read_link(path).with_context(|| format!("failed to read symlink {}", path.display()))?;
+        return Ok((repo.blob(crate::path_to_string(&target)?.as_bytes())?, 0o120000));
+    }
+    let id = repo.blob_path(path).with_context(|| format!("failed to save {}", path.display()))?;
+    Ok((id, crate::filemode(metadata)))
+}
+
+/// The latest snapshot of a subcopy, if any
+pub fn latest(repo: &Repository, key: &Path) -> Result<Option<Snapshot>> {
+    let id = match repo.refname_to_id(&ref_name(key)) {
+        Ok(id) => id,
+        Err(_) => return Ok(None),
+    };
+    let commit = repo.find_commit(id).context("snapshot ref doesn't point to a commit")?;
+    let rev = commit.message().unwrap_or("").lines()
+        .find_map(|line| line.strip_prefix(REVISION))
+        .ok_or_else(|| anyhow!("snapshot {} doesn't record a revision", id))?;
+    Ok(Some(Snapshot {
+        id,
+        rev: rev.to_owned(),
+    }))
+}
+
+/// Replace the content of a subcopy with a snapshot, and then forget
+/// the snapshot so the one before it is next
+pub fn restore(repo: &Repository, key: &Path, snapshot: &Snapshot, local_path: &Path) -> Result<()> {
+    let commit = repo.find_commit(snapshot.id)?;
+    let tree = commit.tree()?;
+    let content = tree.get_name(CONTENT).ok_or_else(|| anyhow!("snapshot {} has no content", snapshot.id))?;
+    restore_path(repo, Some(&content), local_path)?;
+
+    let mut reference = repo.find_reference(&ref_name(key))?;
+    match commit.parent_id(0) {
+        Ok(parent) => {
+            reference.set_target(parent, &format!("subcopy: rolled back {}", key.display()))?;
+        },
+        Err(_) => reference.delete()?,
+    }
+    Ok(())
+}
+
+/// Replace a file or directory with what was saved by `write_path`, or
+/// remove it if `entry` is `None` since it didn't exist back then
+pub(crate) fn restore_path(repo: &Repository, entry: Option<&TreeEntry>, path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() && entry.is_some() => remove_dir_contents(path)?,
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path).with_context(|| format!("failed to remove {}", path.display()))?,
+        Ok(_) => fs::remove_file(path).with_context(|| format!("failed to remove {}", path.display()))?,
+        Err(_) => (),
+    }
+    let entry = match entry {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+    match entry.kind() {
+        Some(ObjectType::Tree) => {
+            fs::create_dir_all(path).context("failed to create directory")?;
+            restore_tree(repo, &repo.find_tree(entry.id())?, path)
+        },
+        Some(ObjectType::Blob) => restore_blob(repo, entry, path),
+        _ => bail!("unexpected object {} in saved tree", entry.id()),
+    }
+}
+
+fn remove_dir_contents(dir: &Path) -> Result<()> {
+    for entry in WalkDir::new(dir).min_depth(1).max_depth(1) {
+        let entry = entry.context("failed to read directory entry")?;
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        if entry.file_type().is_dir() {
+            fs::remove_dir_all(entry.path())
+        } else {
+            fs::remove_file(entry.path())
+        }.with_context(|| format!("failed to remove {}", entry.path().display()))?;
+    }
+    Ok(())
+}
+
+fn restore_tree(repo: &Repository, tree: &git2::Tree, dir: &Path) -> Result<()> {
+    for entry in tree.iter() {
+        let name = entry.name().ok_or_else(|| anyhow!("snapshot has a file name that isn't valid utf-8"))?;
+        let path = dir.join(name);
+        match entry.kind() {
+            Some(ObjectType::Tree) => {
+                fs::create_dir_all(&path).with_context(|| format!("failed to create {}", path.display()))?;
+                restore_tree(repo, &repo.find_tree(entry.id())?, &path)?;
+            },
+            _ => restore_blob(repo, &entry, &path)?,
+        }
+    }
+    Ok(())
+}
+
+/// Write a file, symlink or executable back the way it was saved
+fn restore_blob(repo: &Repository, entry: &TreeEntry, path: &Path) -> Result<()> {
+    let blob = repo.find_blob(entry.id())?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context("failed to create parent directories")?;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        if entry.filemode() == 0o120000 {
+            let target = std::str::from_utf8(blob.content()).context("symlink target is not valid utf-8")?;
+            return symlink(target, path).with_context(|| format!("failed to create symlink {}", path.display()));
+        }
+        fs::write(path, blob.content()).with_context(|| format!("failed to write {}", path.display()))?;
+        if entry.filemode() == 0o100755 {
+            let mut permissions = fs::metadata(path)?.permissions();
+            permissions.set_mode(permissions.mode() | 0o111);
+            fs::set_permissions(path, permissions).with_context(|| format!("failed to make {} executable", path.display()))?;
+        }
+        Ok(())
+    }
+    // Like git without symlink support, symlinks become files with
+    // their target as content
+    #[cfg(not(unix))]
+    fs::write(path, blob.content()).with_context(|| format!("failed to write {}", path.display()))
+}
@@ -0,0 +1,231 @@
+//! Resolving what the user typed as a source into a URL git can clone
+
+use std::{borrow::Cow, env, path::Path};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use git2::{Config, ErrorCode, Repository};
+
+/// Shorthand prefixes and what they expand to
+const SHORTHANDS: &[(&str, &str)] = &[
+    ("github:", "https://github.com/"),
+    ("gitlab:", "https://gitlab.com/"),
+    ("codeberg:", "https://codeberg.org/"),
+    ("sr.ht:", "https://git.sr.ht/~"),
+];
+
+/// Expand shorthands like `github:owner/repo` into full clone URLs.
+/// Anything else is returned as-is.
+pub fn expand(url: &str) -> Cow<'_, str> {
+    for (prefix, base) in SHORTHANDS {
+        if let Some(path) = url.strip_prefix(prefix) {
+            // sourcehut users are prefixed with ~, which is easy to
+            // forget but also fine to include
+            let path = path.trim_start_matches('/');
+            let path = if *prefix == "sr.ht:" { path.trim_start_matches('~') } else { path };
+            return Cow::Owned(format!("{}{}", base, path));
+        }
+    }
+    Cow::Borrowed(url)
+}
+
+/// A clone URL split into its parts. Besides `scheme://` URLs, git
+/// accepts the scp-like `[user@]host:path`, and local paths.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Url<'a> {
+    pub scheme: Option<&'a str>,
+    pub user: Option<&'a str>,
+    /// The host, including the port if there is one. `None` for local
+    /// paths.
+    pub host: Option<&'a str>,
+    pub path: &'a str,
+}
+impl<'a> Url<'a> {
+    /// Split up a URL the way git does. Anything with a `:` before the
+    /// first `/` is scp-like, except for a single letter which is a
+    /// Windows drive.
+    pub fn parse(url: &'a str) -> Self {
+        if let Some((scheme, rest)) = url.split_once("://") {
+            if scheme == "file" {
+                return Self { scheme: Some(scheme), user: None, host: None, path: rest };
+            }
+            let (authority, path) = match rest.find('/') {
+                Some(i) => rest.split_at(i),
+                None => (rest, ""),
+            };
+            let (user, host) = split_user(authority);
+            return Self { scheme: Some(scheme), user, host: Some(host), path };
+        }
+
+        match url.split_once(':') {
+            Some((authority, path)) if !authority.contains('/') && !is_drive(authority) => {
+                let (user, host) = split_user(authority);
+                Self { scheme: None, user, host: Some(host), path }
+            },
+            _ => Self { scheme: None, user: None, host: None, path: url },
+        }
+    }
+
+    /// Whether this is a path on this machine
+    pub fn is_local(&self) -> bool {
+        self.host.is_none()
+    }
+
+    /// The URL without the user, which may be a secret from the
+    /// environment, for showing to the user
+    pub fn redacted(&self) -> String {
+        match (self.scheme, self.host) {
+            (Some(scheme), Some(host)) => format!("{}://{}{}", scheme, host, self.path),
+            (None, Some(host)) => format!("{}:{}", host, self.path),
+            (Some(scheme), None) => format!("{}://{}", scheme, self.path),
+            (None, None) => self.path.to_owned(),
+        }
+    }
+}
+
+/// Make sure this build can fetch from a URL. Support for https and ssh
+/// are cargo features, and libgit2 only says "unsupported URL protocol"
+/// when one is missing.
+pub fn ensure_supported(url: &str) -> Result<()> {
+    let parsed = Url::parse(url);
+    if !parsed.is_local() && parsed.path.ends_with(".bundle") {
+        bail!("can't fetch {}: only bundles on this machine can be used, download it first", parsed.redacted());
+    }
+    let feature = match parsed.scheme {
+        Some("https") if !cfg!(feature = "https") => "https",
+        Some("ssh") | Some("git+ssh") | Some("ssh+git") if !cfg!(feature = "ssh") => "ssh",
+        None if !parsed.is_local() && !cfg!(feature = "ssh") => "ssh",
+        _ => return Ok(()),
+    };
+    Err(anyhow!(
+        "can't fetch {}: git-subcopy was built without {} support, rebuild it with the {:?} feature",
+        parsed.redacted(),
+        feature,
+        feature,
+    ))
+}
+
+/// Whether a URL is `.`, the repository we're in. Its objects are
+/// used directly instead of a cache.
+pub fn is_self(url: &str) -> bool {
+    url == "."
+}
+
+/// Whether a URL is a relative path on this machine, like
+/// `../other-project`
+pub fn is_relative_path(url: &str) -> bool {
+    let parsed = Url::parse(url);
+    parsed.scheme.is_none() && parsed.is_local() && Path::new(parsed.path).is_relative()
+}
+
+/// The path of a git bundle, if that's what a URL is instead of a
+/// repository. libgit2 can't read them, so they're handled separately.
+pub fn bundle_path(url: &str) -> Option<&Path> {
+    let parsed = Url::parse(url);
+    Some(Path::new(parsed.path)).filter(|_| parsed.is_local() && parsed.path.ends_with(".bundle"))
+}
+
+fn split_user(authority: &str) -> (Option<&str>, &str) {
+    match authority.rsplit_once('@') {
+        Some((user, host)) => (Some(user), host),
+        None => (None, authority),
+    }
+}
+
+fn is_drive(authority: &str) -> bool {
+    authority.len() == 1 && authority.chars().all(|c| c.is_ascii_alphabetic())
+}
+
+/// A canonical form of a URL, so different ways of writing the same
+/// repository share a cache. The scheme and user are dropped, as well
+/// as a trailing `.git` or `/`, and the host is lowercased. This means
+/// `https://github.com/x/y`, `https://github.com/x/y.git`, and
+/// `git@github.com:x/y.git` are all `github.com/x/y`.
+pub fn normalize(url: &str) -> String {
+    let url = Url::parse(url);
+    let path = url.path.trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path).trim_end_matches('/');
+    match url.host {
+        Some(host) => format!("{}/{}", host.to_lowercase(), path.trim_start_matches('/')),
+        None => path.to_owned(),
+    }
+}
+
+/// Fill in a URL template for mirroring an upstream, where `{host}` is
+/// its host, `{path}` its path without `.git`, and `{name}` the last
+/// component of the path. `https://git.example.com/mirrors/{path}.git`
+/// turns `git@github.com:x/y` into
+/// `https://git.example.com/mirrors/x/y.git`.
+pub fn mirror_url(template: &str, url: &str) -> String {
+    let normalized = normalize(url);
+    let (host, path) = match Url::parse(url).host {
+        Some(_) => normalized.split_once('/').unwrap_or((&normalized, "")),
+        None => ("", normalized.trim_start_matches('/')),
+    };
+    let name = path.rsplit('/').next().unwrap_or(path);
+    template.replace("{host}", host).replace("{path}", path).replace("{name}", name)
+}
+
+/// Replace `${VAR}` in a URL with the value of the environment
+/// variable, so credentials like `https://${TOKEN}@host/repo` don't
+/// have to be committed. The result may contain secrets, so it should
+/// only be handed to git and never saved or printed.
+pub fn expand_env(url: &str) -> Result<Cow<'_, str>> {
+    if !url.contains("${") {
+        return Ok(Cow::Borrowed(url));
+    }
+    let mut expanded = String::with_capacity(url.len());
+    let mut rest = url;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}').ok_or_else(|| anyhow!("unterminated ${{ in {}", url))?;
+        let name = &after[..end];
+        ensure!(
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "invalid variable name {:?} in {}", name, url,
+        );
+        let value = env::var(name).with_context(|| format!("environment variable {} used in {} is not set", name, url))?;
+        expanded.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(Cow::Owned(expanded))
+}
+
+/// Whether a source looks like the name of an alias rather than a URL
+/// or path
+pub fn is_alias_name(source: &str) -> bool {
+    !source.is_empty() && !source.contains(['/', ':', '\\']) && source != "." && source != ".."
+}
+
+/// Look up the URL of a named source. Git config
+/// (`subcopy.source.<name>.url`) takes priority, so individual users
+/// can override the shared aliases in the `.gitsources` file checked
+/// into the root of the repository.
+pub fn find_alias(repo: Option<&Repository>, name: &str) -> Result<Option<String>> {
+    let key = format!("subcopy.source.{}.url", name);
+    let config = match repo {
+        Some(repo) => repo.config().context("failed to open repository config")?,
+        None => Config::open_default().context("failed to open git config")?,
+    };
+    if let Some(url) = get_string(&config, &key)? {
+        return Ok(Some(url));
+    }
+
+    if let Some(workdir) = repo.and_then(Repository::workdir) {
+        let path = workdir.join(".gitsources");
+        if path.exists() {
+            let file = Config::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+            return get_string(&file, &format!("source.{}.url", name));
+        }
+    }
+    Ok(None)
+}
+
+fn get_string(config: &Config, key: &str) -> Result<Option<String>> {
+    match config.get_string(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(ref err) if err.code() == ErrorCode::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("failed to read {}", key)),
+    }
+}
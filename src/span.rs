@@ -0,0 +1,40 @@
+//! What's being worked on, for log messages. Spans are entered around
+//! operations, like fetching or copying back, and around the work on
+//! each subcopy, and the ones currently entered are attached to every
+//! message logged on that thread. Long bulk operations can then be
+//! followed in structured logs, one subcopy at a time.
+
+use std::{cell::RefCell, fmt::Display};
+
+thread_local! {
+    static SPANS: RefCell<Vec<(&'static str, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// An entered span, which is left when dropped
+#[must_use = "the span is left as soon as this is dropped"]
+pub struct Span {
+    _private: (),
+}
+
+/// Enter a span, like `entry` with the path of a subcopy
+pub fn enter<T: Display>(name: &'static str, value: T) -> Span {
+    SPANS.with(|spans| spans.borrow_mut().push((name, value.to_string())));
+    Span { _private: () }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        SPANS.with(|spans| spans.borrow_mut().pop());
+    }
+}
+
+/// The spans entered on this thread, outermost first
+pub fn current() -> Vec<(&'static str, String)> {
+    SPANS.with(|spans| spans.borrow().clone())
+}
+
+/// Enter spans taken from `current` on another thread, so work handed
+/// to a new thread is logged as part of what started it
+pub fn enter_all(spans: &[(&'static str, String)]) -> Vec<Span> {
+    spans.iter().map(|(name, value)| enter(name, value)).collect()
+}
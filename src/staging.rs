@@ -0,0 +1,124 @@
+//! Writing files all at once. Extracted files are written to a staging
+//! directory next to their destination first, and only moved into
+//! place once all of them were written. Moving them is a rename on the
+//! same filesystem, and anything already moved is put back if one
+//! fails, so the destination is never left half written.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+
+use crate::interrupt;
+use tempfile::{Builder, TempDir};
+use walkdir::WalkDir;
+
+pub struct Staging {
+    dir: TempDir,
+    destination: PathBuf,
+}
+
+impl Staging {
+    pub fn new(destination: &Path) -> Result<Self> {
+        let parent = match destination.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(parent).context("failed to create parent directories")?;
+        let dir = Builder::new().prefix(".git-subcopy-staging").tempdir_in(parent)
+            .context("failed to create staging directory")?;
+        Ok(Self {
+            dir,
+            destination: destination.to_path_buf(),
+        })
+    }
+
+    /// Where to write the files, instead of the destination
+    pub fn path(&self) -> PathBuf {
+        self.dir.path().join("content")
+    }
+
+    /// Where the files are moved to
+    pub fn destination(&self) -> &Path {
+        &self.destination
+    }
+
+    fn backup_path(&self) -> PathBuf {
+        self.dir.path().join("backup")
+    }
+
+    /// Move everything that was written into place, replacing existing
+    /// files. Files in the destination that weren't written are kept.
+    pub fn commit(self) -> Result<()> {
+        let staged = self.path();
+        let metadata = match fs::symlink_metadata(&staged) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(()),
+        };
+        if !metadata.is_dir() || fs::symlink_metadata(&self.destination).is_err() {
+            return fs::rename(&staged, &self.destination)
+                .with_context(|| format!("failed to move files into {}", self.destination.display()));
+        }
+
+        let mut moved = Vec::new();
+        let result = self.move_files(&staged, &mut moved);
+        if result.is_err() {
+            // Put back everything in reverse, so directories are in place
+            // before what was in them
+            for (target, backup) in moved.iter().rev() {
+                debug!("putting back {}", target.display());
+                let restored = fs::remove_file(target).and_then(|()| match backup {
+                    Some(backup) => fs::rename(backup, target),
+                    None => Ok(()),
+                });
+                if let Err(err) = restored {
+                    warn!("failed to put back {}: {}", target.display(), err);
+                }
+            }
+        }
+        result
+    }
+
+    fn move_files(&self, staged: &Path, moved: &mut Vec<(PathBuf, Option<PathBuf>)>) -> Result<()> {
+        for entry in WalkDir::new(staged).min_depth(1).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+            // Interrupting puts back what was already moved
+            interrupt::check()?;
+            let entry = entry.context("failed to read staged files")?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(staged).context("walkdir should always have prefix")?;
+            let target = self.destination.join(relative);
+
+            let backup = if fs::symlink_metadata(&target).is_ok() {
+                let backup = self.backup_path().join(relative);
+                if let Some(parent) = backup.parent() {
+                    fs::create_dir_all(parent).context("failed to create backup directory")?;
+                }
+                fs::rename(&target, &backup).with_context(|| format!("failed to move {} out of the way", target.display()))?;
+                Some(backup)
+            } else {
+                None
+            };
+            if let Some(parent) = target.parent() {
+                if let Err(err) = fs::create_dir_all(parent) {
+                    if let Some(backup) = &backup {
+                        let _ = fs::rename(backup, &target);
+                    }
+                    return Err(err).with_context(|| format!("failed to create {}", parent.display()));
+                }
+            }
+            if let Err(err) = fs::rename(entry.path(), &target) {
+                if let Some(backup) = &backup {
+                    let _ = fs::rename(backup, &target);
+                }
+                return Err(err).with_context(|| format!("failed to move {} into place", target.display()));
+            }
+            moved.push((target, backup));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,21 @@
+//! Filenames on macOS. APFS and HFS+ can list names decomposed (NFD)
+//! even when they were written precomposed (NFC), which is how git and
+//! almost every upstream stores them. Names read from the disk are
+//! precomposed there before they're compared to upstream or looked up
+//! in `.gitcopies`, so files aren't mistaken for added or deleted ones.
+
+use std::{borrow::Cow, path::{Path, PathBuf}};
+
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+/// `path` in NFC on macOS. Elsewhere, or if it isn't valid UTF-8, it's
+/// left as it is.
+pub fn precompose(path: &Path) -> Cow<'_, Path> {
+    if !cfg!(target_os = "macos") {
+        return Cow::Borrowed(path);
+    }
+    match path.to_str() {
+        Some(s) if !is_nfc(s) => Cow::Owned(PathBuf::from(s.nfc().collect::<String>())),
+        _ => Cow::Borrowed(path),
+    }
+}